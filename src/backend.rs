@@ -0,0 +1,243 @@
+//! What the game logic needs from libtcod besides a window: colors, field of
+//! view, lines between tiles and the clock. With the `tcod` feature these are
+//! libtcod's own; without it they're plain Rust stand-ins, so the library
+//! builds and runs headless, e.g. for tests.
+
+#[cfg(feature = "tcod")]
+pub use tcod::colors;
+#[cfg(feature = "tcod")]
+pub use tcod::line::Line;
+#[cfg(feature = "tcod")]
+pub use tcod::map::{FovAlgorithm, Map as FovMap};
+
+/// Cap how many frames are drawn a second
+#[cfg(feature = "tcod")]
+pub fn set_fps(fps: i32) {
+    tcod::system::set_fps(fps);
+}
+
+/// How long the game has been running
+#[cfg(feature = "tcod")]
+pub fn elapsed_time() -> std::time::Duration {
+    tcod::system::get_elapsed_time()
+}
+
+#[cfg(not(feature = "tcod"))]
+pub use self::headless::*;
+
+#[cfg(not(feature = "tcod"))]
+mod headless {
+    use std::cmp;
+    use std::sync::OnceLock;
+    use std::time::{Duration, Instant};
+
+    pub mod colors {
+        use serde::{Deserialize, Serialize};
+
+        /// An RGB color, saved the same way as libtcod's
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+        pub struct Color {
+            pub r: u8,
+            pub g: u8,
+            pub b: u8,
+        }
+
+        impl Color {
+            pub const fn new(r: u8, g: u8, b: u8) -> Self {
+                Color { r, g, b }
+            }
+        }
+
+        /// the color `coefficient` of the way from `from` to `to`
+        pub fn lerp(from: Color, to: Color, coefficient: f32) -> Color {
+            let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * coefficient) as u8;
+            Color::new(mix(from.r, to.r), mix(from.g, to.g), mix(from.b, to.b))
+        }
+
+        // the ones from libtcod's palette that the game uses
+        pub const BLACK: Color = Color::new(0, 0, 0);
+        pub const WHITE: Color = Color::new(255, 255, 255);
+        pub const LIGHT_GREY: Color = Color::new(159, 159, 159);
+        pub const RED: Color = Color::new(255, 0, 0);
+        pub const LIGHT_RED: Color = Color::new(255, 63, 63);
+        pub const DARK_RED: Color = Color::new(191, 0, 0);
+        pub const DARKER_RED: Color = Color::new(127, 0, 0);
+        pub const DARKEST_RED: Color = Color::new(63, 0, 0);
+        pub const ORANGE: Color = Color::new(255, 127, 0);
+        pub const LIGHT_ORANGE: Color = Color::new(255, 159, 63);
+        pub const DARK_ORANGE: Color = Color::new(191, 95, 0);
+        pub const DARKER_ORANGE: Color = Color::new(127, 63, 0);
+        pub const DARK_AMBER: Color = Color::new(191, 143, 0);
+        pub const LIGHT_YELLOW: Color = Color::new(255, 255, 63);
+        pub const DARK_YELLOW: Color = Color::new(191, 191, 0);
+        pub const DARKER_YELLOW: Color = Color::new(127, 127, 0);
+        pub const LIME: Color = Color::new(191, 255, 0);
+        pub const DARK_LIME: Color = Color::new(143, 191, 0);
+        pub const DESATURATED_CHARTREUSE: Color = Color::new(95, 127, 63);
+        pub const LIGHT_GREEN: Color = Color::new(63, 255, 63);
+        pub const DARKER_GREEN: Color = Color::new(0, 127, 0);
+        pub const DESATURATED_GREEN: Color = Color::new(63, 127, 63);
+        pub const LIGHT_CYAN: Color = Color::new(63, 255, 255);
+        pub const SKY: Color = Color::new(0, 191, 255);
+        pub const LIGHT_BLUE: Color = Color::new(63, 63, 255);
+        pub const LIGHT_HAN: Color = Color::new(111, 63, 255);
+        pub const VIOLET: Color = Color::new(127, 0, 255);
+        pub const LIGHT_VIOLET: Color = Color::new(159, 63, 255);
+        pub const DARKER_VIOLET: Color = Color::new(63, 0, 127);
+        pub const LIGHT_PURPLE: Color = Color::new(207, 63, 255);
+        pub const CRIMSON: Color = Color::new(255, 0, 63);
+        pub const SEPIA: Color = Color::new(127, 101, 63);
+        pub const LIGHT_SEPIA: Color = Color::new(158, 134, 100);
+        pub const DARK_SEPIA: Color = Color::new(94, 75, 47);
+        pub const DARKER_SEPIA: Color = Color::new(63, 50, 31);
+        pub const BRASS: Color = Color::new(191, 151, 96);
+    }
+
+    /// The tiles on the way from one to another, not counting the first,
+    /// stepped out the same way as libtcod does
+    pub struct Line {
+        pos: (i32, i32),
+        end: (i32, i32),
+        step: (i32, i32),
+        delta: (i32, i32),
+        error: i32,
+    }
+
+    impl Line {
+        pub fn new(start: (i32, i32), end: (i32, i32)) -> Self {
+            let delta = (end.0 - start.0, end.1 - start.1);
+            let step = (delta.0.signum(), delta.1.signum());
+            Line {
+                pos: start,
+                end,
+                step,
+                delta: (delta.0 * 2, delta.1 * 2),
+                error: cmp::max(step.0 * delta.0, step.1 * delta.1),
+            }
+        }
+    }
+
+    impl Iterator for Line {
+        type Item = (i32, i32);
+
+        fn next(&mut self) -> Option<(i32, i32)> {
+            let (step_x, step_y) = self.step;
+            let (delta_x, delta_y) = self.delta;
+            if step_x * delta_x > step_y * delta_y {
+                if self.pos.0 == self.end.0 {
+                    return None;
+                }
+                self.pos.0 += step_x;
+                self.error -= step_y * delta_y;
+                if self.error < 0 {
+                    self.pos.1 += step_y;
+                    self.error += step_x * delta_x;
+                }
+            } else {
+                if self.pos.1 == self.end.1 {
+                    return None;
+                }
+                self.pos.1 += step_y;
+                self.error -= step_x * delta_x;
+                if self.error < 0 {
+                    self.pos.0 += step_x;
+                    self.error += step_y * delta_y;
+                }
+            }
+            Some(self.pos)
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum FovAlgorithm {
+        /// rays cast from the viewer to the edge of its sight
+        Basic,
+    }
+
+    /// Which tiles can be seen through, and which are in view
+    pub struct FovMap {
+        width: i32,
+        height: i32,
+        transparent: Vec<bool>,
+        in_fov: Vec<bool>,
+    }
+
+    impl FovMap {
+        pub fn new(width: i32, height: i32) -> Self {
+            let size = (width * height) as usize;
+            FovMap {
+                width,
+                height,
+                transparent: vec![false; size],
+                in_fov: vec![false; size],
+            }
+        }
+
+        fn index(&self, x: i32, y: i32) -> Option<usize> {
+            let in_map = (0..self.width).contains(&x) && (0..self.height).contains(&y);
+            Some((y * self.width + x) as usize).filter(|_| in_map)
+        }
+
+        /// walking doesn't matter to the FOV, so only what can be seen through is kept
+        pub fn set(&mut self, x: i32, y: i32, transparent: bool, _walkable: bool) {
+            if let Some(index) = self.index(x, y) {
+                self.transparent[index] = transparent;
+            }
+        }
+
+        pub fn is_in_fov(&self, x: i32, y: i32) -> bool {
+            self.index(x, y).is_some_and(|index| self.in_fov[index])
+        }
+
+        /// Cast a ray from (x, y) to every tile on the edge of what it can see,
+        /// lighting up the tiles along it until something blocks the view.
+        /// A radius of 0 means no limit
+        pub fn compute_fov(
+            &mut self,
+            x: i32,
+            y: i32,
+            radius: i32,
+            light_walls: bool,
+            _algo: FovAlgorithm,
+        ) {
+            self.in_fov.iter_mut().for_each(|tile| *tile = false);
+            let origin = match self.index(x, y) {
+                Some(origin) => origin,
+                None => return,
+            };
+            self.in_fov[origin] = true;
+
+            let reach = if radius > 0 { radius } else { self.width.max(self.height) };
+            let (min_x, max_x) = (x - reach, x + reach);
+            let (min_y, max_y) = (y - reach, y + reach);
+            let edge = (min_x..=max_x)
+                .flat_map(|edge_x| vec![(edge_x, min_y), (edge_x, max_y)])
+                .chain((min_y..=max_y).flat_map(|edge_y| vec![(min_x, edge_y), (max_x, edge_y)]));
+            for end in edge.collect::<Vec<_>>() {
+                for (tile_x, tile_y) in Line::new((x, y), end) {
+                    let distance = (tile_x - x).pow(2) + (tile_y - y).pow(2);
+                    let within = radius <= 0 || distance <= radius.pow(2);
+                    let index = match self.index(tile_x, tile_y) {
+                        Some(index) if within => index,
+                        _ => break,
+                    };
+                    if self.transparent[index] {
+                        self.in_fov[index] = true;
+                    } else {
+                        self.in_fov[index] = light_walls;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// no window, so nothing to slow down for
+    pub fn set_fps(_fps: i32) {}
+
+    /// how long since the game first asked
+    pub fn elapsed_time() -> Duration {
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed()
+    }
+}
@@ -1,47 +1,558 @@
 use std::cmp;
+use std::collections::VecDeque;
+use std::thread::{self, JoinHandle};
 
-use tcod::colors::*;
-use tcod::console::*;
-use tcod::map::{Map as FovMap};
+use crate::backend::colors::*;
+use crate::backend::{self, FovMap, Line};
 
+use rand::{SeedableRng, StdRng};
 use serde::{Serialize, Deserialize};
 
 use crate::*;
 
 pub struct Tcod {
-    pub root: Root,
-    pub con: Offscreen,
-    pub panel: Offscreen,
+    pub renderer: Box<dyn Renderer>,
     pub fov: FovMap,
-    pub key: Key,
-    pub mouse: Mouse,
+    pub key: KeyPress,
+    pub mouse: MouseState,
+    pub render: RenderCache,
+    /// the latest keys the player pressed, oldest first, for bug reports
+    pub recent_keys: VecDeque<KeyPress>,
 }
 
-pub type Map = Vec<Vec<Tile>>;
+impl Tcod {
+    /// Set up the FOV map and input state to go with the renderer
+    pub fn new(renderer: Box<dyn Renderer>) -> Self {
+        Tcod {
+            renderer,
+            fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
+            key: Default::default(),
+            mouse: Default::default(),
+            render: RenderCache::new(),
+            recent_keys: VecDeque::new(),
+        }
+    }
+
+    /// remember the key just pressed, forgetting the oldest once there are
+    /// enough for a bug report
+    pub fn record_key(&mut self) {
+        if self.key.code == KeyCode::None {
+            return;
+        }
+        self.recent_keys.push_back(self.key);
+        if self.recent_keys.len() > BUG_REPORT_INPUTS {
+            self.recent_keys.pop_front();
+        }
+    }
+}
+
+/// Answers which tiles the player can see. The AI only needs this much of the
+/// FOV, so it can be handed a stand-in that declares exactly what's visible.
+pub trait Visibility {
+    fn is_in_fov(&self, x: i32, y: i32) -> bool;
+}
+
+impl Visibility for FovMap {
+    fn is_in_fov(&self, x: i32, y: i32) -> bool {
+        FovMap::is_in_fov(self, x, y)
+    }
+}
+
+/// The tiles of a level, kept row by row in a single buffer
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Map {
+    width: i32,
+    height: i32,
+    tiles: Vec<Tile>,
+}
+
+impl Map {
+    /// a map of the given size with every tile the same
+    pub fn new(width: i32, height: i32, tile: Tile) -> Self {
+        Map {
+            width,
+            height,
+            tiles: vec![tile; (width * height) as usize],
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    /// whether the buffer holds exactly one tile for every spot, which a
+    /// save file that was tampered with might not
+    pub fn is_whole(&self) -> bool {
+        let size = (self.width.max(0) as usize).checked_mul(self.height.max(0) as usize);
+        size == Some(self.tiles.len())
+    }
+
+    /// where (x, y) is in the buffer. Off the map is a bug in the caller, so
+    /// it panics rather than quietly handing back a tile from another row
+    fn index(&self, x: i32, y: i32) -> usize {
+        let in_map = (0..self.width).contains(&x) && (0..self.height).contains(&y);
+        assert!(in_map, "({}, {}) is off the {}x{} map", x, y, self.width, self.height);
+        (y * self.width + x) as usize
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> &Tile {
+        &self.tiles[self.index(x, y)]
+    }
+
+    pub fn get_mut(&mut self, x: i32, y: i32) -> &mut Tile {
+        let index = self.index(x, y);
+        &mut self.tiles[index]
+    }
+
+    pub fn set(&mut self, x: i32, y: i32, tile: Tile) {
+        *self.get_mut(x, y) = tile;
+    }
+
+    /// every tile, in no particular order
+    pub fn tiles_mut(&mut self) -> impl Iterator<Item = &mut Tile> {
+        self.tiles.iter_mut()
+    }
+}
+
+/// How important a message is. Decides the color it's shown in, so that
+/// e.g. pickups always look the same no matter who reports them.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum Severity {
+    Info,
+    Good,
+    Warning,
+    Danger,
+    Critical,
+}
+
+impl Severity {
+    pub fn color(self) -> Color {
+        use Severity::*;
+        match self {
+            Info => WHITE,
+            Good => LIGHT_GREEN,
+            Warning => LIGHT_YELLOW,
+            Danger => LIGHT_RED,
+            Critical => RED,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Message {
+    pub text: String,
+    pub color: Color,
+    pub severity: Severity,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Messages {
-    pub messages: Vec<(String, Color)>,
+    pub messages: Vec<Message>,
+    /// how many messages the player has already had a chance to read
+    pub shown: usize,
 }
 
 impl Messages {
     pub fn new() -> Self {
-        Self { messages: vec![] }
+        Self { messages: vec![], shown: 0 }
+    }
+
+    /// add the new message, styled according to its severity
+    pub fn add<T: Into<String>>(&mut self, message: T, severity: Severity) {
+        self.messages.push(Message {
+            text: message.into(),
+            color: severity.color(),
+            severity,
+        });
     }
 
-    /// add the new message as a tuple, with the text and the color
-    pub fn add<T: Into<String>>(&mut self, message: T, color: Color) {
-        self.messages.push((message.into(), color));
+    /// add the new message with a custom color, for flavor text that
+    /// doesn't fit the usual palette (spell effects and the like)
+    pub fn add_colored<T: Into<String>>(&mut self, message: T, color: Color) {
+        self.messages.push(Message {
+            text: message.into(),
+            color,
+            severity: Severity::Info,
+        });
     }
 
     /// create a 'DoubleEndedIterator' over the messages
-    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &(String, Color)> {
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Message> {
         self.messages.iter()
     }
+
+    /// number of messages that arrived since the player last read the log
+    pub fn unshown(&self) -> usize {
+        self.messages.len() - self.shown
+    }
+
+    /// treat every queued message as read, e.g. to skip the "--more--"
+    /// prompt while fast-forwarding
+    pub fn mark_shown(&mut self) {
+        self.shown = self.messages.len();
+    }
 }
 
-/// A tile of the map and its properties
+impl Default for Messages {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Player-tweakable options, changed from the options menu
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Settings {
+    /// pause with "--more--" when messages don't all fit in the panel
+    pub more_prompt: bool,
+    /// percentage of max HP at which the low HP warning fires
+    pub low_hp_threshold: i32,
+    /// wait for a keypress when the low HP warning fires
+    pub low_hp_pause: bool,
+    /// pick up potions and scrolls just by walking over them
+    pub auto_pickup: bool,
+    /// also pick up equipment by walking over it
+    pub auto_pickup_equipment: bool,
+    /// frames-per-second cap
+    pub fps: i32,
+    /// speed of real-time effects, in percent of normal
+    pub animation_speed: i32,
+    /// how new levels are laid out
+    pub map_generator: MapGenerator,
+    /// show how long drawing each frame takes
+    pub show_frame_time: bool,
+    /// how many items the player can carry
+    pub inventory_cap: i32,
+    /// list who acts next turn in the panel
+    pub show_turn_order: bool,
+    /// show the player's position and which way the stairs are
+    pub show_compass: bool,
+    /// fade newly explored tiles in instead of showing them at once
+    pub reveal_animation: bool,
+    /// whether anything can step diagonally
+    pub movement: MovementMode,
+    /// whether carrying too much weight lowers the player's defense
+    pub encumbrance: bool,
+    /// how much experience each level takes
+    pub xp_curve: XpCurve,
+    /// what happens to equipment picked up for an empty slot
+    pub auto_equip: AutoEquip,
+    /// new monsters turn up on levels over time
+    pub wandering_monsters: bool,
+    /// the stairs up from the first level lead to the town
+    pub town: bool,
+    /// print the exact values over the HP and XP bars
+    pub bar_numbers: bool,
+    /// confused monsters stumble around hazards when they can
+    pub confused_avoid_hazards: bool,
+    /// what stops the player resting
+    pub interrupts: Interrupts,
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Settings {
+            more_prompt: true,
+            low_hp_threshold: LOW_HP_THRESHOLD,
+            low_hp_pause: true,
+            auto_pickup: false,
+            auto_pickup_equipment: false,
+            fps: LIMIT_FPS,
+            animation_speed: ANIMATION_SPEED,
+            map_generator: MapGenerator::RandomRooms,
+            show_frame_time: false,
+            inventory_cap: INVENTORY_CAP,
+            show_turn_order: false,
+            show_compass: false,
+            reveal_animation: true,
+            movement: MovementMode::EightWay,
+            encumbrance: false,
+            xp_curve: XpCurve::Linear,
+            auto_equip: AutoEquip::EmptySlots,
+            wandering_monsters: true,
+            town: true,
+            bar_numbers: true,
+            confused_avoid_hazards: true,
+            interrupts: Interrupts::new(),
+        }
+    }
+
+    /// Tell the backend about the FPS cap, kept within a safe range
+    pub fn apply_fps(&self) {
+        backend::set_fps(self.fps.clamp(MIN_FPS, MAX_FPS));
+    }
+
+    /// How long an effect lasting `millis` at normal speed should really take
+    pub fn animation_millis(&self, millis: u128) -> u128 {
+        millis * 100 / self.animation_speed.max(1) as u128
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What cuts a rest short. Whatever is set here, a rest always ends after
+/// `REST_TURN_CAP` turns, or as soon as a key is pressed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Interrupts {
+    /// a hostile monster comes into view
+    pub monster_in_view: bool,
+    /// the player gets hurt
+    pub damage: bool,
+    /// health drops to this percentage of max HP; 0 turns it off
+    pub hp_percent: i32,
+    /// an item comes into view
+    pub item_in_view: bool,
+    /// a message at least this serious shows up
+    pub message_severity: Option<Severity>,
+}
+
+impl Interrupts {
+    pub fn new() -> Self {
+        Interrupts {
+            monster_in_view: true,
+            damage: true,
+            hp_percent: 0,
+            item_in_view: false,
+            message_severity: Some(Severity::Danger),
+        }
+    }
+}
+
+impl Default for Interrupts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Options given on the command line when the game is launched
+#[derive(Clone, Copy, Debug)]
+pub struct LaunchOptions {
+    /// debug: start new games this deep instead of on level 1
+    pub start_level: u32,
+}
+
+impl LaunchOptions {
+    pub fn new() -> Self {
+        LaunchOptions { start_level: 1 }
+    }
+
+    /// Read the options from the command line, e.g. `--start-level 8`.
+    /// Anything that isn't understood is ignored.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+        let mut options = Self::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--start-level" {
+                if let Some(level) = args.next().and_then(|level| level.parse().ok()) {
+                    options.start_level = cmp::max(1, level);
+                }
+            }
+        }
+        options
+    }
+}
+
+impl Default for LaunchOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Toughen up a character who starts deeper than level 1, to about where
+/// they'd be had they fought their way down there
+pub fn scale_to_depth(fighter: &mut Fighter, level: u32) {
+    let skipped = level as i32 - 1;
+    fighter.base_max_hp += skipped * 10;
+    fighter.hp = fighter.base_max_hp;
+    fighter.base_power += skipped / 2;
+    fighter.base_defense += skipped / 3;
+}
+
+/// The kind of adventurer the player chose to be at the start of the game
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PlayerClass {
+    /// tougher and hits harder
+    Warrior,
+    /// sometimes lands critical hits
+    Rogue,
+    /// frail, but starts with scrolls and casts them with extra power
+    Mage,
+}
+
+pub const CLASSES: &[PlayerClass] = &[PlayerClass::Warrior, PlayerClass::Rogue, PlayerClass::Mage];
+
+impl PlayerClass {
+    pub fn name(&self) -> &str {
+        use PlayerClass::*;
+        match self {
+            Warrior => "Warrior",
+            Rogue => "Rogue",
+            Mage => "Mage",
+        }
+    }
+
+    pub fn description(&self) -> &str {
+        use PlayerClass::*;
+        match self {
+            Warrior => "more HP and power, whirlwind (W)",
+            Rogue => "critical hits",
+            Mage => "starts with scrolls, stronger spells",
+        }
+    }
+
+    /// What the player starts the game carrying
+    pub fn loadout(&self) -> &'static [Item] {
+        use PlayerClass::*;
+        match self {
+            Warrior => WARRIOR_LOADOUT,
+            Rogue => ROGUE_LOADOUT,
+            Mage => MAGE_LOADOUT,
+        }
+    }
+
+    /// The player's combat stats at the start of the game
+    pub fn fighter(&self) -> Fighter {
+        use PlayerClass::*;
+        let (max_hp, defense, power) = match self {
+            Warrior => (120, 1, 3),
+            Rogue => (100, 1, 2),
+            Mage => (80, 0, 2),
+        };
+        Fighter {
+            base_max_hp: max_hp,
+            hp: max_hp,
+            base_defense: defense,
+            base_power: power,
+            xp: 0,
+            on_death: DeathCallback::Player,
+        }
+    }
+}
+
+/// Running tallies of how the current game went, shown when it ends
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunStats {
+    /// monsters killed, by name
+    pub kills: HashMap<String, u32>,
+    pub items_used: u32,
+    pub deepest_level: u32,
+    pub turns: u32,
+    pub damage_dealt: i32,
+    pub damage_taken: i32,
+    /// the level the game began on; anything but 1 is a debug run
+    pub start_level: u32,
+    /// the debug menu was used at some point
+    pub debug_used: bool,
+}
+
+impl RunStats {
+    pub fn new() -> Self {
+        RunStats {
+            kills: HashMap::new(),
+            items_used: 0,
+            deepest_level: 1,
+            turns: 0,
+            damage_dealt: 0,
+            damage_taken: 0,
+            start_level: 1,
+            debug_used: false,
+        }
+    }
+
+    pub fn total_kills(&self) -> u32 {
+        self.kills.values().sum()
+    }
+
+    /// a line for each kind of monster killed and how many, sorted by name
+    pub fn kill_lines(&self) -> Vec<String> {
+        let mut kills: Vec<_> = self.kills.iter().collect();
+        kills.sort();
+        kills
+            .iter()
+            .map(|(name, count)| format!("  {}: {}", name, count))
+            .collect()
+    }
+}
+
+impl Default for RunStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which objects are on each tile, so finding them by position doesn't mean
+/// going through every object. It has to hear about every object that moves,
+/// comes or goes; after bigger shake-ups of the object list, just rebuild it.
+#[derive(Debug, Default)]
+pub struct SpatialIndex {
+    tiles: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl SpatialIndex {
+    pub fn rebuild(&mut self, objects: &[Object]) {
+        self.tiles.clear();
+        for (id, object) in objects.iter().enumerate() {
+            self.tiles.entry(object.pos()).or_default().push(id);
+        }
+    }
+
+    /// ids of the objects on the tile
+    pub fn at(&self, x: i32, y: i32) -> &[usize] {
+        self.tiles.get(&(x, y)).map_or(&[], |ids| ids)
+    }
+
+    /// a new object was pushed onto the object list
+    pub fn added(&mut self, id: usize, pos: (i32, i32)) {
+        self.tiles.entry(pos).or_default().push(id);
+    }
+
+    pub fn moved(&mut self, id: usize, from: (i32, i32), to: (i32, i32)) {
+        self.remove(id, from);
+        self.added(id, to);
+    }
+
+    /// `objects.swap_remove(id)` took out the object that was at `pos`,
+    /// and moved the last object into its slot
+    pub fn swap_removed(&mut self, id: usize, pos: (i32, i32), objects: &[Object]) {
+        self.remove(id, pos);
+        if id < objects.len() {
+            let moved_pos = objects[id].pos();
+            self.remove(objects.len(), moved_pos);
+            self.added(id, moved_pos);
+        }
+    }
+
+    fn remove(&mut self, id: usize, pos: (i32, i32)) {
+        if let Some(ids) = self.tiles.get_mut(&pos) {
+            ids.retain(|&other| other != id);
+            if ids.is_empty() {
+                self.tiles.remove(&pos);
+            }
+        }
+    }
+
+    /// whether the index agrees with where the objects really are
+    pub fn is_in_sync(&self, objects: &[Object]) -> bool {
+        let mut fresh = SpatialIndex::default();
+        fresh.rebuild(objects);
+        self.tiles.len() == fresh.tiles.len()
+            && fresh.tiles.iter().all(|(&(x, y), ids)| {
+                let mut ours = self.at(x, y).to_vec();
+                ours.sort_unstable();
+                ours == *ids
+            })
+    }
+}
+
+/// A tile of the map and its properties
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Tile {
     pub blocked: bool,
     pub explored: bool,
@@ -64,179 +575,1899 @@ pub struct Game {
     pub messages: Messages,
     pub inventory: Vec<Object>,
     pub dungeon_level: u32,
+    pub settings: Settings,
+    /// whether the low HP warning already fired for the current dip
+    pub low_hp_warned: bool,
+    /// monster types wiped out on the current level, which never turn up
+    /// there again
+    pub genocided: Vec<String>,
+    /// how each kind of unidentified item looks this game
+    pub appearances: Vec<(Item, String)>,
+    /// item kinds the player has figured out
+    pub identified: Vec<Item>,
+    pub stats: RunStats,
+    pub class: PlayerClass,
+    /// rebuilt from the objects when a game starts or is loaded
+    #[serde(skip)]
+    pub positions: SpatialIndex,
+    /// every level is generated from this and its depth
+    pub seed: usize,
+    #[serde(skip)]
+    pub preloaded: Option<PreloadedLevel>,
+    /// which branch of the dungeon the player is in
+    pub branch: Branch,
+    /// levels the player has left, by branch and depth, kept just as they
+    /// were left
+    pub visited: HashMap<Branch, HashMap<u32, LevelState>>,
+    /// debug: the player takes no damage
+    pub invulnerable: bool,
+    /// magical protection around the player
+    pub shield: Shield,
+    /// what the player learned in earlier games; kept in its own file
+    #[serde(skip)]
+    pub lore: Lore,
+    /// the trail the player left on the current level
+    pub scent: ScentMap,
+    /// caltrops and oil lying around the current level, for now
+    pub hazards: Vec<Hazard>,
+    /// wandering monsters that turned up since the player got to this level
+    pub wanderers: u32,
+    /// what the player left in the town's stash chest, kept between dives
+    pub stash: Vec<Object>,
+    /// what happened since the plugins last heard about it
+    #[serde(skip)]
+    pub events: Vec<GameEvent>,
 }
 
-pub fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
-    // fill map with "unblocked" tiles
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+/// What can be spread over a tile for a while
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HazardKind {
+    /// hurt whatever steps on them
+    Caltrops,
+    /// slippery, and burns away when fire reaches it
+    Oil,
+}
 
-    // Player is the first element, remove everything else.
-    // Note: works only when the player is the first object!
-    assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
-    objects.truncate(1);
+impl HazardKind {
+    pub fn char(self) -> char {
+        match self {
+            HazardKind::Caltrops => ':',
+            HazardKind::Oil => '~',
+        }
+    }
 
-    let mut rooms = vec![];
+    pub fn color(self) -> Color {
+        match self {
+            HazardKind::Caltrops => LIGHT_GREY,
+            HazardKind::Oil => DARK_AMBER,
+        }
+    }
+}
 
-    for _ in 0..MAX_ROOMS {
-        // random width and height
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        // random position without going out of the boundaries of the map
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+/// A hazard on one tile, and how many more turns it lasts
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Hazard {
+    pub kind: HazardKind,
+    pub pos: (i32, i32),
+    pub turns: i32,
+}
 
-        let new_room = Rect::new(x, y, w, h);
+/// the hazard lying on a tile, if any
+pub fn hazard_at(game: &Game, x: i32, y: i32) -> Option<HazardKind> {
+    game.hazards.iter().find(|hazard| hazard.pos == (x, y)).map(|hazard| hazard.kind)
+}
 
-        // run through the other rooms and see if the intersect with this one
-        let failed = rooms
-            .iter()
-            .any(|other_room| new_room.intersects_with(other_room));
+/// spread a hazard over a tile and the open tiles around it, covering up
+/// whatever was lying there before
+pub fn spread_hazard(kind: HazardKind, (x, y): (i32, i32), turns: i32, game: &mut Game) {
+    for tile_y in y - 1..=y + 1 {
+        for tile_x in x - 1..=x + 1 {
+            let on_map = (0..MAP_WIDTH).contains(&tile_x) && (0..MAP_HEIGHT).contains(&tile_y);
+            if !on_map || game.map.get(tile_x, tile_y).blocked {
+                continue;
+            }
+            game.hazards.retain(|hazard| hazard.pos != (tile_x, tile_y));
+            game.hazards.push(Hazard {
+                kind,
+                pos: (tile_x, tile_y),
+                turns,
+            });
+        }
+    }
+}
 
-        if !failed {
-            // this means there are no intersections, so room is valid
+/// count every hazard down by a turn, and clear away the ones that wore off
+pub fn tick_hazards(game: &mut Game) {
+    for hazard in &mut game.hazards {
+        hazard.turns -= 1;
+    }
+    game.hazards.retain(|hazard| hazard.turns > 0);
+}
 
-            // "paint" it to the map's tiles
-            create_room(new_room, &mut map);
+/// Where the player has walked lately on the current level, for monsters
+/// that hunt by smell. Rather than fading every tile every turn, it keeps
+/// when the player was last on each tile and works the strength out from that.
+#[derive(Serialize, Deserialize)]
+pub struct ScentMap {
+    /// one more than the turn the player last stood on each tile, row by
+    /// row like the map; 0 if never
+    last_here: Vec<u32>,
+}
 
-            // add some content to this room, such as monsters
-            place_objects(new_room, &map, objects, level);
+impl ScentMap {
+    pub fn new() -> Self {
+        ScentMap {
+            last_here: vec![0; (MAP_WIDTH * MAP_HEIGHT) as usize],
+        }
+    }
 
-            // center coordinates of the new room, will be useful later
-            let (new_x, new_y) = new_room.center();
+    /// where (x, y) is in the buffer, if it's on the map
+    fn index(x: i32, y: i32) -> Option<usize> {
+        let in_map = (0..MAP_WIDTH).contains(&x) && (0..MAP_HEIGHT).contains(&y);
+        Some((y * MAP_WIDTH + x) as usize).filter(|_| in_map)
+    }
 
-            if rooms.is_empty() {
-                // this is the first room, where the player stars at
-                objects[PLAYER].set_pos(new_x, new_y);
-            }
-            else {
-                // all rooms after the first:
-                // connect it to the previous room with a tunnel
+    pub fn mark(&mut self, (x, y): (i32, i32), turn: u32) {
+        let tile = ScentMap::index(x, y).and_then(|index| self.last_here.get_mut(index));
+        if let Some(last_here) = tile {
+            *last_here = turn + 1;
+        }
+    }
 
-                // center coords of the previous room
-                let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
+    /// how strong the scent on a tile still is, 0 once it has faded
+    pub fn strength(&self, x: i32, y: i32, turn: u32) -> u32 {
+        let tile = ScentMap::index(x, y).and_then(|index| self.last_here.get(index));
+        match tile.copied().unwrap_or(0) {
+            0 => 0,
+            last => SCENT_DURATION.saturating_sub(turn + 1 - last),
+        }
+    }
 
-                // toss a coin (random bool value -- true or false)
-                if rand::random() {
-                    // first move horizontally, then vertically
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
-                }
-                else {
-                    // first move vertically, then horizontally
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                }
-            }
+    /// forget the trail, e.g. when the player leaves the level
+    pub fn clear(&mut self) {
+        *self = ScentMap::new();
+    }
+}
 
-            // finally, append the new room to the list
-            rooms.push(new_room);
+impl Default for ScentMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the player has picked up over all their games, not just this one
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Lore {
+    /// item kinds the player has ever identified
+    pub identified: Vec<Item>,
+}
+
+impl Lore {
+    /// The lore saved by earlier games. If there's none, or it can't be
+    /// read, start over with a blank slate.
+    pub fn load() -> Self {
+        let mut json = String::new();
+        File::open(LORE_FILE)
+            .and_then(|mut file| file.read_to_string(&mut json))
+            .ok()
+            .and_then(|_| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let json = serde_json::to_string(self)?;
+        let mut file = File::create(LORE_FILE)?;
+        file.write_all(json.as_bytes())?;
+        Ok(())
+    }
+
+    /// remember an item kind for good
+    pub fn learn(&mut self, item: Item) {
+        if !self.identified.contains(&item) {
+            self.identified.push(item);
+            // losing the lore only costs some hints, so don't make a fuss
+            let _ = self.save();
         }
     }
+}
+
+/// A pool of points that soaks up damage before the player's HP does, until
+/// it's used up or runs out of turns
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Shield {
+    pub points: i32,
+    pub turns: i32,
+}
+
+impl Shield {
+    pub fn is_up(&self) -> bool {
+        self.points > 0 && self.turns > 0
+    }
 
-    // create stairs at the center of the last room
-    let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
-    let mut stairs = Object::new(last_room_x, last_room_y, '<', "stairs", WHITE, false);
-    stairs.always_visible = true;
-    objects.push(stairs);
+    /// soak up as much of `damage` as the shield can, returning what's left
+    /// over for the HP
+    pub fn absorb(&mut self, damage: i32) -> i32 {
+        if !self.is_up() {
+            return damage;
+        }
+        let absorbed = cmp::min(self.points, damage.max(0));
+        self.points -= absorbed;
+        damage - absorbed
+    }
 
-    map
+    /// count down a turn; returns whether the shield ran out of time just now
+    pub fn tick(&mut self) -> bool {
+        if !self.is_up() {
+            return false;
+        }
+        self.turns -= 1;
+        self.turns == 0
+    }
 }
 
-struct Transition {
-    level: u32,
-    value: u32,
+/// A level the player isn't on: its map, explored tiles and all, and
+/// everything on it except the player
+#[derive(Serialize, Deserialize)]
+pub struct LevelState {
+    pub map: Map,
+    pub objects: Vec<Object>,
+    /// monster types wiped out on this level
+    #[serde(default)]
+    pub genocided: Vec<String>,
 }
 
-/// Returns a value that depends on level. the table specifies
-/// what value occurs after each level, default is 0
-fn from_dungeon_level(table: &[Transition], level: u32) -> u32 {
-    table
-        .iter()
-        .rev()
-        .find(|transition| level >= transition.level)
-        .map_or(0, |transition| transition.value)
+impl Game {
+    /// A game just starting out on `map`, with nothing learned yet and no
+    /// lore from earlier games
+    pub fn new(
+        map: Map,
+        settings: Settings,
+        class: PlayerClass,
+        start_level: u32,
+        seed: usize,
+    ) -> Self {
+        Game {
+            map,
+            messages: Messages::new(),
+            inventory: vec![],
+            dungeon_level: start_level,
+            settings,
+            low_hp_warned: false,
+            genocided: vec![],
+            appearances: vec![],
+            identified: vec![],
+            stats: RunStats {
+                deepest_level: start_level,
+                start_level,
+                ..RunStats::new()
+            },
+            class,
+            positions: SpatialIndex::default(),
+            seed,
+            preloaded: None,
+            branch: Branch::Main,
+            visited: HashMap::new(),
+            invulnerable: false,
+            shield: Shield::default(),
+            lore: Lore::default(),
+            scent: ScentMap::new(),
+            hazards: vec![],
+            wanderers: 0,
+            stash: vec![],
+            events: vec![],
+        }
+    }
 }
 
-fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
-    // horizontal tunnel. 'min()' and 'max()' are used in case of x1 > x2
-    for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+/// A first-level warrior game on `map` for tests, with the first object
+/// brought to life as the player
+#[cfg(test)]
+pub fn test_game(map: Map, mut objects: Vec<Object>) -> (Game, Vec<Object>) {
+    objects[PLAYER].alive = true;
+    objects[PLAYER].fighter = Some(PlayerClass::Warrior.fighter());
+    let mut game = Game::new(map, Settings::new(), PlayerClass::Warrior, 1, 0);
+    game.positions.rebuild(&objects);
+    (game, objects)
+}
+
+/// A line of levels. The main dungeon goes down forever; other branches
+/// split off from it partway down and go on forever too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Branch {
+    Main,
+    /// overrun by the dead
+    Crypt,
+}
+
+impl Branch {
+    pub fn name(self) -> &'static str {
+        match self {
+            Branch::Main => "Dungeon",
+            Branch::Crypt => "Crypt",
+        }
+    }
+
+    /// the level of the main dungeon with the stairs into this branch
+    pub fn entrance(self) -> Option<u32> {
+        match self {
+            Branch::Main => None,
+            Branch::Crypt => Some(CRYPT_ENTRANCE_LEVEL),
+        }
+    }
+
+    /// what the stairs leading down into this branch are called
+    pub fn stairs_name(self) -> &'static str {
+        match self {
+            Branch::Main => "stairs",
+            Branch::Crypt => "crypt stairs",
+        }
+    }
+
+    /// where the down stairs called `name` lead, starting out from `self`
+    pub fn below(self, name: &str) -> Option<Branch> {
+        if name == "stairs" {
+            // the ordinary stairs keep to the branch the player is in
+            Some(self)
+        } else {
+            BRANCHES.iter().copied().find(|branch| branch.stairs_name() == name)
+        }
+    }
+}
+
+pub const BRANCHES: &[Branch] = &[Branch::Main, Branch::Crypt];
+
+/// Which ways the player and monsters can step
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MovementMode {
+    /// along the diagonals too
+    EightWay,
+    /// only up, down, left and right
+    FourWay,
+}
+
+impl MovementMode {
+    pub fn name(&self) -> &str {
+        match self {
+            MovementMode::EightWay => "8 directions",
+            MovementMode::FourWay => "4 directions",
+        }
+    }
+}
+
+/// Whether equipment picked up goes straight on, when nothing else is in its slot
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AutoEquip {
+    Off,
+    EmptySlots,
+    /// ask every time
+    Prompt,
+}
+
+impl AutoEquip {
+    pub fn name(&self) -> &str {
+        match self {
+            AutoEquip::Off => "off",
+            AutoEquip::EmptySlots => "empty slots",
+            AutoEquip::Prompt => "ask",
+        }
+    }
+}
+
+/// How fast the experience needed for the next level grows
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum XpCurve {
+    /// the same amount more every level
+    Linear,
+    /// more and more every level
+    Quadratic,
+    /// hand-picked amounts for the early levels
+    Table,
+}
+
+impl XpCurve {
+    pub fn name(&self) -> &str {
+        match self {
+            XpCurve::Linear => "linear",
+            XpCurve::Quadratic => "quadratic",
+            XpCurve::Table => "table",
+        }
+    }
+
+    /// the experience it takes to get from `level` to the next one. Only the
+    /// experience towards the next level is kept, so switching curves
+    /// leaves the player's level and experience as they were
+    pub fn level_up_xp(&self, level: i32) -> i32 {
+        match self {
+            XpCurve::Linear => LEVEL_UP_BASE + level * LEVEL_UP_FACTOR,
+            XpCurve::Quadratic => LEVEL_UP_BASE + level * level * LEVEL_UP_QUADRATIC_FACTOR,
+            XpCurve::Table => {
+                let last = LEVEL_UP_TABLE.len() - 1;
+                let index = cmp::max(level - 1, 0) as usize;
+                match LEVEL_UP_TABLE.get(index) {
+                    Some(&xp) => xp,
+                    None => {
+                        let step = LEVEL_UP_TABLE[last] - LEVEL_UP_TABLE[last - 1];
+                        LEVEL_UP_TABLE[last] + (index - last) as i32 * step
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What sets a level apart from the ones around it. It only depends on the
+/// seed, branch and depth, so it's known before the level is generated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LevelTheme {
+    Ordinary,
+    /// crawling with monsters
+    Lair,
+    /// richer in items than usual
+    Hoard,
+}
+
+impl LevelTheme {
+    pub fn of(seed: usize, branch: Branch, level: u32) -> Self {
+        // a stream of its own, so looking ahead doesn't disturb the level's rng
+        let mut rng = StdRng::from_seed(&[seed, level as usize, branch as usize, 1][..]);
+        match rng.gen_range(0, 100) {
+            0..=14 => LevelTheme::Lair,
+            15..=29 => LevelTheme::Hoard,
+            _ => LevelTheme::Ordinary,
+        }
+    }
+
+    /// what the player picks up from the stairs leading down to the level
+    pub fn hint(self) -> Option<&'static str> {
+        match self {
+            LevelTheme::Ordinary => None,
+            LevelTheme::Lair => Some("You hear a great many things stirring below..."),
+            LevelTheme::Hoard => Some("You catch a glint of treasure from the depths..."),
+        }
+    }
+
+    /// how many more monsters and items each room can have
+    fn extra_monsters(self) -> u32 {
+        if self == LevelTheme::Lair { 2 } else { 0 }
+    }
+
+    fn extra_items(self) -> u32 {
+        if self == LevelTheme::Hoard { 2 } else { 0 }
+    }
+}
+
+/// How the rooms of a level are laid out
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MapGenerator {
+    /// rooms dropped at random spots, each tunneled to the one before
+    RandomRooms,
+    /// the map split up into regions with a room in each
+    Bsp,
+}
+
+impl MapGenerator {
+    pub fn name(&self) -> &str {
+        use MapGenerator::*;
+        match self {
+            RandomRooms => "random rooms",
+            Bsp => "BSP",
+        }
+    }
+}
+
+pub fn make_map(
+    objects: &mut Vec<Object>,
+    branch: Branch,
+    level: u32,
+    theme: LevelTheme,
+    generator: MapGenerator,
+    genocided: &[String],
+    rng: &mut impl Rng,
+) -> Map {
+    // Player is the first element, remove everything else.
+    // Note: works only when the player is the first object!
+    assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
+    objects.truncate(1);
+
+    if level == TOWN_LEVEL {
+        return make_town(objects);
+    }
+
+    // deeper down, some levels are caverns instead of rooms
+    let cave = level >= CAVE_MIN_LEVEL && rng.gen_range(0, 100) < CAVE_CHANCE;
+
+    let (mut map, rooms, (start_x, start_y)) = lay_out_rooms(rng, |map, rng| {
+        if cave {
+            make_cave(map, rng)
+        } else {
+            match generator {
+                MapGenerator::RandomRooms => make_random_rooms(map, rng),
+                MapGenerator::Bsp => make_bsp_rooms(map, rng),
+            }
+        }
+    });
+    objects[PLAYER].set_pos(start_x, start_y);
+
+    // add some content to each room, such as monsters
+    for &room in &rooms {
+        place_objects(room, &map, objects, branch, level, theme, genocided, rng);
+    }
+
+    // every now and then, tuck a hand-designed vault in somewhere
+    if rng.gen_range(0, 100) < VAULT_CHANCE {
+        place_vault(&rooms, &mut map, objects, branch, level, genocided, rng);
+    }
+
+    // the dice can leave a level deserted, so make up for it
+    top_up_level(&rooms, &map, objects, branch, level, genocided, rng);
+
+    // create stairs at the center of the last room
+    let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
+    let mut stairs = Object::new(last_room_x, last_room_y, '<', "stairs", WHITE, false);
+    stairs.always_visible = true;
+    objects.push(stairs);
+
+    // branches split off from the main dungeon with stairs of their own
+    for &other in BRANCHES {
+        if branch == Branch::Main && other.entrance() == Some(level) {
+            place_branch_stairs(other, &rooms, &map, objects, (start_x, start_y), rng);
+        }
+    }
+
+    // and the way back up where the player comes in; from the first level
+    // that's the town
+    if level > TOWN_LEVEL {
+        let mut upstairs = Object::new(start_x, start_y, '>', "upstairs", WHITE, false);
+        upstairs.always_visible = true;
+        objects.push(upstairs);
+    }
+
+    // whatever the generator did, make sure the player can walk to
+    // everything on the level
+    connect_orphans(&mut map, objects, (start_x, start_y));
+
+    map
+}
+
+/// The town above the dungeon: the same open square every time, with the
+/// stairs down at one end and the stash chest by where the player arrives
+fn make_town(objects: &mut Vec<Object>) -> Map {
+    let mut map = Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+    let square = Rect::new(MAP_WIDTH / 2 - 15, MAP_HEIGHT / 2 - 8, 30, 16);
+    create_room(square, RoomShape::Rectangle, &mut map);
+    for x in square.x1..=square.x2 {
+        for y in square.y1..=square.y2 {
+            // nothing to discover here
+            map.get_mut(x, y).explored = true;
+        }
+    }
+
+    let (_, center_y) = square.center();
+    objects[PLAYER].set_pos(square.x1 + 3, center_y);
+
+    let mut chest = Object::new(square.x1 + 3, center_y - 2, '=', "stash chest", DARK_AMBER, true);
+    chest.always_visible = true;
+    objects.push(chest);
+
+    let mut stairs = Object::new(square.x2 - 3, center_y, '<', "stairs", WHITE, false);
+    stairs.always_visible = true;
+    objects.push(stairs);
+
+    map
+}
+
+/// Tunnel from each object the player can't walk to, the stairs among them,
+/// over to the nearest tile they can, until nothing is cut off
+fn connect_orphans(map: &mut Map, objects: &[Object], start: (i32, i32)) {
+    loop {
+        let reachable = match reachable_from(map, start) {
+            Some(reachable) => reachable,
+            None => return,
+        };
+        let mut connected = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        for &(x, y) in &reachable {
+            connected[x as usize][y as usize] = true;
+        }
+        let orphan = objects
+            .iter()
+            .skip(1)
+            .map(Object::pos)
+            .find(|&(x, y)| !connected[x as usize][y as usize]);
+        let (x, y) = match orphan {
+            Some(pos) => pos,
+            None => return,
+        };
+        let distance = |&&(to_x, to_y): &&(i32, i32)| (to_x - x).pow(2) + (to_y - y).pow(2);
+        let &(to_x, to_y) = reachable.iter().min_by_key(distance).unwrap();
+        create_h_tunnel(x, to_x, y, map);
+        create_v_tunnel(y, to_y, to_x, map);
+    }
+}
+
+/// The open tile closest to `preferred` that the stairs can be walked to from,
+/// or None if the stairs themselves ended up on a blocked tile
+fn safe_start_position(map: &Map, preferred: (i32, i32), stairs: (i32, i32)) -> Option<(i32, i32)> {
+    let reachable = reachable_from(map, stairs)?;
+    let distance = |(x, y): (i32, i32)| (x - preferred.0).pow(2) + (y - preferred.1).pow(2);
+    reachable.into_iter().min_by_key(|&tile| distance(tile))
+}
+
+/// Every open tile that can be walked to from `from`, nearest first, or None
+/// if `from` itself is blocked. Tiles only connect through their sides, so
+/// whatever is reachable can be walked to with either movement mode
+fn reachable_from(map: &Map, from: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+    let open = |x: i32, y: i32| {
+        (0..MAP_WIDTH).contains(&x)
+            && (0..MAP_HEIGHT).contains(&y)
+            && !map.get(x, y).blocked
+    };
+    if !open(from.0, from.1) {
+        return None;
+    }
+
+    // flood out from the starting tile
+    let mut seen = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    seen[from.0 as usize][from.1 as usize] = true;
+    let mut to_visit = VecDeque::new();
+    to_visit.push_back(from);
+    let mut reachable = vec![];
+    while let Some((x, y)) = to_visit.pop_front() {
+        reachable.push((x, y));
+        for &(dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let (next_x, next_y) = (x + dx, y + dy);
+            if open(next_x, next_y) && !seen[next_x as usize][next_y as usize] {
+                seen[next_x as usize][next_y as usize] = true;
+                to_visit.push_back((next_x, next_y));
+            }
+        }
+    }
+    Some(reachable)
+}
+
+/// Check a loaded game for a state it could never have reached in play. Small
+/// problems are fixed on the spot and described in the returned list; anything
+/// past saving is an error explaining why
+pub fn validate_game_state(
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> Result<Vec<String>, String> {
+    let right_size = game.map.is_whole()
+        && game.map.width() == MAP_WIDTH
+        && game.map.height() == MAP_HEIGHT;
+    if !right_size {
+        return Err("the map is the wrong size".to_string());
+    }
+    let floor: Vec<(i32, i32)> = (0..MAP_WIDTH)
+        .flat_map(|x| (0..MAP_HEIGHT).map(move |y| (x, y)))
+        .filter(|&(x, y)| !game.map.get(x, y).blocked)
+        .collect();
+    if floor.is_empty() {
+        return Err("the map has no floor".to_string());
+    }
+    let has_player = objects
+        .first()
+        .map(|player| player.name == "player" && player.fighter.is_some());
+    if has_player != Some(true) {
+        return Err("the player is missing".to_string());
+    }
+
+    let mut repairs = vec![];
+    let in_bounds =
+        |(x, y): (i32, i32)| (0..MAP_WIDTH).contains(&x) && (0..MAP_HEIGHT).contains(&y);
+
+    // anything off the map can never be reached, so let it go
+    let count = objects.len();
+    let mut index = 0;
+    objects.retain(|object| {
+        index += 1;
+        index == 1 || in_bounds(object.pos())
+    });
+    if objects.len() < count {
+        repairs.push(format!("Removed {} objects lying off the map.", count - objects.len()));
+    }
+
+    // the player has to stand somewhere they can move from
+    let (x, y) = objects[PLAYER].pos();
+    if !in_bounds((x, y)) || game.map.get(x, y).blocked {
+        let distance = |&&(to_x, to_y): &&(i32, i32)| (to_x - x).pow(2) + (to_y - y).pow(2);
+        let &(to_x, to_y) = floor.iter().min_by_key(distance).unwrap();
+        objects[PLAYER].set_pos(to_x, to_y);
+        repairs.push("Moved the player back onto open floor.".to_string());
+    }
+
+    // and needs a way further down
+    if !objects.iter().any(|object| object.name == "stairs") {
+        // as far away as the player can walk, as the stairs usually are
+        let reachable = reachable_from(&game.map, objects[PLAYER].pos()).unwrap();
+        let &(stairs_x, stairs_y) = reachable.last().unwrap();
+        let mut stairs = Object::new(stairs_x, stairs_y, '<', "stairs", WHITE, false);
+        stairs.always_visible = true;
+        objects.push(stairs);
+        repairs.push("Put back the missing stairs.".to_string());
+    }
+
+    Ok(repairs)
+}
+
+/// Put the stairs down into `branch` in the middle of a room the player can
+/// walk to, other than the first and last, which have the other stairs. If
+/// there's no such room, any free tile that can be walked to will do.
+fn place_branch_stairs(
+    branch: Branch,
+    rooms: &[Rect],
+    map: &Map,
+    objects: &mut Vec<Object>,
+    start: (i32, i32),
+    rng: &mut impl Rng,
+) {
+    let reachable = reachable_from(map, start).unwrap_or_default();
+    let taken = |pos: (i32, i32)| objects.iter().any(|object| object.pos() == pos);
+    let mut spots: Vec<(i32, i32)> = rooms
+        .iter()
+        .map(|room| room.center())
+        .filter(|&center| reachable.contains(&center) && !taken(center))
+        .collect();
+    if spots.is_empty() {
+        spots = reachable.into_iter().filter(|&pos| !taken(pos)).collect();
+    }
+    if spots.is_empty() {
+        return;
+    }
+    let (x, y) = spots[rng.gen_range(0, spots.len())];
+    let mut stairs = Object::new(x, y, '<', branch.stairs_name(), LIGHT_PURPLE, false);
+    stairs.always_visible = true;
+    objects.push(stairs);
+}
+
+/// Width and height for a room, both from a size range picked by its odds
+fn random_room_size(rng: &mut impl Rng) -> (i32, i32) {
+    use rand::distributions::Weighted;
+    let sizes = ROOM_SIZES
+        .iter()
+        .map(|&(min, max, weight)| Weighted {
+            weight,
+            item: (min, max),
+        })
+        .collect();
+    let (min, max) = choose_weighted(sizes, rng);
+    (rng.gen_range(min, max + 1), rng.gen_range(min, max + 1))
+}
+
+fn make_random_rooms(map: &mut Map, rng: &mut impl Rng) -> Vec<Rect> {
+    let mut rooms = vec![];
+
+    for _ in 0..MAX_ROOMS {
+        // random width and height
+        let (w, h) = random_room_size(rng);
+        // random position without going out of the boundaries of the map
+        let x = rng.gen_range(0, MAP_WIDTH - w);
+        let y = rng.gen_range(0, MAP_HEIGHT - h);
+
+        let new_room = Rect::new(x, y, w, h);
+
+        // run through the other rooms and see if the intersect with this one
+        let failed = rooms
+            .iter()
+            .any(|other_room| new_room.intersects_with(other_room));
+
+        if !failed {
+            // this means there are no intersections, so room is valid
+
+            // "paint" it to the map's tiles
+            create_room(new_room, RoomShape::random(rng), map);
+
+            if !rooms.is_empty() {
+                // all rooms after the first:
+                // connect it to the previous room with a tunnel
+
+                // the rooms placed before the previous one must stay untouched
+                let (others, prev_room) = rooms.split_at(rooms.len() - 1);
+                connect_rooms(prev_room[0], new_room, others, map, rng);
+            }
+
+            // finally, append the new room to the list
+            rooms.push(new_room);
+        }
+    }
+
+    rooms
+}
+
+/// Dig out a fresh layout with `dig` until the player can get from the
+/// first room to the stairs at the center of the last one, returning the map,
+/// its rooms and where the player starts. There's always at least one room
+fn lay_out_rooms<R: Rng>(
+    rng: &mut R,
+    mut dig: impl FnMut(&mut Map, &mut R) -> Vec<Rect>,
+) -> (Map, Vec<Rect>, (i32, i32)) {
+    loop {
+        // fill map with "unblocked" tiles
+        let mut map = Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+        let mut rooms = dig(&mut map, rng);
+        if rooms.is_empty() {
+            // nothing fit, so fall back on a single room in the middle
+            rooms.push(fallback_room(&mut map));
+        }
+
+        // the first room is where the player starts at, if they can get from
+        // there to the stairs; otherwise try again with a new layout
+        let stairs = rooms[rooms.len() - 1].center();
+        if let Some(start) = safe_start_position(&map, rooms[0].center(), stairs) {
+            return (map, rooms, start);
+        }
+    }
+}
+
+/// Dig out a room of the smallest size in the middle of the map, or as much
+/// of one as the map has space for
+fn fallback_room(map: &mut Map) -> Rect {
+    let w = cmp::max(2, cmp::min(ROOM_MIN_SIZE, MAP_WIDTH - 2));
+    let h = cmp::max(2, cmp::min(ROOM_MIN_SIZE, MAP_HEIGHT - 2));
+    let room = Rect::new((MAP_WIDTH - w) / 2, (MAP_HEIGHT - h) / 2, w, h);
+    create_room(room, RoomShape::Rectangle, map);
+    room
+}
+
+fn make_bsp_rooms(map: &mut Map, rng: &mut impl Rng) -> Vec<Rect> {
+    let mut rooms = vec![];
+    split_region(Rect::new(0, 0, MAP_WIDTH, MAP_HEIGHT), map, &mut rooms, rng);
+    rooms
+}
+
+/// Split a region of the map in two until the pieces are about room sized,
+/// then put a room in each piece and connect the two halves of every split.
+/// The region covers the tiles from (x1, y1) up to, but not including, (x2, y2).
+fn split_region(region: Rect, map: &mut Map, rooms: &mut Vec<Rect>, rng: &mut impl Rng) {
+    let w = region.x2 - region.x1;
+    let h = region.y2 - region.y1;
+    let can_split_x = w >= BSP_MIN_LEAF * 2;
+    let can_split_y = h >= BSP_MIN_LEAF * 2;
+
+    if (w <= BSP_MAX_LEAF && h <= BSP_MAX_LEAF) || !(can_split_x || can_split_y) {
+        // a leaf: the room's walls must stay inside the region, so that
+        // rooms in neighbouring regions never share a wall
+        let max_w = cmp::min(ROOM_MAX_SIZE, w - 1);
+        let max_h = cmp::min(ROOM_MAX_SIZE, h - 1);
+        if max_w < ROOM_MIN_SIZE || max_h < ROOM_MIN_SIZE {
+            // too cramped for a proper room
+            return;
+        }
+        let room_w = rng.gen_range(ROOM_MIN_SIZE, max_w + 1);
+        let room_h = rng.gen_range(ROOM_MIN_SIZE, max_h + 1);
+        let x = rng.gen_range(region.x1, region.x2 - room_w);
+        let y = rng.gen_range(region.y1, region.y2 - room_h);
+        let room = Rect::new(x, y, room_w, room_h);
+        create_room(room, RoomShape::random(rng), map);
+        rooms.push(room);
+        return;
+    }
+
+    // split across the longer side, so the pieces don't get too thin
+    let split_x = can_split_x && (!can_split_y || w > h);
+    let (first, second) = if split_x {
+        let at = rng
+            .gen_range(region.x1 + BSP_MIN_LEAF, region.x2 - BSP_MIN_LEAF + 1);
+        (
+            Rect { x2: at, ..region },
+            Rect { x1: at, ..region },
+        )
+    }
+    else {
+        let at = rng
+            .gen_range(region.y1 + BSP_MIN_LEAF, region.y2 - BSP_MIN_LEAF + 1);
+        (
+            Rect { y2: at, ..region },
+            Rect { y1: at, ..region },
+        )
+    };
+
+    let first_start = rooms.len();
+    split_region(first, map, rooms, rng);
+    let second_start = rooms.len();
+    split_region(second, map, rooms, rng);
+
+    // each half is connected within itself, so a single corridor between
+    // them keeps the whole tree connected
+    if first_start < second_start && second_start < rooms.len() {
+        let from = second_start - 1;
+        let to = second_start;
+        let others: Vec<Rect> = rooms
+            .iter()
+            .enumerate()
+            .filter(|&(id, _)| id != from && id != to)
+            .map(|(_, &room)| room)
+            .collect();
+        connect_rooms(rooms[from], rooms[to], &others, map, rng);
+    }
+}
+
+/// Add monsters and items until the level has at least the minimum of each,
+/// in random rooms with space left. The starting room is kept clear of
+/// extra monsters unless it's the only room there is.
+fn top_up_level(
+    rooms: &[Rect],
+    map: &Map,
+    objects: &mut Vec<Object>,
+    branch: Branch,
+    level: u32,
+    genocided: &[String],
+    rng: &mut impl Rng,
+) {
+    let monster_rooms = if rooms.len() > 1 { &rooms[1..] } else { rooms };
+    let monsters = objects.iter().filter(|object| object.ai.is_some()).count() as i32;
+    for _ in monsters..MIN_MONSTERS_PER_LEVEL {
+        if let Some((x, y)) = random_free_spot(monster_rooms, map, objects, rng) {
+            objects.extend(random_monster(x, y, branch, level, genocided, rng));
+        }
+    }
+
+    // poison doesn't count as something worth finding
+    let is_useful = |object: &Object| object.item.is_some() && object.item != Some(Item::Poison);
+    let items = objects.iter().filter(|object| is_useful(object)).count() as i32;
+    for _ in items..MIN_ITEMS_PER_LEVEL {
+        if let Some((x, y)) = random_free_spot(rooms, map, objects, rng) {
+            let mut item = random_item(x, y, level, rng);
+            while !is_useful(&item) {
+                item = random_item(x, y, level, rng);
+            }
+            objects.push(item);
+        }
+    }
+}
+
+/// Every so often, bring in a monster from somewhere the player can't see,
+/// heading for where the player is, so no level stays safe for good
+pub fn spawn_wanderer(fov: &dyn Visibility, game: &mut Game, objects: &mut Vec<Object>) {
+    let interval = cmp::max(
+        WANDER_BASE_TURNS.saturating_sub(game.dungeon_level * WANDER_TURNS_PER_LEVEL),
+        WANDER_MIN_TURNS,
+    );
+    let due = game.stats.turns.is_multiple_of(interval);
+    let in_town = game.dungeon_level == TOWN_LEVEL;
+    if !game.settings.wandering_monsters || in_town || !due || game.wanderers >= WANDERER_CAP {
+        return;
+    }
+
+    // somewhere out of sight, and well away from the player. A stream of its
+    // own for each turn, so a replayed game gets the same visitors
+    let (branch, level) = (game.branch, game.dungeon_level);
+    let turn = game.stats.turns as usize;
+    let rng = &mut StdRng::from_seed(&[game.seed, level as usize, branch as usize, 2, turn][..]);
+    let player = &objects[PLAYER];
+    let spot = (0..FREE_SPOT_TRIES)
+        .map(|_| (rng.gen_range(0, MAP_WIDTH), rng.gen_range(0, MAP_HEIGHT)))
+        .find(|&(x, y)| {
+            !fov.is_in_fov(x, y)
+                && player.distance(x, y) > 2.0
+                && !is_tile_blocked(x, y, game, objects)
+        });
+    if let Some((x, y)) = spot {
+        let mut monster = match random_monster(x, y, branch, level, &game.genocided, rng) {
+            Some(monster) => monster,
+            None => return,
+        };
+        // it keeps its own way of fighting for when it finds the player
+        let then = Box::new(monster.ai.take().unwrap_or(Ai::Basic));
+        monster.ai = Some(Ai::Hunting {
+            last_seen: player.pos(),
+            num_turns: WANDER_PURSUIT_TURNS,
+            then,
+        });
+        game.wanderers += 1;
+        game.events.push(GameEvent::Spawn(objects.len()));
+        game.positions.added(objects.len(), (x, y));
+        objects.push(monster);
+    }
+}
+
+/// A random unblocked tile inside one of the rooms, if one turns up
+fn random_free_spot(
+    rooms: &[Rect],
+    map: &Map,
+    objects: &[Object],
+    rng: &mut impl Rng,
+) -> Option<(i32, i32)> {
+    for _ in 0..FREE_SPOT_TRIES {
+        let room = rooms[rng.gen_range(0, rooms.len())];
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects)
+            && !objects.iter().any(|object| object.pos() == (x, y))
+        {
+            return Some((x, y));
+        }
+    }
+    None
+}
+
+/// Stamp a random vault template onto a part of the map nothing was dug
+/// out of yet, with a corridor from its entrance to the nearest room.
+/// Gives up quietly if no such spot turns up.
+fn place_vault(
+    rooms: &[Rect],
+    map: &mut Map,
+    objects: &mut Vec<Object>,
+    branch: Branch,
+    level: u32,
+    genocided: &[String],
+    rng: &mut impl Rng,
+) {
+    let template = VAULTS[rng.gen_range(0, VAULTS.len())];
+    let w = template.iter().map(|row| row.len()).max().unwrap_or(0) as i32;
+    let h = template.len() as i32;
+    if w + 2 > MAP_WIDTH || h + 2 > MAP_HEIGHT {
+        return;
+    }
+    let entrance = template.iter().enumerate().find_map(|(dy, row)| {
+        row.chars()
+            .position(|c| c == 'D')
+            .map(|dx| (dx as i32, dy as i32))
+    });
+    let (entrance_dx, entrance_dy) = match entrance {
+        Some(offset) => offset,
+        None => return,
+    };
+
+    for _ in 0..VAULT_PLACEMENT_TRIES {
+        // keep off the edge of the map, so there's always a way out of the entrance
+        let x = rng.gen_range(1, MAP_WIDTH - w);
+        let y = rng.gen_range(1, MAP_HEIGHT - h);
+        let vault = Rect::new(x, y, w - 1, h - 1);
+
+        let untouched = !rooms.iter().any(|room| vault.intersects_with(room))
+            && (x..x + w).all(|tile_x| {
+                (y..y + h).all(|tile_y| map.get(tile_x, tile_y).blocked)
+            });
+        if !untouched {
+            continue;
+        }
+
+        // dig the way in first, so a vault that can't be reached is never placed
+        let (target_id, target) = rooms
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, room)| {
+                let (room_x, room_y) = room.center();
+                let (vault_x, vault_y) = vault.center();
+                (room_x - vault_x).pow(2) + (room_y - vault_y).pow(2)
+            })
+            .unwrap();
+        let in_the_way = |tile_x: i32, tile_y: i32| {
+            vault.contains(tile_x, tile_y)
+                || rooms
+                    .iter()
+                    .enumerate()
+                    .any(|(id, room)| id != target_id && room.contains(tile_x, tile_y))
+        };
+        let entrance = (x + entrance_dx, y + entrance_dy);
+        let path = match find_corridor(entrance, target.center(), in_the_way) {
+            Some(path) => path,
+            None => continue,
+        };
+        for (tile_x, tile_y) in path {
+            map.set(tile_x, tile_y, Tile::empty());
+        }
+
+        // '.' is floor, 'D' the entrance, 'M' a monster and 'I' an item,
+        // anything else stays solid wall
+        for (dy, row) in template.iter().enumerate() {
+            for (dx, c) in row.chars().enumerate() {
+                let (tile_x, tile_y) = (x + dx as i32, y + dy as i32);
+                match c {
+                    '.' | 'D' => {}
+                    'M' => {
+                        // vault monsters keep to the vault until they spot the player
+                        let monster =
+                            random_monster(tile_x, tile_y, branch, level, genocided, rng);
+                        if let Some(mut monster) = monster {
+                            let then = monster.ai.take().unwrap_or(Ai::Basic);
+                            let guard = Guard {
+                                post: (tile_x, tile_y),
+                                then,
+                            };
+                            monster.ai = Some(Ai::Custom(Brain(Box::new(guard))));
+                            objects.push(monster);
+                        }
+                    }
+                    'I' => objects.push(random_item(tile_x, tile_y, level, rng)),
+                    _ => continue,
+                }
+                map.set(tile_x, tile_y, Tile::empty());
+            }
+        }
+        return;
+    }
+}
+
+struct Transition {
+    level: u32,
+    value: u32,
+}
+
+/// Returns a value that depends on level. the table specifies
+/// what value occurs after each level, default is 0
+fn from_dungeon_level(table: &[Transition], level: u32) -> u32 {
+    table
+        .iter()
+        .rev()
+        .find(|transition| level >= transition.level)
+        .map_or(0, |transition| transition.value)
+}
+
+/// Pick one entry from a random table. Entries with no chance at this level
+/// are dropped first, so a table where only some kinds have shown up yet
+/// still picks fairly among those. The table must have at least one entry
+/// with a chance.
+fn choose_weighted<T: Clone>(
+    mut chances: Vec<rand::distributions::Weighted<T>>,
+    rng: &mut impl Rng,
+) -> T {
+    use rand::distributions::{IndependentSample, WeightedChoice};
+
+    chances.retain(|chance| chance.weight > 0);
+    WeightedChoice::new(&mut chances).ind_sample(rng)
+}
+
+/// Grow a cave: scatter walls at random, smooth them over with a cellular
+/// automaton, and tunnel the caverns that come out of it together. The
+/// returned rooms are squares of the cave, each centered on open floor, for
+/// stocking the level like any other
+fn make_cave(map: &mut Map, rng: &mut impl Rng) -> Vec<Rect> {
+    // the outer edge stays solid
+    for y in 1..MAP_HEIGHT - 1 {
+        for x in 1..MAP_WIDTH - 1 {
+            if rng.gen_range(0, 100) >= CAVE_WALL_CHANCE {
+                map.set(x, y, Tile::empty());
+            }
+        }
+    }
+    for _ in 0..CAVE_SMOOTHING_PASSES {
+        smooth_cave(map);
+    }
+    connect_caverns(map);
+
+    let mut rooms = vec![];
+    for area_y in (0..MAP_HEIGHT).step_by(CAVE_AREA_SIZE as usize) {
+        for area_x in (0..MAP_WIDTH).step_by(CAVE_AREA_SIZE as usize) {
+            let area = Rect::new(area_x, area_y, CAVE_AREA_SIZE, CAVE_AREA_SIZE);
+            let (center_x, center_y) = area.center();
+            let distance = |&(x, y): &(i32, i32)| (x - center_x).pow(2) + (y - center_y).pow(2);
+            let open_spot = (area.y1..cmp::min(area.y2, MAP_HEIGHT))
+                .flat_map(|y| (area.x1..cmp::min(area.x2, MAP_WIDTH)).map(move |x| (x, y)))
+                .filter(|&(x, y)| !map.get(x, y).blocked)
+                .min_by_key(distance);
+            if let Some((x, y)) = open_spot {
+                // as big as fits on the map around that spot
+                let radius = [CAVE_AREA_SIZE / 2, x, y, MAP_WIDTH - 1 - x, MAP_HEIGHT - 1 - y]
+                    .iter()
+                    .copied()
+                    .min()
+                    .unwrap();
+                rooms.push(Rect::new(x - radius, y - radius, radius * 2, radius * 2));
+            }
+        }
+    }
+    rooms
+}
+
+/// One step of the cave automaton: a tile turns to wall when most of the
+/// tiles around it, itself included, are walls, and to floor otherwise
+fn smooth_cave(map: &mut Map) {
+    let before = map.clone();
+    for y in 1..MAP_HEIGHT - 1 {
+        for x in 1..MAP_WIDTH - 1 {
+            let mut walls = 0;
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    if before.get(x + dx, y + dy).blocked {
+                        walls += 1;
+                    }
+                }
+            }
+            let tile = if walls >= 5 { Tile::wall() } else { Tile::empty() };
+            map.set(x, y, tile);
+        }
+    }
+}
+
+/// Fill in the cave's smallest pockets, then tunnel each cavern left through
+/// to the biggest one so the whole cave can be walked
+fn connect_caverns(map: &mut Map) {
+    let mut seen = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut caverns = vec![];
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            if map.get(x, y).blocked || seen[x as usize][y as usize] {
+                continue;
+            }
+            let cavern = reachable_from(map, (x, y)).unwrap();
+            for &(x, y) in &cavern {
+                seen[x as usize][y as usize] = true;
+            }
+            caverns.push(cavern);
+        }
+    }
+    caverns.sort_by_key(|cavern| cmp::Reverse(cavern.len()));
+
+    let mut caverns = caverns.into_iter();
+    let mut joined = match caverns.next() {
+        Some(biggest) => biggest,
+        None => return,
+    };
+    for cavern in caverns {
+        if cavern.len() < CAVE_MIN_REGION {
+            for &(x, y) in &cavern {
+                map.set(x, y, Tile::wall());
+            }
+            continue;
+        }
+        let (x, y) = cavern[0];
+        let distance = |&&(to_x, to_y): &&(i32, i32)| (to_x - x).pow(2) + (to_y - y).pow(2);
+        let &(to_x, to_y) = joined.iter().min_by_key(distance).unwrap();
+        create_h_tunnel(x, to_x, y, map);
+        create_v_tunnel(y, to_y, to_x, map);
+        joined.extend(cavern);
+    }
+}
+
+/// Dig a corridor between the centers of two rooms without slicing through
+/// any of the `others`, so corridors only open into rooms through their walls
+fn connect_rooms(
+    prev_room: Rect,
+    new_room: Rect,
+    others: &[Rect],
+    map: &mut Map,
+    rng: &mut impl Rng,
+) {
+    let (prev_x, prev_y) = prev_room.center();
+    let (new_x, new_y) = new_room.center();
+
+    let in_other_room = |x: i32, y: i32| others.iter().any(|room| room.contains(x, y));
+    let h_clear = |x1: i32, x2: i32, y: i32| {
+        (cmp::min(x1, x2)..=cmp::max(x1, x2)).all(|x| !in_other_room(x, y))
+    };
+    let v_clear = |y1: i32, y2: i32, x: i32| {
+        (cmp::min(y1, y2)..=cmp::max(y1, y2)).all(|y| !in_other_room(x, y))
+    };
+    if rng.gen_range(0, 100) < DIAGONAL_TUNNEL_CHANCE {
+        let path = diagonal_path(prev_x, prev_y, new_x, new_y);
+        if path.iter().all(|&(x, y)| !in_other_room(x, y)) {
+            create_diagonal_tunnel(prev_x, prev_y, new_x, new_y, map);
+            return;
+        }
+    }
+
+    let h_first_clear = h_clear(prev_x, new_x, prev_y) && v_clear(prev_y, new_y, new_x);
+    let v_first_clear = v_clear(prev_y, new_y, prev_x) && h_clear(prev_x, new_x, new_y);
+
+    let h_first = match (h_first_clear, v_first_clear) {
+        (true, false) => true,
+        (false, true) => false,
+        // toss a coin (random bool value -- true or false)
+        (true, true) => rng.gen(),
+        (false, false) => {
+            // neither straight route is clean, so walk around the rooms in the way
+            if let Some(path) = find_corridor((prev_x, prev_y), (new_x, new_y), in_other_room) {
+                for (x, y) in path {
+                    map.set(x, y, Tile::empty());
+                }
+                return;
+            }
+            // boxed in: cutting through a room beats leaving this one unreachable
+            rng.gen()
+        }
+    };
+
+    if h_first {
+        // first move horizontally, then vertically
+        create_h_tunnel(prev_x, new_x, prev_y, map);
+        create_v_tunnel(prev_y, new_y, new_x, map);
+    }
+    else {
+        // first move vertically, then horizontally
+        create_v_tunnel(prev_y, new_y, prev_x, map);
+        create_h_tunnel(prev_x, new_x, new_y, map);
+    }
+}
+
+/// Shortest 4-directional path between two tiles that avoids `forbidden` ones
+/// and the outer edge of the map
+fn find_corridor(
+    from: (i32, i32),
+    to: (i32, i32),
+    forbidden: impl Fn(i32, i32) -> bool,
+) -> Option<Vec<(i32, i32)>> {
+    let mut came_from = vec![vec![None; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    came_from[from.0 as usize][from.1 as usize] = Some(from);
+    let mut to_visit = VecDeque::new();
+    to_visit.push_back(from);
+    while let Some((x, y)) = to_visit.pop_front() {
+        if (x, y) == to {
+            // retrace the steps back to the start
+            let mut path = vec![to];
+            let mut current = to;
+            while current != from {
+                current = came_from[current.0 as usize][current.1 as usize].unwrap();
+                path.push(current);
+            }
+            return Some(path);
+        }
+        for &(dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let (next_x, next_y) = (x + dx, y + dy);
+            let inside =
+                (1..MAP_WIDTH - 1).contains(&next_x) && (1..MAP_HEIGHT - 1).contains(&next_y);
+            if inside
+                && came_from[next_x as usize][next_y as usize].is_none()
+                && !forbidden(next_x, next_y)
+            {
+                came_from[next_x as usize][next_y as usize] = Some((x, y));
+                to_visit.push_back((next_x, next_y));
+            }
+        }
+    }
+    None
+}
+
+fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+    // horizontal tunnel. 'min()' and 'max()' are used in case of x1 > x2
+    for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
+        map.set(x, y, Tile::empty());
+    }
+}
+
+fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
+    // horizontal tunnel. 'min()' and 'max()' are used in case of x1 > x2
+    for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
+        map.set(x, y, Tile::empty());
+    }
+}
+
+fn create_diagonal_tunnel(x1: i32, y1: i32, x2: i32, y2: i32, map: &mut Map) {
+    for (x, y) in diagonal_path(x1, y1, x2, y2) {
+        map.set(x, y, Tile::empty());
+    }
+}
+
+/// the tiles of a straight line from one point to another. Every diagonal
+/// step also takes the tile beside it, so the path is as easy to walk when
+/// moving four ways as it is when moving eight ways
+fn diagonal_path(x1: i32, y1: i32, x2: i32, y2: i32) -> Vec<(i32, i32)> {
+    let mut path = vec![(x1, y1)];
+    let (mut last_x, mut last_y) = (x1, y1);
+    for (x, y) in Line::new((x1, y1), (x2, y2)) {
+        if x != last_x && y != last_y {
+            path.push((x, last_y));
+        }
+        path.push((x, y));
+        last_x = x;
+        last_y = y;
+    }
+    path
+}
+
+fn player_move_or_attack(
+    dx: i32,
+    dy: i32,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) {
+    // the coords the player is moving to/attacking
+    let x = objects[PLAYER].x + dx;
+    let y = objects[PLAYER].y + dy;
+
+    // try to find an attackable object there
+    let target_id = game
+        .positions
+        .at(x, y)
+        .iter()
+        .copied()
+        .find(|&id| objects[id].fighter.is_some());
+
+    // the town's stash chest and any unopened container open when the
+    // player walks into them
+    let at_stash = game.positions.at(x, y).iter().any(|&id| objects[id].name == "stash chest");
+    let container_id = game
+        .positions
+        .at(x, y)
+        .iter()
+        .copied()
+        .find(|&id| objects[id].contents.is_some());
+
+    // attack if target found, move otherwise
+    match target_id {
+        None if at_stash => {
+            open_stash(tcod, game, objects);
+        }
+        None if container_id.is_some() => {
+            open_container(container_id.unwrap(), game, objects);
+        }
+        Some(target_id) if objects[target_id].ally => {
+            // swap places with the pet instead of attacking it
+            let (player_x, player_y) = objects[PLAYER].pos();
+            move_object(target_id, player_x, player_y, game, objects);
+            move_object(PLAYER, x, y, game, objects);
+        }
+        Some(target_id) => {
+            let (player, target) = mut_two(PLAYER, target_id, objects);
+            player.attack(target, game);
+        }
+        None => match reach_target(dx, dy, game, objects) {
+            Some(target_id) => {
+                // a reach weapon strikes over the open tile instead of stepping onto it
+                let (player, target) = mut_two(PLAYER, target_id, objects);
+                player.attack(target, game);
+            }
+            None => {
+                move_by(PLAYER, dx, dy, game, objects);
+                if objects[PLAYER].pos() == (x, y) {
+                    auto_pickup(tcod, game, objects);
+                    peek_below(game, objects);
+                }
+            }
+        },
+    }
+}
+
+/// on stepping onto stairs down, hint at what the level they lead to holds
+fn peek_below(game: &mut Game, objects: &[Object]) {
+    let below = game
+        .positions
+        .at(objects[PLAYER].x, objects[PLAYER].y)
+        .iter()
+        .find_map(|&id| game.branch.below(&objects[id].name));
+    let hint = below
+        .and_then(|branch| LevelTheme::of(game.seed, branch, game.dungeon_level + 1).hint());
+    if let Some(hint) = hint {
+        game.messages.add_colored(hint, LIGHT_VIOLET);
+    }
+}
+
+/// swing at every hostile monster around the player, all in one turn.
+/// returns whether there was anything to hit
+fn whirlwind_attack(game: &mut Game, objects: &mut [Object]) -> bool {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let mut targets = vec![];
+    for y in player_y - 1..=player_y + 1 {
+        for x in player_x - 1..=player_x + 1 {
+            targets.extend(game.positions.at(x, y).iter().copied().filter(|&id| {
+                id != PLAYER && objects[id].fighter.is_some() && !objects[id].ally
+            }));
+        }
+    }
+    if targets.is_empty() {
+        game.messages
+            .add("There is nothing around you to hit.", Severity::Warning);
+        return false;
+    }
+
+    // one line for the whole swing, rather than one per monster
+    let damages: Vec<i32> = targets
+        .iter()
+        .map(|&id| objects[PLAYER].damage_against(&objects[id], game))
+        .collect();
+    game.messages.add(
+        format!(
+            "You whirl around, hitting {} {} for {} damage in all.",
+            targets.len(),
+            if targets.len() == 1 { "enemy" } else { "enemies" },
+            damages.iter().filter(|&&damage| damage > 0).sum::<i32>(),
+        ),
+        Severity::Info,
+    );
+    for (&id, &damage) in targets.iter().zip(&damages) {
+        let (player, target) = mut_two(PLAYER, id, objects);
+        player.deal_damage(target, damage, game);
+        if damage > 0 {
+            player.inflict_on_hit(target, game);
+        }
+    }
+    true
+}
+
+/// the hostile monster the player's reach weapon can hit in the given
+/// direction, if nothing stands in the way. plain weapons only reach next door
+fn reach_target(dx: i32, dy: i32, game: &Game, objects: &[Object]) -> Option<usize> {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    for distance in 1..=objects[PLAYER].reach(game) {
+        let (x, y) = (player_x + dx * distance, player_y + dy * distance);
+        let target_id = game
+            .positions
+            .at(x, y)
+            .iter()
+            .copied()
+            .find(|&id| objects[id].fighter.is_some());
+        if distance > 1 && target_id.is_some() {
+            return target_id.filter(|&id| !objects[id].ally);
+        }
+        // walls and creatures block the weapon
+        if is_tile_blocked(x, y, game, objects) {
+            return None;
+        }
+    }
+    None
+}
+
+/// pick up whatever the player stepped on, if auto-pickup is on for that kind of item
+fn auto_pickup(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    if !game.settings.auto_pickup {
+        return;
+    }
+    let pick_equipment = game.settings.auto_pickup_equipment;
+    pick_up_all(tcod, game, objects, |item| pick_equipment || item.equipment.is_none());
+}
+
+/// Open a chest or barrel, using up a key if it's locked, and tip out what
+/// was inside around it. Whatever finds no room on the floor goes straight
+/// to the player
+fn open_container(container_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    if objects[container_id].locked {
+        let key = game.inventory.iter().position(|item| item.item == Some(Item::Key));
+        match key {
+            Some(key) => {
+                game.inventory.remove(key);
+                objects[container_id].locked = false;
+                game.messages.add("You unlock the chest with your key.", Severity::Info);
+            }
+            None => {
+                game.messages.add("The chest is locked. You need a key.", Severity::Warning);
+                return;
+            }
+        }
+    }
+
+    let container = &mut objects[container_id];
+    let mut contents = container.contents.take().unwrap_or_default();
+    let name = container.name.trim_start_matches("locked ").to_string();
+    container.name = format!("open {}", name);
+    container.blocks = false;
+    let (x, y) = container.pos();
+    if contents.is_empty() {
+        game.messages.add(format!("The {} is empty.", name), Severity::Info);
+        return;
+    }
+    game.messages.add(format!("You open the {}.", name), Severity::Info);
+
+    // the player only learns what they are when they see them
+    disguise_unidentified(game, &mut contents);
+    for mut item in contents {
+        match free_tile_near(x, y, &game.map, objects) {
+            Some((item_x, item_y)) if (item_x - x).abs() <= 1 && (item_y - y).abs() <= 1 => {
+                item.set_pos(item_x, item_y);
+                game.messages.add(format!("Out falls a {}.", item.name), Severity::Info);
+                game.positions.added(objects.len(), item.pos());
+                objects.push(item);
+            }
+            _ if (game.inventory.len() as i32) < game.settings.inventory_cap => {
+                game.messages.add(format!("You take a {}.", item.name), Severity::Good);
+                game.inventory.push(item);
+            }
+            _ => {
+                // no room anywhere else, so at the player's feet it goes
+                let (player_x, player_y) = objects[PLAYER].pos();
+                item.set_pos(player_x, player_y);
+                game.messages.add(format!("A {} falls at your feet.", item.name), Severity::Info);
+                game.positions.added(objects.len(), item.pos());
+                objects.push(item);
+            }
+        }
+    }
+}
+
+/// Put things into the town's stash chest, or take them back out, until the
+/// player closes it
+fn open_stash(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    loop {
+        let header = format!("Stash chest ({}/{})\n", game.stash.len(), STASH_CAP);
+        let choice = menu(
+            &header,
+            &["Put something in", "Take something out"],
+            INVENTORY_WIDTH,
+            &mut *tcod.renderer,
+        );
+        match choice {
+            Some(0) => {
+                let inventory_index = inventory_menu(
+                    game,
+                    &objects[PLAYER],
+                    "Press the key next to an item to stash it, or any other to cancel.\n",
+                    &mut *tcod.renderer,
+                );
+                if let Some(inventory_index) = inventory_index {
+                    if game.stash.len() >= STASH_CAP {
+                        game.messages.add("The stash chest is full.", Severity::Warning);
+                        continue;
+                    }
+                    let mut item = game.inventory.remove(inventory_index);
+                    if item.equipment.is_some() {
+                        item.dequip(&mut game.messages);
+                    }
+                    game.messages
+                        .add(format!("You put the {} in the stash.", item.name), Severity::Info);
+                    game.stash.push(item);
+                }
+            }
+            Some(1) => {
+                if game.stash.is_empty() {
+                    let empty = ["The chest is empty."];
+                    menu("Stash chest\n", &empty, INVENTORY_WIDTH, &mut *tcod.renderer);
+                    continue;
+                }
+                let names: Vec<&str> = game.stash.iter().map(|item| item.name.as_str()).collect();
+                let stash_index = menu(
+                    "Press the key next to an item to take it, or any other to cancel.\n",
+                    &names,
+                    INVENTORY_WIDTH,
+                    &mut *tcod.renderer,
+                );
+                if let Some(stash_index) = stash_index {
+                    if game.inventory.len() as i32 >= game.settings.inventory_cap {
+                        game.messages.add("Your inventory is full.", Severity::Warning);
+                        continue;
+                    }
+                    let item = game.stash.remove(stash_index);
+                    game.messages
+                        .add(format!("You take the {} from the stash.", item.name), Severity::Info);
+                    game.inventory.push(item);
+                }
+            }
+            _ => break,
+        }
+    }
+}
+
+/// pick up every item on the player's tile that passes the filter, stopping
+/// once the inventory is full
+fn pick_up_all(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+    wanted: impl Fn(&Object) -> bool,
+) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let on_tile = |object: &Object| object.item.is_some() && wanted(object);
+    loop {
+        let item_id = game
+            .positions
+            .at(player_x, player_y)
+            .iter()
+            .copied()
+            .find(|&id| on_tile(&objects[id]));
+        let item_id = match item_id {
+            Some(item_id) => item_id,
+            None => break,
+        };
+        let mimic = objects[item_id].disguise.is_some();
+        if !pick_item_up(item_id, tcod, game, objects) {
+            if mimic {
+                // not a full inventory, but a fight: the rest can wait
+                break;
+            }
+            let left = game
+                .positions
+                .at(player_x, player_y)
+                .iter()
+                .filter(|&&id| on_tile(&objects[id]))
+                .count();
+            if left > 1 {
+                game.messages.add(
+                    format!("{} items are left on the floor.", left),
+                    Severity::Warning,
+                );
+            }
+            break;
+        }
     }
 }
 
-fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
-    // horizontal tunnel. 'min()' and 'max()' are used in case of x1 > x2
-    for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+/// drop every carried item of the same kind as the given one
+fn drop_all_of_kind(inventory_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    let kind = game.inventory[inventory_id].item;
+    while let Some(inventory_id) = game.inventory.iter().position(|item| item.item == kind) {
+        drop_item(inventory_id, game, objects);
     }
 }
 
-fn player_move_or_attack(dx: i32, dy: i32, game: &mut Game, objects: &mut [Object]) {
-    // the coords the player is moving to/attacking
-    let x = objects[PLAYER].x + dx;
-    let y = objects[PLAYER].y + dy;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlayerAction {
+    TookTurn,
+    DidntTakeTurn,
+    /// wait turn after turn, until something interrupts
+    Rest,
+    /// swap the game for the one in the quick-save slot
+    QuickLoad,
+    Exit,
+}
 
-    // try to find an attackable object there
-    let target_id = objects
-        .iter()
-        .position(|object| object.fighter.is_some() && object.pos() == (x, y));
+/// A rest in progress, along with how things stood when it started
+pub struct Rest {
+    pub turns: u32,
+    hp: i32,
+    items_in_view: usize,
+    messages: usize,
+}
 
-    // attack if target found, move otherwise
-    match target_id {
-        Some(target_id) => {
-            let (player, target) = mut_two(PLAYER, target_id, objects);
-            player.attack(target, game);
+impl Rest {
+    pub fn start(fov: &dyn Visibility, game: &Game, objects: &[Object]) -> Self {
+        Rest {
+            turns: 0,
+            hp: objects[PLAYER].fighter.map_or(0, |f| f.hp),
+            items_in_view: visible_items(fov, objects),
+            messages: game.messages.messages.len(),
         }
-        None => {
-            move_by(PLAYER, dx, dy, &game.map, objects);
+    }
+
+    /// why the rest has to stop now, if it does
+    pub fn interruption(
+        &self,
+        fov: &dyn Visibility,
+        game: &Game,
+        objects: &[Object],
+    ) -> Option<&'static str> {
+        let rules = &game.settings.interrupts;
+        let player = &objects[PLAYER];
+        let hp = player.fighter.map_or(0, |f| f.hp);
+        let hp_line = player.max_hp(game) * rules.hp_percent / 100;
+        let monster_in_view = objects.iter().any(|object| {
+            object.fighter.is_some()
+                && object.ai.is_some()
+                && !object.ally
+                && fov.is_in_fov(object.x, object.y)
+        });
+        let worst_news = game.messages.messages[self.messages..]
+            .iter()
+            .map(|message| message.severity)
+            .fold(None, |worst: Option<Severity>, severity| match worst {
+                Some(worst) if worst >= severity => Some(worst),
+                _ => Some(severity),
+            });
+
+        if !player.alive {
+            Some("Your rest is over.")
+        } else if rules.damage && hp < self.hp {
+            Some("You are hurt, and stop resting!")
+        } else if rules.hp_percent > 0 && hp <= hp_line && self.hp > hp_line {
+            Some("Your health is getting low, you stop resting!")
+        } else if rules.monster_in_view && monster_in_view {
+            Some("A monster comes into view, you stop resting.")
+        } else if rules.item_in_view && visible_items(fov, objects) > self.items_in_view {
+            Some("You spot something, and stop resting.")
+        } else if rules.message_severity.is_some() && worst_news >= rules.message_severity {
+            Some("Something happens, and you stop resting.")
+        } else if self.turns >= REST_TURN_CAP {
+            Some("You finish resting.")
+        } else {
+            None
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum PlayerAction {
-    TookTurn,
-    DidntTakeTurn,
-    Exit,
+fn visible_items(fov: &dyn Visibility, objects: &[Object]) -> usize {
+    objects
+        .iter()
+        .filter(|object| object.item.is_some() && fov.is_in_fov(object.x, object.y))
+        .count()
 }
 
 /// return the position of a tile left-clicked in player's FOV (optionally in a
-/// range), or (None, None) if right-clicked.
+/// range), or (None, None) if right-clicked. Tab cycles through the monsters
+/// in range instead, and Enter picks the highlighted one
 pub fn target_tile(
     tcod: &mut Tcod,
     game: &mut Game,
     objects: &mut [Object],
     max_range: Option<f32>,
 ) -> Option<(i32, i32)> {
-    use tcod::input::KeyCode::Escape;
+    let mut target: Option<usize> = None;
     loop {
         // render the screen. this erases the inventory and shows the names of
         // objects under the mouse.
-        tcod.root.flush();
-        let event = input::check_for_event(input::KEY_PRESS | input::MOUSE).map(|e| e.1);
-        match event {
-            Some(Event::Mouse(m)) => tcod.mouse = m,
-            Some(Event::Key(k)) => tcod.key = k,
+        tcod.renderer.flush();
+        match tcod.renderer.check_for_event() {
+            Some(InputEvent::Mouse(m)) => tcod.mouse = m,
+            Some(InputEvent::Key(k)) => tcod.key = k,
             None => tcod.key = Default::default(),
         }
-        render_all(tcod, game, objects, false);
 
-        let (x, y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+        // step on to the next monster, wrapping around after the farthest
+        let targets = targetable_monsters(&tcod.fov, objects, max_range);
+        target = target.filter(|id| targets.contains(id));
+        if tcod.key.code == KeyCode::Tab && !targets.is_empty() {
+            let next = target
+                .and_then(|id| targets.iter().position(|&other| other == id))
+                .map_or(0, |index| (index + 1) % targets.len());
+            target = Some(targets[next]);
+        }
+        // a line of fire follows the mouse until a monster is picked with Tab,
+        // as long as the mouse is over a tile the player knows about
+        let (mouse_x, mouse_y) = (tcod.mouse.x, tcod.mouse.y);
+        let on_map = (0..MAP_WIDTH).contains(&mouse_x) && (0..MAP_HEIGHT).contains(&mouse_y);
+        let known = on_map
+            && (tcod.fov.is_in_fov(mouse_x, mouse_y) || game.map.get(mouse_x, mouse_y).explored);
+        let cursor = Some((mouse_x, mouse_y)).filter(|_| tcod.render.line_of_fire && known);
+        tcod.render.target = target.map(|id| objects[id].pos()).or(cursor);
+        render_all(tcod, game, objects);
+        if tcod.key.code == KeyCode::Enter && target.is_some() {
+            return tcod.render.target.take();
+        }
+
+        let (x, y) = (tcod.mouse.x, tcod.mouse.y);
 
         // accept the target if the player clicked in FOV, and in case a range
         // is specified, if it's not in that range
         let in_fov = (x < MAP_WIDTH) && (y < MAP_HEIGHT) && tcod.fov.is_in_fov(x, y);
         let in_range = max_range.map_or(true, |range| objects[PLAYER].distance(x, y) <= range);
         if tcod.mouse.lbutton_pressed && in_fov && in_range {
+            tcod.render.target = None;
             return Some((x, y));
         }
 
-        if tcod.mouse.rbutton_pressed || tcod.key.code == Escape {
+        if tcod.mouse.rbutton_pressed || tcod.key.code == KeyCode::Escape {
+            tcod.render.target = None;
             return None; // cancel if the player right-clicked or pressed Escape
         }
     }
 }
 
+/// like `target_tile`, but showing the line a shot would take on the way,
+/// for things that fly straight at what they're aimed at
+pub fn target_line(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+    max_range: Option<f32>,
+) -> Option<(i32, i32)> {
+    tcod.render.line_of_fire = true;
+    let target = target_tile(tcod, game, objects, max_range);
+    tcod.render.line_of_fire = false;
+    target
+}
+
+/// The tiles a shot from one tile to another passes over, up to and
+/// including the first wall or creature in the way
+pub fn line_of_fire(
+    from: (i32, i32),
+    to: (i32, i32),
+    game: &Game,
+    objects: &[Object],
+) -> Vec<(i32, i32)> {
+    line_until(from, to, |x, y| blocks_fire(x, y, game, objects))
+}
+
+/// like `line_of_fire`, but as far as the player can tell: only a wall or
+/// creature in view stops it, so it gives nothing away about what's unseen
+pub fn seen_line_of_fire(
+    from: (i32, i32),
+    to: (i32, i32),
+    fov: &dyn Visibility,
+    game: &Game,
+    objects: &[Object],
+) -> Vec<(i32, i32)> {
+    line_until(from, to, |x, y| fov.is_in_fov(x, y) && blocks_fire(x, y, game, objects))
+}
+
+/// whether a wall or a creature on the tile would stop a shot
+pub fn blocks_fire(x: i32, y: i32, game: &Game, objects: &[Object]) -> bool {
+    game.map.get(x, y).blocked || game.positions.at(x, y).iter().any(|&id| objects[id].blocks)
+}
+
+/// the tiles on the line from one tile to another, up to and including the
+/// first one that `stops` it
+fn line_until(
+    from: (i32, i32),
+    to: (i32, i32),
+    stops: impl Fn(i32, i32) -> bool,
+) -> Vec<(i32, i32)> {
+    let mut path = vec![];
+    for (x, y) in Line::new(from, to) {
+        path.push((x, y));
+        if stops(x, y) {
+            break;
+        }
+    }
+    path
+}
+
 /// returns a clicked monster inside FOV up to a range, or None if right-clicked
 pub fn target_monster(
     tcod: &mut Tcod,
@@ -248,10 +2479,14 @@ pub fn target_monster(
         match target_tile(tcod, game, objects, max_range) {
             Some((x, y)) => {
                 // return the first clicked monster, otherwise continue looping
-                for (id, obj) in objects.iter().enumerate() {
-                    if obj.pos() == (x, y) && obj.fighter.is_some() && id != PLAYER {
-                        return Some(id);
-                    }
+                let monster_id = game
+                    .positions
+                    .at(x, y)
+                    .iter()
+                    .copied()
+                    .find(|&id| objects[id].fighter.is_some() && id != PLAYER);
+                if monster_id.is_some() {
+                    return monster_id;
                 }
             },
             None => return None,
@@ -259,8 +2494,61 @@ pub fn target_monster(
     }
 }
 
+/// like `target_monster`, but for the player or one of their allies. clicking
+/// a hostile monster is turned down and the player gets to pick again
+pub fn target_ally(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+    max_range: Option<f32>,
+) -> Option<usize> {
+    loop {
+        let (x, y) = target_tile(tcod, game, objects, max_range)?;
+        let target_id = game
+            .positions
+            .at(x, y)
+            .iter()
+            .copied()
+            .find(|&id| objects[id].fighter.is_some() && objects[id].alive);
+        match target_id {
+            Some(id) if id == PLAYER || objects[id].ally => return Some(id),
+            Some(id) => game.messages.add(
+                format!("You won't help the {}.", objects[id].name),
+                Severity::Warning,
+            ),
+            None => {}
+        }
+    }
+}
+
+/// every enemy in the player's FOV and up to a range, nearest first
+fn targetable_monsters(
+    fov: &dyn Visibility,
+    objects: &[Object],
+    max_range: Option<f32>,
+) -> Vec<usize> {
+    let distance = |id: usize| objects[PLAYER].distance_to(&objects[id]);
+    let mut targets: Vec<usize> = (0..objects.len())
+        .filter(|&id| {
+            let object = &objects[id];
+            id != PLAYER
+                && object.fighter.is_some()
+                && object.ai.is_some()
+                && !object.ally
+                && fov.is_in_fov(object.x, object.y)
+                && distance(id) <= max_range.unwrap_or(f32::INFINITY)
+        })
+        .collect();
+    targets.sort_by(|&a, &b| distance(a).partial_cmp(&distance(b)).unwrap());
+    targets
+}
+
 /// find closest enemy, up to a max range, and in the player's FOV
-pub fn closest_monster(tcod: &Tcod, objects: &[Object], max_range: i32) -> Option<usize> {
+pub fn closest_monster(
+    fov: &dyn Visibility,
+    objects: &[Object],
+    max_range: i32,
+) -> Option<usize> {
     let mut closest_enemy = None;
     let mut closest_dist = (max_range + 1) as f32; // start with (slightly more than) max
 
@@ -268,7 +2556,8 @@ pub fn closest_monster(tcod: &Tcod, objects: &[Object], max_range: i32) -> Optio
         if (id != PLAYER)
             && object.fighter.is_some()
             && object.ai.is_some()
-            && tcod.fov.is_in_fov(object.x, object.y)
+            && !object.ally
+            && fov.is_in_fov(object.x, object.y)
         {
             // calculate distance between this object and the player
             let dist = objects[PLAYER].distance_to(object);
@@ -283,24 +2572,142 @@ pub fn closest_monster(tcod: &Tcod, objects: &[Object], max_range: i32) -> Optio
 }
 
 /// move by given amount, if the destination is not blocked
-pub fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
+pub fn move_by(id: usize, dx: i32, dy: i32, game: &mut Game, objects: &mut [Object]) {
+    let (x, y) = objects[id].pos();
+    if !is_tile_blocked(x + dx, y + dy, game, objects) {
+        move_object(id, x + dx, y + dy, game, objects);
+        step_on_hazard(id, dx, dy, game, objects);
+    }
+}
+
+/// whatever just stepped onto a hazard gets what's coming to it
+fn step_on_hazard(id: usize, dx: i32, dy: i32, game: &mut Game, objects: &mut [Object]) {
     let (x, y) = objects[id].pos();
-    if !is_blocked(x + dx, y + dy, map, objects) {
-        objects[id].set_pos(x + dx, y + dy);
+    match hazard_at(game, x, y) {
+        Some(HazardKind::Caltrops) if objects[id].fighter.is_some() => {
+            if id == PLAYER {
+                game.messages.add("You step on the caltrops!", Severity::Danger);
+            }
+            if let Some(xp) = objects[id].take_damage(CALTROPS_DAMAGE, game) {
+                if id != PLAYER {
+                    // the player put them there
+                    objects[PLAYER].grant_xp(xp);
+                }
+            }
+        }
+        Some(HazardKind::Oil) => {
+            // skid on another tile, and deal with wherever that ends up
+            let slips = rand::thread_rng().gen_range(0, 100) < OIL_SLIP_CHANCE;
+            if slips && !is_tile_blocked(x + dx, y + dy, game, objects) {
+                if id == PLAYER {
+                    game.messages.add("You slip on the oil!", Severity::Warning);
+                }
+                move_object(id, x + dx, y + dy, game, objects);
+                step_on_hazard(id, dx, dy, game, objects);
+            }
+        }
+        _ => {}
     }
 }
 
-pub fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object]) {
+/// put an object somewhere else, keeping the spatial index up to date
+pub fn move_object(id: usize, x: i32, y: i32, game: &mut Game, objects: &mut [Object]) {
+    game.positions.moved(id, objects[id].pos(), (x, y));
+    objects[id].set_pos(x, y);
+}
+
+pub fn move_towards(
+    id: usize,
+    target_x: i32,
+    target_y: i32,
+    game: &mut Game,
+    objects: &mut [Object],
+) {
     // vector from this object to the target, and distance
     let dx = target_x - objects[id].x;
     let dy = target_y - objects[id].y;
     let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
 
     // normalize it to length 1 (preserving direction), then round it
-    // and convert to int so the movement is restricted to the map grid
-    let dx = (dx as f32 / distance).round() as i32;
-    let dy = (dy as f32 / distance).round() as i32;
-    move_by(id, dx, dy, map, objects);
+    // and convert to int so the movement is restricted to the map grid.
+    // without diagonals, step along whichever way is further to go
+    let (dx, dy) = match game.settings.movement {
+        MovementMode::EightWay => (
+            (dx as f32 / distance).round() as i32,
+            (dy as f32 / distance).round() as i32,
+        ),
+        MovementMode::FourWay if dx.abs() >= dy.abs() => (dx.signum(), 0),
+        MovementMode::FourWay => (0, dy.signum()),
+    };
+
+    // if that way is blocked, slide along whatever is in the way: try the
+    // straight steps that still get closer, the one covering more ground first
+    let (x, y) = objects[id].pos();
+    let (to_x, to_y) = (target_x - x, target_y - y);
+    let horizontal = (to_x.signum(), 0);
+    let vertical = (0, to_y.signum());
+    let steps = if to_x.abs() >= to_y.abs() {
+        [(dx, dy), horizontal, vertical]
+    } else {
+        [(dx, dy), vertical, horizontal]
+    };
+    let step = steps.iter().copied().find(|&(step_x, step_y)| {
+        (step_x, step_y) != (0, 0)
+            && !is_tile_blocked(x + step_x, y + step_y, game, objects)
+            // monsters know better than to walk into a hazard they can see
+            && (id == PLAYER || hazard_at(game, x + step_x, y + step_y).is_none())
+    });
+    if let Some((step_x, step_y)) = step {
+        move_by(id, step_x, step_y, game, objects);
+    }
+}
+
+/// Split every slime hurt since the last check into two, each with half of
+/// its max HP, HP and xp. It only happens while the halves stay big enough,
+/// there's a free tile next to it, the level isn't full of slimes, and its
+/// kind wasn't wiped out here
+pub fn split_slimes(fov: &dyn Visibility, game: &mut Game, objects: &mut Vec<Object>) {
+    for id in 0..objects.len() {
+        if !objects[id].split_pending {
+            continue;
+        }
+        objects[id].split_pending = false;
+        if game.genocided.contains(&objects[id].name) {
+            continue;
+        }
+        let mut fighter = match objects[id].fighter {
+            Some(fighter) if objects[id].alive => fighter,
+            _ => continue,
+        };
+        let slimes = objects.iter().filter(|object| object.alive && object.splits).count();
+        if fighter.base_max_hp / 2 < SLIME_MIN_HP || slimes >= SLIME_CAP {
+            continue;
+        }
+        let (x, y) = objects[id].pos();
+        let spot = (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+            .find(|&(spot_x, spot_y)| !is_tile_blocked(spot_x, spot_y, game, objects));
+        let (spot_x, spot_y) = match spot {
+            Some(spot) => spot,
+            None => continue,
+        };
+
+        fighter.base_max_hp /= 2;
+        fighter.hp = cmp::max(fighter.hp / 2, 1);
+        fighter.xp /= 2;
+        objects[id].fighter = Some(fighter);
+        let mut half = create_monster(&objects[id].name, spot_x, spot_y);
+        half.fighter = Some(fighter);
+        if fov.is_in_fov(x, y) {
+            game.messages.add(
+                format!("The {} splits in two!", objects[id].name),
+                Severity::Warning,
+            );
+        }
+        game.events.push(GameEvent::Spawn(objects.len()));
+        game.positions.added(objects.len(), (spot_x, spot_y));
+        objects.push(half);
+    }
 }
 
 /// Mutably borrow two *seperate* elements from the given slice.
@@ -316,9 +2723,42 @@ pub fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (
     }
 }
 
+/// find the closest unblocked tile around the given position (not counting
+/// the position itself), or None if the whole map is full
+pub fn free_tile_near(x: i32, y: i32, map: &Map, objects: &[Object]) -> Option<(i32, i32)> {
+    for radius in 1..cmp::max(MAP_WIDTH, MAP_HEIGHT) {
+        for dx in -radius..=radius {
+            for dy in -radius..=radius {
+                // only look at the ring at this distance, nearer ones were checked already
+                if dx.abs() != radius && dy.abs() != radius {
+                    continue;
+                }
+                let (tile_x, tile_y) = (x + dx, y + dy);
+                let in_map = (0..MAP_WIDTH).contains(&tile_x) && (0..MAP_HEIGHT).contains(&tile_y);
+                if in_map && !is_blocked(tile_x, tile_y, map, objects) {
+                    return Some((tile_x, tile_y));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// whether there is an open path between two tiles (objects don't count),
+/// by the same rule as `reachable_from`
+pub fn is_reachable(map: &Map, from: (i32, i32), to: (i32, i32)) -> bool {
+    reachable_from(map, from).is_some_and(|reachable| reachable.contains(&to))
+}
+
+/// like `is_blocked`, but looks objects up in the spatial index
+pub fn is_tile_blocked(x: i32, y: i32, game: &Game, objects: &[Object]) -> bool {
+    game.map.get(x, y).blocked
+        || game.positions.at(x, y).iter().any(|&id| objects[id].blocks)
+}
+
 pub fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
     // first test the map tile
-    if map[x as usize][y as usize].blocked {
+    if map.get(x, y).blocked {
         return true;
     }
     // new check for any blocking objects
@@ -354,20 +2794,78 @@ impl Rect {
             && (self.y1 <= other.y2)
             && (self.y2 >= other.y1)
     }
+
+    /// whether the tile is inside the room or on its walls
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        (self.x1..=self.x2).contains(&x) && (self.y1..=self.y2).contains(&y)
+    }
 }
 
-fn create_room(room: Rect, map: &mut Map) {
-    // go through the tiles in the rectangle and make them passable
+/// What part of its rectangle a room actually takes up. Every shape covers
+/// the center, where the corridors meet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RoomShape {
+    Rectangle,
+    Circle,
+    Cross,
+}
+
+impl RoomShape {
+    fn random(rng: &mut impl Rng) -> Self {
+        let roll = rng.gen_range(0, 100);
+        if roll < CIRCLE_ROOM_CHANCE {
+            RoomShape::Circle
+        } else if roll < CIRCLE_ROOM_CHANCE + CROSS_ROOM_CHANCE {
+            RoomShape::Cross
+        } else {
+            RoomShape::Rectangle
+        }
+    }
+
+    /// whether the tile, inside the room's walls, is part of the room
+    fn covers(self, room: Rect, x: i32, y: i32) -> bool {
+        let (center_x, center_y) = room.center();
+        let (dx, dy) = ((x - center_x).abs(), (y - center_y).abs());
+        let (w, h) = (room.x2 - room.x1, room.y2 - room.y1);
+        match self {
+            RoomShape::Rectangle => true,
+            RoomShape::Circle => {
+                let radius = (cmp::min(w, h) - 1) / 2;
+                dx * dx + dy * dy <= radius * radius + radius
+            }
+            // two bars through the middle, each about a third of the room thick
+            RoomShape::Cross => dx <= cmp::max(1, w / 6) || dy <= cmp::max(1, h / 6),
+        }
+    }
+}
+
+fn create_room(room: Rect, shape: RoomShape, map: &mut Map) {
+    // go through the tiles in the rectangle and make the room's ones passable
     for x in (room.x1 + 1)..room.x2 {
         for y in (room.y1 + 1)..room.y2 {
-            map[x as usize][y as usize] = Tile::empty();
+            if shape.covers(room, x, y) {
+                map.set(x, y, Tile::empty());
+            }
         }
     }
 }
 
-/// Advance to the next level
-fn next_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
-    game.messages.add(
+/// the open tiles inside a room's walls, whatever shape it was dug in
+fn room_floor(room: Rect, map: &Map) -> Vec<(i32, i32)> {
+    let mut floor = vec![];
+    for x in (room.x1 + 1)..room.x2 {
+        for y in (room.y1 + 1)..room.y2 {
+            if !map.get(x, y).blocked {
+                floor.push((x, y));
+            }
+        }
+    }
+    floor
+}
+
+/// Go down the stairs into the next level of `branch`
+fn next_level(branch: Branch, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    game.messages.add_colored(
         "You take a moment to rest, and recover your strength.",
         VIOLET,
     );
@@ -377,15 +2875,196 @@ fn next_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
     game.messages.add(
         "After a rare moment of peace, you descend deeper into \
         the heart of the dungeon...",
-        RED,
+        Severity::Danger,
+    );
+    if branch != game.branch {
+        game.messages.add(
+            format!("You enter the {}.", branch.name().to_lowercase()),
+            Severity::Warning,
+        );
+    }
+    let level = game.dungeon_level + 1;
+    game.stats.deepest_level = game.stats.deepest_level.max(level);
+    change_level(branch, level, "upstairs", tcod, game, objects);
+}
+
+/// Climb back up to the level above, just as the player left it. The top
+/// level of a branch leads back out to the main dungeon.
+fn previous_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    let level = game.dungeon_level - 1;
+    let (branch, arrival) = if game.branch.entrance() == Some(level) {
+        (Branch::Main, game.branch.stairs_name())
+    } else {
+        (game.branch, "stairs")
+    };
+    game.messages.add(
+        format!("You climb back up to level {}.", level),
+        Severity::Info,
     );
-    game.dungeon_level += 1;
-    game.map = make_map(objects, game.dungeon_level);
+    change_level(branch, level, arrival, tcod, game, objects);
+}
+
+/// Leave the current level for another one, in the given branch. A level the
+/// player has been on before comes back as it was left, with the player on
+/// the `arrival` stairs; any other is generated. Living pets come along
+/// either way.
+fn change_level(
+    branch: Branch,
+    level: u32,
+    arrival: &str,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) {
+    game.scent.clear();
+    game.hazards.clear();
+    game.wanderers = 0;
+
+    // living pets follow the player, no matter where they were left; the
+    // rest stays behind for when the player comes back
+    let (pets, left_behind): (Vec<Object>, Vec<Object>) = objects
+        .drain(1..)
+        .partition(|object| object.ally && object.alive);
+    let left = LevelState {
+        map: std::mem::take(&mut game.map),
+        objects: left_behind,
+        genocided: std::mem::take(&mut game.genocided),
+    };
+    game.visited
+        .entry(game.branch)
+        .or_default()
+        .insert(game.dungeon_level, left);
+    game.branch = branch;
+    game.dungeon_level = level;
+
+    let returning = game.visited.get_mut(&branch).and_then(|levels| levels.remove(&level));
+    let (map, level_objects) = match returning {
+        Some(state) => {
+            let start = state
+                .objects
+                .iter()
+                .find(|object| object.name == arrival)
+                .map_or(objects[PLAYER].pos(), Object::pos);
+            objects[PLAYER].set_pos(start.0, start.1);
+            game.genocided = state.genocided;
+            (state.map, state.objects)
+        }
+        None => {
+            let (map, mut level_objects) = take_next_level(game);
+            let stand_in = level_objects.remove(PLAYER);
+            let start = level_objects
+                .iter()
+                .find(|object| object.name == arrival)
+                .map_or(stand_in.pos(), Object::pos);
+            objects[PLAYER].set_pos(start.0, start.1);
+            (map, level_objects)
+        }
+    };
+    game.map = map;
+    objects.extend(level_objects);
+    disguise_unidentified(game, objects);
+    for pet in pets {
+        place_near_player(pet, &game.map, objects);
+    }
+    game.positions.rebuild(objects);
     initialize_fov(tcod, &game.map);
+
+    preload_next_level(game);
+}
+
+/// A level being generated on another thread, before the player gets there
+pub struct PreloadedLevel {
+    branch: Branch,
+    level: u32,
+    generator: MapGenerator,
+    handle: JoinHandle<(Map, Vec<Object>)>,
+}
+
+/// The random number generator for one level of a game, so that the level
+/// comes out the same no matter when, or on which thread, it's generated
+pub fn level_rng(seed: usize, branch: Branch, level: u32) -> StdRng {
+    StdRng::from_seed(&[seed, level as usize, branch as usize][..])
+}
+
+/// Generate a level from scratch. The first object is a stand-in for the
+/// player, standing where the player starts.
+fn generate_level(
+    seed: usize,
+    branch: Branch,
+    level: u32,
+    generator: MapGenerator,
+) -> (Map, Vec<Object>) {
+    let mut objects = vec![Object::new(0, 0, '@', "player", WHITE, true)];
+    let mut rng = level_rng(seed, branch, level);
+    let theme = LevelTheme::of(seed, branch, level);
+    // nothing has been wiped out on a level nobody has been to
+    let map = make_map(&mut objects, branch, level, theme, generator, &[], &mut rng);
+    (map, objects)
 }
 
-fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
-    use rand::distributions::{IndependentSample, WeightedChoice, Weighted};
+/// Start generating the level below the current one, in the same branch, in
+/// the background, unless the player has been there already
+pub fn preload_next_level(game: &mut Game) {
+    let seed = game.seed;
+    let branch = game.branch;
+    let level = game.dungeon_level + 1;
+    let visited = game.visited.get(&branch).and_then(|levels| levels.get(&level));
+    if visited.is_some() {
+        game.preloaded = None;
+        return;
+    }
+    let generator = game.settings.map_generator;
+    let handle = thread::spawn(move || generate_level(seed, branch, level, generator));
+    game.preloaded = Some(PreloadedLevel {
+        branch,
+        level,
+        generator,
+        handle,
+    });
+}
+
+/// The level the player is going down to: the preloaded one if it's the
+/// right one, waiting for it to finish if need be, or else a fresh one
+fn take_next_level(game: &mut Game) -> (Map, Vec<Object>) {
+    let branch = game.branch;
+    let level = game.dungeon_level;
+    let generator = game.settings.map_generator;
+    if let Some(preloaded) = game.preloaded.take() {
+        if preloaded.branch == branch && preloaded.level == level && preloaded.generator == generator
+        {
+            if let Ok(generated) = preloaded.handle.join() {
+                return generated;
+            }
+        }
+    }
+    generate_level(game.seed, branch, level, generator)
+}
+
+/// put an object (e.g. a pet) on the free tile closest to the player
+pub fn place_near_player(mut object: Object, map: &Map, objects: &mut Vec<Object>) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    if let Some((x, y)) = free_tile_near(player_x, player_y, map, objects) {
+        object.set_pos(x, y);
+        objects.push(object);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn place_objects(
+    room: Rect,
+    map: &Map,
+    objects: &mut Vec<Object>,
+    branch: Branch,
+    level: u32,
+    theme: LevelTheme,
+    genocided: &[String],
+    rng: &mut impl Rng,
+) {
+    // spots are picked from what was actually dug out, whatever the room's shape
+    let floor = room_floor(room, map);
+    if floor.is_empty() {
+        return;
+    }
 
     // maximum number of monsters per room
     let max_monsters = from_dungeon_level(
@@ -395,10 +3074,87 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
             Transition { level: 6, value: 5 },
         ],
         level,
-    );
+    ) + theme.extra_monsters();
 
     // choose random number of monsters
-    let num_monsters = rand::thread_rng().gen_range(0, max_monsters + 1);
+    let num_monsters = rng.gen_range(0, max_monsters + 1);
+
+    for _ in 0..num_monsters {
+        // choose random spot for this monster
+        let (x, y) = floor[rng.gen_range(0, floor.len())];
+
+        // only place it if the tile is not blocked
+        if !is_blocked(x, y, map, objects) {
+            objects.extend(random_monster(x, y, branch, level, genocided, rng));
+        }
+    }
+
+    // maximum number of items per room
+    let max_items = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 1 },
+            Transition { level: 4, value: 2 },
+        ],
+        level,
+    ) + theme.extra_items();
+
+    // choose random number of items
+    let num_items = rng.gen_range(0, max_items + 1);
+
+    for _ in 0..num_items {
+        // choose random spot for this item
+        let (x, y) = floor[rng.gen_range(0, floor.len())];
+
+        // only place it if tile is not blocked
+        if !is_blocked(x, y, map, objects) {
+            if level >= MIMIC_MIN_LEVEL && rng.gen_range(0, 100) < MIMIC_CHANCE {
+                objects.push(create_mimic(x, y, level, rng));
+            } else {
+                objects.push(random_item(x, y, level, rng));
+            }
+        }
+    }
+
+    // now and then, a chest or barrel with a few more
+    if rng.gen_range(0, 100) < CONTAINER_CHANCE {
+        let (x, y) = floor[rng.gen_range(0, floor.len())];
+        if !is_blocked(x, y, map, objects) {
+            objects.push(create_container(x, y, level, rng));
+        }
+    }
+}
+
+/// Create a chest or barrel at (x, y) with a few items from this level
+/// inside. Chests deeper down may be locked
+fn create_container(x: i32, y: i32, level: u32, rng: &mut impl Rng) -> Object {
+    let mut container = if rng.gen() {
+        Object::new(x, y, '=', "chest", SEPIA, true)
+    } else {
+        Object::new(x, y, '0', "barrel", DARK_SEPIA, true)
+    };
+    let num_items = rng.gen_range(1, CONTAINER_MAX_ITEMS + 1);
+    let contents = (0..num_items).map(|_| random_item(x, y, level, rng)).collect();
+    container.contents = Some(contents);
+    container.locked = container.name == "chest"
+        && level >= LOCKED_MIN_LEVEL
+        && rng.gen_range(0, 100) < LOCKED_CHANCE;
+    if container.locked {
+        container.name = "locked chest".into();
+    }
+    container
+}
+
+/// Create a monster at (x, y), picked from the ones that show up at this level
+/// of the branch and weren't wiped out there. `None` if there are none left
+fn random_monster(
+    x: i32,
+    y: i32,
+    branch: Branch,
+    level: u32,
+    genocided: &[String],
+    rng: &mut impl Rng,
+) -> Option<Object> {
+    use rand::distributions::Weighted;
 
     // monster random table
     let troll_chance = from_dungeon_level(
@@ -419,77 +3175,315 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
         level,
     );
 
-    let monster_chances = &mut [
+    // the crypt is mostly zombies, from the very first level on
+    let zombie_chance = match branch {
+        Branch::Main => from_dungeon_level(&[Transition { level: 2, value: 15 }], level),
+        Branch::Crypt => 60,
+    };
+    let orc_chance = match branch {
+        Branch::Main => 80,
+        Branch::Crypt => 20,
+    };
+
+    let monster_chances = vec![
         Weighted {
-            weight: 80,
+            weight: orc_chance,
             item: "orc",
         },
         Weighted {
             weight: troll_chance,
             item: "troll",
         },
+        Weighted {
+            weight: from_dungeon_level(
+                &[
+                    Transition {
+                        level: 4,
+                        value: 10,
+                    },
+                    Transition {
+                        level: 6,
+                        value: 20,
+                    },
+                ],
+                level,
+            ),
+            item: "dire wolf",
+        },
+        Weighted {
+            weight: zombie_chance,
+            item: "zombie",
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 10 }], level),
+            item: "bloodhound",
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 10 }], level),
+            item: "slime",
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 8 }], level),
+            item: "shaman",
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 10 }], level),
+            item: "archer",
+        },
+        Weighted {
+            weight: 20,
+            item: "goblin",
+        },
     ];
-    let monster_choice = WeightedChoice::new(monster_chances);
+    let monster_chances: Vec<_> = monster_chances
+        .into_iter()
+        .filter(|chance| chance.weight > 0 && !genocided.iter().any(|kind| kind == chance.item))
+        .collect();
+    if monster_chances.is_empty() {
+        return None;
+    }
+    let mut monster = create_monster(choose_weighted(monster_chances, rng), x, y);
+    if let Some(fighter) = monster.fighter.as_mut() {
+        scale_xp(fighter, level);
+    }
+    Some(monster)
+}
 
-    for _ in 0..num_monsters {
-        // choose random spot for this monster
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+/// make a monster worth more the deeper down it's met, so that killing
+/// keeps pace with what each level up costs
+fn scale_xp(fighter: &mut Fighter, level: u32) {
+    let depth = level.saturating_sub(1) as i32;
+    fighter.xp = fighter.xp * (100 + depth * XP_PERCENT_PER_LEVEL) / 100;
+}
+
+fn create_monster(name: &str, x: i32, y: i32) -> Object {
+    let mut monster = match name {
+        "orc" => {
+            // create an orc
+            let mut orc = Object::new(x, y, 'o', "orc", DESATURATED_GREEN, true);
+            orc.fighter = Some(Fighter {
+                base_max_hp: 20,
+                hp: 20,
+                base_defense: 0,
+                base_power: 4,
+                xp: 35,
+                on_death: DeathCallback::Monster,
+            });
+            orc.ai = Some(Ai::Basic);
+            orc.morale = Some(Morale {
+                breaking_point: 50,
+                pack: 0,
+            });
+            orc
+        }
+        "troll" => {
+            // create a troll
+            let mut troll = Object::new(x, y, 'T', "troll", DARKER_GREEN, true);
+            troll.fighter = Some(Fighter {
+                base_max_hp: 30,
+                hp: 30,
+                base_defense: 2,
+                base_power: 8,
+                xp: 100,
+                on_death: DeathCallback::Monster,
+            });
+            troll.ai = Some(Ai::Basic);
+            troll
+        }
+        "dire wolf" => {
+            // create a dire wolf, quick enough to run the player down
+            let mut wolf = Object::new(x, y, 'w', "dire wolf", DARKER_SEPIA, true);
+            wolf.fighter = Some(Fighter {
+                base_max_hp: 14,
+                hp: 14,
+                base_defense: 0,
+                base_power: 5,
+                xp: 60,
+                on_death: DeathCallback::Monster,
+            });
+            wolf.ai = Some(Ai::Basic);
+            wolf.speed = Speed::Fast;
+            wolf.morale = Some(Morale {
+                breaking_point: 40,
+                pack: 0,
+            });
+            wolf
+        }
+        "zombie" => {
+            // create a zombie, tough but slow
+            let mut zombie = Object::new(x, y, 'Z', "zombie", DESATURATED_CHARTREUSE, true);
+            zombie.fighter = Some(Fighter {
+                base_max_hp: 35,
+                hp: 35,
+                base_defense: 1,
+                base_power: 6,
+                xp: 50,
+                on_death: DeathCallback::Monster,
+            });
+            zombie.ai = Some(Ai::Basic);
+            zombie.speed = Speed::Slow;
+            zombie
+        }
+        "slime" => {
+            // create a slime, which splits in two when hit
+            let mut slime = Object::new(x, y, 'j', "slime", LIME, true);
+            slime.fighter = Some(Fighter {
+                base_max_hp: 24,
+                hp: 24,
+                base_defense: 0,
+                base_power: 3,
+                xp: 40,
+                on_death: DeathCallback::Monster,
+            });
+            slime.ai = Some(Ai::Basic);
+            slime.speed = Speed::Slow;
+            slime.splits = true;
+            slime
+        }
+        "shaman" => {
+            // create a shaman, which blesses the monsters around it
+            let mut shaman = Object::new(x, y, 's', "shaman", LIGHT_PURPLE, true);
+            shaman.fighter = Some(Fighter {
+                base_max_hp: 12,
+                hp: 12,
+                base_defense: 0,
+                base_power: 2,
+                xp: 50,
+                on_death: DeathCallback::Monster,
+            });
+            shaman.ai = Some(Ai::Custom(Brain(Box::new(Shaman))));
+            shaman
+        }
+        "archer" => {
+            // create an archer, which shoots from a distance
+            let mut archer = Object::new(x, y, 'a', "archer", LIGHT_HAN, true);
+            archer.fighter = Some(Fighter {
+                base_max_hp: 14,
+                hp: 14,
+                base_defense: 0,
+                base_power: 3,
+                xp: 55,
+                on_death: DeathCallback::Monster,
+            });
+            archer.ai = Some(Ai::Ranged {
+                range: ARCHER_RANGE,
+            });
+            archer
+        }
+        "goblin" => {
+            // create a goblin, which runs off when it's losing
+            let mut goblin = Object::new(x, y, 'g', "goblin", DARK_LIME, true);
+            goblin.fighter = Some(Fighter {
+                base_max_hp: 12,
+                hp: 12,
+                base_defense: 0,
+                base_power: 3,
+                xp: 20,
+                on_death: DeathCallback::Monster,
+            });
+            goblin.ai = Some(Ai::Basic);
+            goblin.cowardly = true;
+            goblin
+        }
+        "bloodhound" => {
+            // create a bloodhound, which follows the player's trail
+            let mut hound = Object::new(x, y, 'h', "bloodhound", BRASS, true);
+            hound.fighter = Some(Fighter {
+                base_max_hp: 16,
+                hp: 16,
+                base_defense: 0,
+                base_power: 4,
+                xp: 45,
+                on_death: DeathCallback::Monster,
+            });
+            hound.ai = Some(Ai::Tracker);
+            hound
+        }
+        _ => unreachable!(),
+    };
+    monster.alive = true;
+    monster
+}
+
+/// Create a mimic at (x, y). It passes for a potion or a scroll, and lies
+/// still until the player comes too close or tries to pick it up
+fn create_mimic(x: i32, y: i32, level: u32, rng: &mut impl Rng) -> Object {
+    let looks = [Item::Heal, Item::Lightning, Item::Fireball, Item::Confuse];
+    let mut mimic = create_item(looks[rng.gen_range(0, looks.len())], x, y);
+    let mut fighter = Fighter {
+        base_max_hp: 22,
+        hp: 22,
+        base_defense: 1,
+        base_power: 6,
+        xp: 70,
+        on_death: DeathCallback::Monster,
+    };
+    scale_xp(&mut fighter, level);
+    mimic.disguise = Some(Disguise {
+        char: 'm',
+        color: DARK_AMBER,
+        name: "mimic".into(),
+        fighter,
+        ai: Ai::Basic,
+    });
+    mimic
+}
+
+/// A mimic gives itself away, and gets ready to fight. Found out underfoot,
+/// it lurches aside to a free tile nearby
+pub fn reveal_mimic(id: usize, game: &mut Game, objects: &mut [Object]) {
+    let disguised_as = objects[id].name.clone();
+    if !objects[id].reveal() {
+        return;
+    }
+    game.messages.add(
+        format!("The {} was a {} all along!", disguised_as, objects[id].name),
+        Severity::Danger,
+    );
+    let (x, y) = objects[id].pos();
+    let crowded = game.positions.at(x, y).iter().any(|&other| other != id && objects[other].blocks);
+    if let Some(to) = free_tile_near(x, y, &game.map, objects).filter(|_| crowded) {
+        objects[id].set_pos(to.0, to.1);
+        game.positions.moved(id, (x, y), to);
+    }
+}
 
-        // only place it if the tile is not blocked
-        if !is_blocked(x, y, map, objects) {
-            let mut monster = match monster_choice.ind_sample(&mut rand::thread_rng()) {
-                "orc" => {
-                    // create an orc
-                    let mut orc = Object::new(x, y, 'o', "orc", DESATURATED_GREEN, true);
-                    orc.fighter = Some(Fighter {
-                        base_max_hp: 20,
-                        hp: 20,
-                        base_defense: 0,
-                        base_power: 4,
-                        xp: 35,
-                        on_death: DeathCallback::Monster,
-                    });
-                    orc.ai = Some(Ai::Basic);
-                    orc
-                }
-                "troll" => {
-                    // create a troll
-                    let mut troll = Object::new(x, y, 'T', "troll", DARKER_GREEN, true);
-                    troll.fighter = Some(Fighter {
-                        base_max_hp: 30,
-                        hp: 30,
-                        base_defense: 2,
-                        base_power: 8,
-                        xp: 100,
-                        on_death: DeathCallback::Monster,
-                    });
-                    troll.ai = Some(Ai::Basic);
-                    troll
-                }
-                _ => unreachable!(),
-            };
-            monster.alive = true;
-            objects.push(monster);
+/// Reveal every mimic the player has walked up to
+pub fn reveal_mimics(game: &mut Game, objects: &mut [Object]) {
+    for id in 0..objects.len() {
+        if objects[id].disguise.is_some() && objects[PLAYER].distance_to(&objects[id]) < 2.0 {
+            reveal_mimic(id, game, objects);
         }
     }
+}
 
-    // maximum number of items per room
-    let max_items = from_dungeon_level(
-        &[
-            Transition { level: 1, value: 1 },
-            Transition { level: 4, value: 2 },
-        ],
-        level,
-    );
+/// Create an item at (x, y), picked from the ones that show up at this level
+fn random_item(x: i32, y: i32, level: u32, rng: &mut impl Rng) -> Object {
+    use rand::distributions::Weighted;
 
     // item random table
-    let item_chances = &mut [
+    let item_chances = vec![
         // healing potion will always show up, even if all other items have 0 chance
         Weighted {
             weight: 35,
             item: Item::Heal,
         },
+        Weighted {
+            weight: 10,
+            item: Item::Ration,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 5 }], level),
+            item: Item::Caltrops,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 5 }], level),
+            item: Item::Oil,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 10 }], level),
+            item: Item::Poison,
+        },
         Weighted {
             weight: from_dungeon_level(
                 &[Transition {
@@ -520,10 +3514,49 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
             ),
             item: Item::Confuse,
         },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 10 }], level),
+            item: Item::Entangle,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 7, value: 3 }], level),
+            item: Item::Genocide,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 10 }], level),
+            item: Item::Stone,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 10 }], level),
+            item: Item::Protection,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 5, value: 5 }], level),
+            item: Item::Recharge,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 8 }], level),
+            item: Item::PhaseDoor,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition { level: LOCKED_MIN_LEVEL, value: 5 }],
+                level,
+            ),
+            item: Item::Key,
+        },
         Weighted {
             weight: from_dungeon_level(&[Transition { level: 4, value: 5 }], level),
             item: Item::Sword,
         },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 5 }], level),
+            item: Item::Spear,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 5 }], level),
+            item: Item::PoisonedDagger,
+        },
         Weighted {
             weight: from_dungeon_level(
                 &[Transition {
@@ -534,205 +3567,539 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
             ),
             item: Item::Shield,
         },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 5, value: 5 }], level),
+            item: Item::LightningWand,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 7, value: 5 }], level),
+            item: Item::FireballWand,
+        },
     ];
-    let item_choice = WeightedChoice::new(item_chances);
-
-    // choose random number of items
-    let num_items = rand::thread_rng().gen_range(0, max_items + 1);
+    let mut item = create_item(choose_weighted(item_chances, rng), x, y);
+    roll_quality(&mut item, level, rng);
+    item
+}
 
-    for _ in 0..num_items {
-        // choose random spot for this item
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+/// Deeper equipment is more likely to be well made: fine gear is one better
+/// at whatever it's good for, masterwork two. The chances level off, so
+/// loot stays within reach of what the monsters can take.
+fn roll_quality(item: &mut Object, level: u32, rng: &mut impl Rng) {
+    let equipment = match item.equipment.as_mut() {
+        Some(equipment) => equipment,
+        None => return,
+    };
+    let masterwork_chance = from_dungeon_level(
+        &[
+            Transition { level: 6, value: 5 },
+            Transition { level: 10, value: 15 },
+        ],
+        level,
+    );
+    let fine_chance = from_dungeon_level(
+        &[
+            Transition { level: 3, value: 15 },
+            Transition { level: 6, value: 30 },
+            Transition { level: 9, value: 40 },
+        ],
+        level,
+    );
+    let roll = rng.gen_range(0, 100);
+    let (quality, bonus) = if roll < masterwork_chance {
+        ("masterwork", 2)
+    } else if roll < masterwork_chance + fine_chance {
+        ("fine", 1)
+    } else {
+        return;
+    };
+    if equipment.power_bonus > 0 {
+        equipment.power_bonus += bonus;
+    }
+    if equipment.defense_bonus > 0 {
+        equipment.defense_bonus += bonus;
+    }
+    if equipment.max_hp_bonus > 0 {
+        equipment.max_hp_bonus += bonus * 10;
+    }
+    item.name = format!("{} {}", quality, item.name);
+}
 
-        // only place it if tile is not blocked
-        if !is_blocked(x, y, map, objects) {
-            let mut item = match item_choice.ind_sample(&mut rand::thread_rng()) {
-                Item::Heal => {
-                    // create a healing potion
-                    let mut object = Object::new(x, y, '!', "healing potion", VIOLET, false);
-                    object.item = Some(Item::Heal);
-                    object
-                }
-                Item::Lightning => {
-                    // create a lightning bolt scroll
-                    let mut object = Object::new(x, y, '#', "scroll of lightning bolt", LIGHT_YELLOW, false, );
-                    object.item = Some(Item::Lightning);
-                    object
-                }
-                Item::Fireball => {
-                    // create a fireball scroll
-                    let mut object = Object::new(x, y, '#', "scroll of fireball", LIGHT_YELLOW, false);
-                    object.item = Some(Item::Fireball);
-                    object
-                }
-                Item::Confuse => {
-                    // create a confuse scroll (10% chance)
-                    let mut object = Object::new(x, y, '#', "scroll of confusion", LIGHT_YELLOW, false);
-                    object.item = Some(Item::Confuse);
-                    object
-                }
-                Item::Sword => {
-                    // create a sword
-                    let mut object = Object::new(x, y, '/', "sword", SKY, false);
-                    object.item = Some(Item::Sword);
-                    object.equipment = Some(Equipment{
-                        equipped: false,
-                        slot: Slot::RightHand,
-                        power_bonus: 3,
-                        defense_bonus: 0,
-                        max_hp_bonus: 0,
-                    });
-                    object
-                }
-                Item::Shield => {
-                    // create a shield
-                    let mut object = Object::new(x, y, '[', "shield", DARKER_ORANGE, false);
-                    object.item = Some(Item::Shield);
-                    object.equipment = Some(Equipment{
-                        equipped: false,
-                        slot: Slot::LeftHand,
-                        power_bonus: 0,
-                        defense_bonus: 1,
-                        max_hp_bonus: 0,
-                    });
-                    object
-                }
-            };
-            item.always_visible = true;
-            objects.push(item);
+pub fn create_item(kind: Item, x: i32, y: i32) -> Object {
+    let mut item = match kind {
+        Item::Heal => {
+            // create a healing potion
+            let mut object = Object::new(x, y, '!', "healing potion", VIOLET, false);
+            object.item = Some(Item::Heal);
+            object
         }
-    }
+        Item::Ration => {
+            // create an emergency ration
+            let mut object = Object::new(x, y, '%', "emergency ration", LIGHT_ORANGE, false);
+            object.item = Some(Item::Ration);
+            object
+        }
+        Item::Caltrops => {
+            // create a bag of caltrops
+            let mut object = Object::new(x, y, '*', "bag of caltrops", LIGHT_GREY, false);
+            object.item = Some(Item::Caltrops);
+            object
+        }
+        Item::Oil => {
+            // create a flask of oil
+            let mut object = Object::new(x, y, '!', "flask of oil", DARK_AMBER, false);
+            object.item = Some(Item::Oil);
+            object
+        }
+        Item::Poison => {
+            // create a poison potion, which looks just like any other
+            let mut object = Object::new(x, y, '!', "potion of poison", VIOLET, false);
+            object.item = Some(Item::Poison);
+            object
+        }
+        Item::Lightning => {
+            // create a lightning bolt scroll
+            let mut object = Object::new(x, y, '#', "scroll of lightning bolt", LIGHT_YELLOW, false, );
+            object.item = Some(Item::Lightning);
+            object
+        }
+        Item::Fireball => {
+            // create a fireball scroll
+            let mut object = Object::new(x, y, '#', "scroll of fireball", LIGHT_YELLOW, false);
+            object.item = Some(Item::Fireball);
+            object
+        }
+        Item::Confuse => {
+            // create a confuse scroll (10% chance)
+            let mut object = Object::new(x, y, '#', "scroll of confusion", LIGHT_YELLOW, false);
+            object.item = Some(Item::Confuse);
+            object
+        }
+        Item::Entangle => {
+            // create an entangle scroll
+            let mut object = Object::new(x, y, '#', "scroll of entanglement", LIGHT_YELLOW, false);
+            object.item = Some(Item::Entangle);
+            object
+        }
+        Item::Genocide => {
+            // create a genocide scroll (rare)
+            let mut object = Object::new(x, y, '#', "scroll of genocide", LIGHT_YELLOW, false);
+            object.item = Some(Item::Genocide);
+            object
+        }
+        Item::Stone => {
+            // create a stone scroll
+            let mut object = Object::new(x, y, '#', "scroll of stone", LIGHT_YELLOW, false);
+            object.item = Some(Item::Stone);
+            object
+        }
+        Item::Protection => {
+            // create a protection scroll
+            let mut object = Object::new(x, y, '#', "scroll of protection", LIGHT_YELLOW, false);
+            object.item = Some(Item::Protection);
+            object
+        }
+        Item::Recharge => {
+            // create a recharging scroll
+            let mut object = Object::new(x, y, '#', "scroll of recharging", LIGHT_YELLOW, false);
+            object.item = Some(Item::Recharge);
+            object
+        }
+        Item::PhaseDoor => {
+            // create a phase door scroll
+            let mut object = Object::new(x, y, '#', "scroll of phase door", LIGHT_YELLOW, false);
+            object.item = Some(Item::PhaseDoor);
+            object
+        }
+        Item::Key => {
+            // create a key, for locked chests
+            let mut object = Object::new(x, y, '\'', "iron key", LIGHT_GREY, false);
+            object.item = Some(Item::Key);
+            object
+        }
+        Item::Dagger => {
+            // create a dagger
+            let mut object = Object::new(x, y, '-', "dagger", SKY, false);
+            object.item = Some(Item::Dagger);
+            object.equipment = Some(Equipment{
+                equipped: false,
+                slot: Slot::LeftHand,
+                power_bonus: 2,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                reach: 1,
+                on_hit: None,
+            });
+            object
+        }
+        Item::PoisonedDagger => {
+            // create a dagger with a coat of poison that works on whatever it cuts
+            let mut object = Object::new(x, y, '-', "poisoned dagger", LIME, false);
+            object.item = Some(Item::PoisonedDagger);
+            object.equipment = Some(Equipment{
+                equipped: false,
+                slot: Slot::LeftHand,
+                power_bonus: 2,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                reach: 1,
+                on_hit: Some(Status::Poison {
+                    damage_per_turn: WEAPON_POISON_DAMAGE,
+                    turns_left: WEAPON_POISON_TURNS,
+                }),
+            });
+            object
+        }
+        Item::Sword => {
+            // create a sword
+            let mut object = Object::new(x, y, '/', "sword", SKY, false);
+            object.item = Some(Item::Sword);
+            object.equipment = Some(Equipment{
+                equipped: false,
+                slot: Slot::RightHand,
+                power_bonus: 3,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                reach: 1,
+                on_hit: None,
+            });
+            object
+        }
+        Item::Spear => {
+            // create a spear, long enough to hit over an open tile
+            let mut object = Object::new(x, y, '|', "spear", SKY, false);
+            object.item = Some(Item::Spear);
+            object.equipment = Some(Equipment{
+                equipped: false,
+                slot: Slot::RightHand,
+                power_bonus: 2,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                reach: 2,
+                on_hit: None,
+            });
+            object
+        }
+        Item::Shield => {
+            // create a shield
+            let mut object = Object::new(x, y, '[', "shield", DARKER_ORANGE, false);
+            object.item = Some(Item::Shield);
+            object.equipment = Some(Equipment{
+                equipped: false,
+                slot: Slot::LeftHand,
+                power_bonus: 0,
+                defense_bonus: 1,
+                max_hp_bonus: 0,
+                reach: 1,
+                on_hit: None,
+            });
+            object
+        }
+        Item::LightningWand => {
+            // create a wand of lightning, good for a few bolts
+            let mut object = Object::new(x, y, '~', "wand of lightning", LIGHT_BLUE, false);
+            object.item = Some(Item::LightningWand);
+            object.charges = Some(WAND_CHARGES);
+            object
+        }
+        Item::FireballWand => {
+            // create a wand of fireball, good for a few blasts
+            let mut object = Object::new(x, y, '~', "wand of fireball", ORANGE, false);
+            object.item = Some(Item::FireballWand);
+            object.charges = Some(WAND_CHARGES);
+            object
+        }
+    };
+    item.always_visible = true;
+    item
 }
 
 pub fn handle_keys(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>, menu_open: &mut bool) -> PlayerAction {
     use PlayerAction::*;
-    use tcod::input::KeyCode::*;
+    use KeyCode::{
+        Char, Down, End, Enter, Escape, Home, Left, NumPad5, PageDown, PageUp, Right, Shift, Up, F5,
+        F9,
+    };
 
     let player_alive = objects[PLAYER].alive;
-    match (tcod.key, tcod.key.text(), player_alive, &menu_open) {
+    let diagonals = game.settings.movement == MovementMode::EightWay;
+    match (tcod.key, player_alive, &menu_open) {
         (
-            Key {
+            KeyPress {
                 code: Enter,
                 alt: true,
-                ..
             },
             _,
             _,
-            _,
         ) => {
             // Alt + Enter: toggle fullscreen
-            let fullscreen = tcod.root.is_fullscreen();
-            tcod.root.set_fullscreen(!fullscreen);
+            let fullscreen = tcod.renderer.is_fullscreen();
+            tcod.renderer.set_fullscreen(!fullscreen);
+            DidntTakeTurn
+        }
+        #[cfg(debug_assertions)]
+        (KeyPress { code: Char('d') | Char('D'), alt: true }, true, false) => {
+            // Alt + D: the hidden debug menu
+            *menu_open = true;
+            debug_menu(tcod, game, objects);
             DidntTakeTurn
         }
-        (Key { code: Escape, .. }, _, _, _) => Exit, // exit game
-        (Key { code: Up, .. }, _, true, false) | (Key { code: Text, .. }, "w", true, false) => {
-            player_move_or_attack(0, -1, game, objects);
+        (KeyPress { code: Char('b') | Char('B'), alt: true }, _, _) => {
+            // Alt + B: bundle up what's needed to reproduce a bug
+            match write_bug_report(tcod, game, objects) {
+                Ok(path) => game
+                    .messages
+                    .add(format!("Bug report written to {}.", path), Severity::Info),
+                Err(e) => game
+                    .messages
+                    .add(format!("Could not write the bug report: {}", e), Severity::Warning),
+            }
+            DidntTakeTurn
+        }
+        (KeyPress { code: Escape, .. }, _, _) => Exit, // exit game
+        (KeyPress { code: F5, .. }, true, false) => {
+            // quick-save, leaving the exit save alone
+            match save_game(game, objects, QUICKSAVE_FILE) {
+                Ok(()) => game.messages.add("Game quick-saved.", Severity::Info),
+                Err(e) => game
+                    .messages
+                    .add(format!("Could not quick-save: {}", e), Severity::Warning),
+            }
+            DidntTakeTurn
+        }
+        // loading works from beyond the grave too
+        (KeyPress { code: F9, .. }, _, false) => QuickLoad,
+        (KeyPress { code: Up | Char('w'), .. }, true, false) => {
+            player_move_or_attack(0, -1, tcod, game, objects);
             TookTurn
         },
-        (Key { code: Down, .. }, _, true, false) | (Key { code: Text, .. }, "s", true, false) => {
-            player_move_or_attack(0, 1, game, objects);
+        (KeyPress { code: Down | Char('s'), .. }, true, false) => {
+            player_move_or_attack(0, 1, tcod, game, objects);
             TookTurn
         },
-        (Key { code: Left, .. }, _, true, false) | (Key { code: Text, .. }, "a", true, false) => {
-            player_move_or_attack(-1, 0, game, objects);
+        (KeyPress { code: Left | Char('a'), .. }, true, false) => {
+            player_move_or_attack(-1, 0, tcod, game, objects);
             TookTurn
         },
-        (Key { code: Right, .. }, _, true, false) | (Key { code: Text, .. }, "d", true, false) => {
-            player_move_or_attack(1, 0, game, objects);
+        (KeyPress { code: Right | Char('d'), .. }, true, false) => {
+            player_move_or_attack(1, 0, tcod, game, objects);
             TookTurn
         },
-        (Key { code: Home, .. }, _, true, false) | (Key { code: Text, .. }, "q", true, false) => {
-            player_move_or_attack(-1, -1, game, objects);
+        // the diagonals only work when moving eight ways
+        (KeyPress { code: Home | Char('q'), .. }, true, false) if diagonals => {
+            player_move_or_attack(-1, -1, tcod, game, objects);
             TookTurn
         },
-        (Key { code: PageUp, .. }, _, true, false) | (Key { code: Text, .. }, "e", true, false) => {
-            player_move_or_attack(1, -1, game, objects);
+        (KeyPress { code: PageUp | Char('e'), .. }, true, false) if diagonals => {
+            player_move_or_attack(1, -1, tcod, game, objects);
             TookTurn
         },
-        (Key { code: End, .. }, _, true, false) | (Key { code: Text, .. }, "z", true, false) => {
-            player_move_or_attack(-1, 1, game, objects);
+        (KeyPress { code: End | Char('z'), .. }, true, false) if diagonals => {
+            player_move_or_attack(-1, 1, tcod, game, objects);
             TookTurn
         },
-        (Key { code: PageDown, .. }, _, true, false) | (Key { code: Text, .. }, "x", true, false) => {
-            player_move_or_attack(1, 1, game, objects);
+        (KeyPress { code: PageDown | Char('x'), .. }, true, false) if diagonals => {
+            player_move_or_attack(1, 1, tcod, game, objects);
             TookTurn
         },
-        (Key { code: NumPad5, .. }, _, true, false) | (Key { code: Shift, .. }, _, true, false) => {
+        (KeyPress { code: NumPad5 | Shift, .. }, true, false) => {
             TookTurn // do nothing, i.e. wait for the monster to come to you
         },
-        (Key { code: Text, .. }, "g", true, _) => {
+        (KeyPress { code: Char('R'), .. }, true, false) => {
+            game.messages.add("You settle down to rest.", Severity::Info);
+            Rest
+        },
+        (KeyPress { code: Char('W'), .. }, true, false) => {
+            // warriors can hit everything around them at once
+            if game.class != PlayerClass::Warrior {
+                game.messages
+                    .add("Only a warrior knows the whirlwind attack.", Severity::Warning);
+                DidntTakeTurn
+            } else if whirlwind_attack(game, objects) {
+                TookTurn
+            } else {
+                DidntTakeTurn
+            }
+        }
+        (KeyPress { code: Char('g'), .. }, true, _) => {
             // pick up an item
-            let item_id = objects
+            let (player_x, player_y) = objects[PLAYER].pos();
+            let item_id = game
+                .positions
+                .at(player_x, player_y)
                 .iter()
-                .position(|object| object.pos() == objects[PLAYER].pos() && object.item.is_some());
+                .copied()
+                .find(|&id| objects[id].item.is_some());
             if let Some(item_id) = item_id {
-                pick_item_up(item_id, game, objects);
+                pick_item_up(item_id, tcod, game, objects);
             }
             DidntTakeTurn
         },
-        (Key { code: Text, ..}, "i", true, false) => {
-            // show the inventory; if an item is selected, use it
+        (KeyPress { code: Char('G'), .. }, true, _) => {
+            // pick up everything on this tile
+            pick_up_all(tcod, game, objects, |_| true);
+            DidntTakeTurn
+        },
+        (KeyPress { code: Char('i'), .. }, true, false) => {
+            // show the inventory; if an item is selected, use it. Backing out
+            // of aiming an item brings the inventory back up
             *menu_open = true;
-            let inventory_index = inventory_menu(
-                &game.inventory,
-                "Press the key next to an item to use it, or any other to cancel.\n",
-                &mut tcod.root,
-            );
-            if let Some(inventory_index) = inventory_index {
-                use_item(inventory_index, tcod, game, objects);
+            loop {
+                let inventory_index = inventory_menu(
+                    game,
+                    &objects[PLAYER],
+                    "Press the key next to an item to use it, or any other to cancel.\n",
+                    &mut *tcod.renderer,
+                );
+                match inventory_index {
+                    Some(inventory_index) if use_item(inventory_index, tcod, game, objects) => {}
+                    _ => break,
+                }
             }
             DidntTakeTurn
         },
-        (Key { code: Text, .. }, "f", true, false) => {
+        (KeyPress { code: Char('f'), .. }, true, false) => {
             // show the inventory; if an item is selected, drop it
             *menu_open = true;
             let inventory_index = inventory_menu(
-                &game.inventory,
+                game,
+                &objects[PLAYER],
                 "Press the key next to an item to drop it, or any other to cancel.\n",
-                &mut tcod.root,
+                &mut *tcod.renderer,
             );
             if let Some(inventory_index) = inventory_index {
                 drop_item(inventory_index, game, objects);
             }
             DidntTakeTurn
         },
-        (Key { code: Text, .. }, "<", true, false) => {
-            // go down stairs, if the player is on them
-            let player_on_stairs = objects
+        (KeyPress { code: Char('F'), .. }, true, false) => {
+            // show the inventory; if an item is selected, drop all of its kind
+            *menu_open = true;
+            let inventory_index = inventory_menu(
+                game,
+                &objects[PLAYER],
+                "Press the key next to an item to drop all of its kind, or any other to cancel.\n",
+                &mut *tcod.renderer,
+            );
+            if let Some(inventory_index) = inventory_index {
+                drop_all_of_kind(inventory_index, game, objects);
+            }
+            DidntTakeTurn
+        },
+        (KeyPress { code: Char('<'), .. }, true, false) => {
+            // go down stairs, if the player is on them, into wherever they lead
+            let below = objects
+                .iter()
+                .filter(|object| object.pos() == objects[PLAYER].pos())
+                .find_map(|object| game.branch.below(&object.name));
+            if let Some(branch) = below {
+                next_level(branch, tcod, game, objects);
+            }
+            DidntTakeTurn
+        }
+        (KeyPress { code: Char('>'), .. }, true, false) => {
+            // go back up stairs, if the player is on them
+            let player_on_upstairs = objects
                 .iter()
-                .any(|object| object.pos() == objects[PLAYER].pos() && object.name == "stairs");
-            if player_on_stairs {
-                next_level(tcod, game, objects);
+                .any(|object| object.pos() == objects[PLAYER].pos() && object.name == "upstairs");
+            let to_town = game.branch == Branch::Main && game.dungeon_level == TOWN_LEVEL + 1;
+            if player_on_upstairs && to_town && !game.settings.town {
+                game.messages.add("The way up to the town is barred.", Severity::Warning);
+            } else if player_on_upstairs {
+                previous_level(tcod, game, objects);
             }
             DidntTakeTurn
         }
-        (Key { code: Text, .. }, "c", true, false) => {
+        (KeyPress { code: Char('c'), .. }, true, false) => {
             // show character information
             *menu_open = true;
             let player = &objects[PLAYER];
             let level = player.level;
-            let level_up_xp = LEVEL_UP_BASE + player.level * LEVEL_UP_FACTOR;
+            let level_up_xp = game.settings.xp_curve.level_up_xp(player.level);
+            // everything slain so far this run, by kind
+            let kill_lines = game.stats.kill_lines();
+            let bestiary = if kill_lines.is_empty() {
+                "  nothing yet".to_string()
+            } else {
+                kill_lines.join("\n")
+            };
+            // temporary protection on top of the usual stats
+            let shield = if game.shield.is_up() {
+                format!(
+                    "\nShield: {} for {} more turns",
+                    game.shield.points, game.shield.turns
+                )
+            } else {
+                String::new()
+            };
+            // and what the pack costs, if weight matters
+            let load = if game.settings.encumbrance {
+                let penalty = match player.encumbrance(game) {
+                    0 => String::new(),
+                    penalty => format!(" (-{} defense)", penalty),
+                };
+                format!(
+                    "\nCarrying: {}/{}{}",
+                    carried_weight(&game.inventory),
+                    player.carrying_capacity(),
+                    penalty
+                )
+            } else {
+                String::new()
+            };
             if let Some(fighter) = player.fighter.as_ref() {
                 let msg = format!(
                     "Character information\n\
                     \n\
+                    Class: {}\n\
                     Level: {}\n\
                     Experience: {}\n\
                     Experience to level up: {}\n\
                     \n\
                     Maximum HP: {}\n\
                     Attack: {}\n\
-                    Defense: {}",
+                    Defense: {}{}{}\n\
+                    \n\
+                    Bestiary ({} slain):\n\
+                    {}{}",
+                    game.class.name(),
                     level,
                     fighter.xp,
                     level_up_xp,
-                    player.max_hp(game),
-                    player.power(game),
-                    player.defense(game),
+                    stat_breakdown(fighter.base_max_hp, game, |e| e.max_hp_bonus),
+                    stat_breakdown(fighter.base_power, game, |e| e.power_bonus),
+                    stat_breakdown(fighter.base_defense, game, |e| e.defense_bonus),
+                    shield,
+                    load,
+                    game.stats.total_kills(),
+                    bestiary,
+                    debug_run_note(game),
                 );
-                msgbox(&msg, CHARACTER_SCREEN_WIDTH, &mut tcod.root);
+                msgbox(&msg, CHARACTER_SCREEN_WIDTH, &mut *tcod.renderer);
+            }
+            DidntTakeTurn
+        }
+        (KeyPress { code: Char('o'), .. }, true, false) => {
+            // show the options menu
+            *menu_open = true;
+            options_menu(&mut game.settings, &mut *tcod.renderer);
+            game.settings.apply_fps();
+            DidntTakeTurn
+        }
+        (KeyPress { code: Char('m'), .. }, _, false) => {
+            // look back over every message so far
+            *menu_open = true;
+            message_log(&game.messages, &mut *tcod.renderer);
+            DidntTakeTurn
+        }
+        (KeyPress { code: Char('J'), .. }, _, _) => {
+            // dump the game state for inspection, without saving or quitting
+            match export_game(game, objects) {
+                Ok(()) => game
+                    .messages
+                    .add("Game state exported to game_export.json.", Severity::Info),
+                Err(e) => game
+                    .messages
+                    .add(format!("Could not export the game state: {}", e), Severity::Warning),
             }
             DidntTakeTurn
         }
@@ -744,15 +4111,133 @@ pub fn handle_keys(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>,
     }
 }
 
+/// A stat's total, followed by where it comes from if any equipment adds to
+/// it, e.g. "7 (4 base + 3 from sword)"
+fn stat_breakdown(base: i32, game: &Game, bonus: impl Fn(&Equipment) -> i32) -> String {
+    let mut total = base;
+    let mut parts = format!("{} base", base);
+    let mut from_gear = false;
+    for item in &game.inventory {
+        let amount = match item.equipment {
+            Some(equipment) if equipment.equipped => bonus(&equipment),
+            _ => 0,
+        };
+        if amount != 0 {
+            from_gear = true;
+            total += amount;
+            let sign = if amount > 0 { '+' } else { '-' };
+            parts += &format!(" {} {} from {}", sign, amount.abs(), item.name);
+        }
+    }
+    if !from_gear {
+        total.to_string()
+    } else {
+        format!("{} ({})", total, parts)
+    }
+}
+
+/// a line flagging a game that used debug options, if it did
+pub fn debug_run_note(game: &Game) -> String {
+    if game.stats.start_level > 1 {
+        format!("\n\nStarted on level {} (debug run)", game.stats.start_level)
+    } else if game.stats.debug_used {
+        String::from("\n\nDebug run")
+    } else {
+        String::new()
+    }
+}
+
+/// the developer's cheats, only in debug builds. marks the run as a debug run
+#[cfg(debug_assertions)]
+fn debug_menu(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    let options = &[
+        format!("Invulnerable: {}", if game.invulnerable { "on" } else { "off" }),
+        String::from("Reveal the map"),
+        String::from("Spawn an item under the mouse"),
+        String::from("Level up"),
+    ];
+    let choice = menu("Debug\n", options, OPTIONS_WIDTH, &mut *tcod.renderer);
+    if choice.is_some() {
+        game.stats.debug_used = true;
+    }
+    match choice {
+        Some(0) => game.invulnerable = !game.invulnerable,
+        Some(1) => {
+            for tile in game.map.tiles_mut() {
+                tile.explored = true;
+            }
+            tcod.render.map_changed();
+        }
+        Some(2) => {
+            let (x, y) = (tcod.mouse.x, tcod.mouse.y);
+            let on_floor = (0..MAP_WIDTH).contains(&x)
+                && (0..MAP_HEIGHT).contains(&y)
+                && !game.map.get(x, y).blocked;
+            if !on_floor {
+                game.messages.add("Point the mouse at the floor first.", Severity::Warning);
+                return;
+            }
+            let names: Vec<&str> = ALL_ITEMS.iter().map(|item| item.name()).collect();
+            let choice = menu("Spawn which item?\n", &names, OPTIONS_WIDTH, &mut *tcod.renderer);
+            if let Some(index) = choice {
+                let mut item = create_item(ALL_ITEMS[index], x, y);
+                disguise_unidentified(game, std::slice::from_mut(&mut item));
+                game.positions.added(objects.len(), (x, y));
+                objects.push(item);
+            }
+        }
+        Some(3) => {
+            // just enough experience for the next level, which play_game picks up
+            let player = &mut objects[PLAYER];
+            let level_up_xp = game.settings.xp_curve.level_up_xp(player.level);
+            if let Some(fighter) = player.fighter.as_mut() {
+                fighter.xp = cmp::max(fighter.xp, level_up_xp);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// true if the (living) player is at or below the low HP threshold
+pub fn is_low_hp(player: &Object, game: &Game) -> bool {
+    let hp = player.fighter.map_or(0, |f| f.hp);
+    player.alive && hp * 100 <= player.max_hp(game) * game.settings.low_hp_threshold
+}
+
+/// warn the player once each time their health drops below the threshold.
+/// returns true on the turn the threshold is crossed
+pub fn check_low_hp(game: &mut Game, objects: &[Object]) -> bool {
+    let low_hp = is_low_hp(&objects[PLAYER], game);
+    let crossed = low_hp && !game.low_hp_warned;
+    game.low_hp_warned = low_hp;
+    if crossed {
+        game.messages.add("Your health is critically low!", Severity::Critical);
+    }
+    crossed
+}
+
 pub fn player_death(player: &mut Object, game: &mut Game) {
     // the game ended!
-    game.messages.add("You died!", RED);
+    game.messages.add("You died!", Severity::Critical);
 
     // for added effect, transform the player into a corpse!
     player.char = '%';
     player.color = DARK_RED;
 }
 
+pub fn pet_death(pet: &mut Object, game: &mut Game) {
+    game.messages
+        .add(format!("Your {} dies!", pet.name), Severity::Danger);
+    pet.char = '%';
+    pet.color = DARK_RED;
+    pet.blocks = false;
+    pet.fighter = None;
+    pet.ai = None;
+    // what's left can't move, so it stays where the player remembers it
+    pet.always_visible = true;
+    pet.name = format!("remains of {}", pet.name);
+}
+
 pub fn monster_death(monster: &mut Object, game: &mut Game) {
     // transform it into a nasty corpse! it doesn't block, can't be
     // attacked and doesn't move
@@ -762,14 +4247,401 @@ pub fn monster_death(monster: &mut Object, game: &mut Game) {
             monster.name,
             monster.fighter.unwrap().xp
         ),
-        ORANGE,
+        Severity::Good,
     );
+    *game.stats.kills.entry(monster.name.clone()).or_insert(0) += 1;
     monster.char = '%';
     monster.color = DARK_RED;
     monster.blocks = false;
     monster.fighter = None;
     monster.ai = None;
+    // a corpse never moves, so it can show on explored tiles out of sight,
+    // even when the monster died there unseen
+    monster.always_visible = true;
     monster.name = format!("remains of {}", monster.name);
     game.messages
-        .add(&monster.name, ORANGE);
+        .add(&monster.name, Severity::Info);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::distributions::Weighted;
+
+    fn rng(seed: usize) -> StdRng {
+        StdRng::from_seed(&[seed][..])
+    }
+
+    #[test]
+    fn choose_weighted_skips_zero_weights() {
+        let chances = || {
+            vec![
+                Weighted { weight: 0, item: "never" },
+                Weighted { weight: 3, item: "often" },
+                Weighted { weight: 0, item: "never" },
+                Weighted { weight: 1, item: "sometimes" },
+            ]
+        };
+        let rng = &mut rng(1);
+        let picks: Vec<&str> = (0..1000).map(|_| choose_weighted(chances(), rng)).collect();
+        assert!(!picks.contains(&"never"));
+        assert!(picks.contains(&"often") && picks.contains(&"sometimes"));
+    }
+
+    #[test]
+    fn choose_weighted_with_one_chance_left() {
+        let chances = vec![
+            Weighted { weight: 0, item: 1 },
+            Weighted { weight: 0, item: 2 },
+            Weighted { weight: 5, item: 3 },
+        ];
+        assert_eq!(choose_weighted(chances, &mut rng(2)), 3);
+    }
+
+    #[test]
+    fn first_level_only_has_monsters_that_show_up_there() {
+        let rng = &mut rng(3);
+        for _ in 0..500 {
+            let monster = random_monster(0, 0, Branch::Main, 1, &[], rng).unwrap();
+            assert!(["orc", "goblin"].contains(&monster.name.as_str()), "{}", monster.name);
+        }
+    }
+
+    /// a solid map with the given tiles dug out
+    fn map_with_floor(floor: &[(i32, i32)]) -> Map {
+        let mut map = Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+        for &(x, y) in floor {
+            map.set(x, y, Tile::empty());
+        }
+        map
+    }
+
+    #[test]
+    fn tiles_only_connect_through_their_sides() {
+        let map = map_with_floor(&[(5, 5), (6, 6), (7, 6)]);
+        assert!(!is_reachable(&map, (5, 5), (6, 6)));
+        assert!(is_reachable(&map, (6, 6), (7, 6)));
+        assert_eq!(reachable_from(&map, (5, 5)), Some(vec![(5, 5)]));
+        assert_eq!(reachable_from(&map, (1, 1)), None);
+    }
+
+    #[test]
+    fn start_moves_off_a_wall_to_the_nearest_tile_reaching_the_stairs() {
+        // a corridor to the stairs, and a closer pocket that's cut off from it
+        let mut floor: Vec<(i32, i32)> = (10..=20).map(|x| (x, 10)).collect();
+        floor.push((5, 8));
+        let map = map_with_floor(&floor);
+        assert_eq!(safe_start_position(&map, (6, 9), (20, 10)), Some((10, 10)));
+        assert_eq!(safe_start_position(&map, (15, 10), (20, 10)), Some((15, 10)));
+        assert_eq!(safe_start_position(&map, (15, 10), (20, 11)), None);
+    }
+
+    #[test]
+    fn player_never_starts_on_a_blocked_tile() {
+        for seed in 0..40 {
+            for level in 1..6 {
+                for &generator in &[MapGenerator::RandomRooms, MapGenerator::Bsp] {
+                    let (map, objects) = generate_level(seed, Branch::Main, level, generator);
+                    let start = objects[PLAYER].pos();
+                    let stairs = objects.iter().find(|object| object.name == "stairs").unwrap();
+                    let context = format!("seed {} level {}", seed, level);
+                    assert!(!map.get(start.0, start.1).blocked, "{}", context);
+                    assert!(is_reachable(&map, start, stairs.pos()), "{}", context);
+                }
+            }
+        }
+    }
+
+    /// a game on the first level, with a player who can take a hit
+    fn first_level(seed: usize) -> (Game, Vec<Object>) {
+        let (map, objects) = generate_level(seed, Branch::Main, 1, MapGenerator::RandomRooms);
+        let (mut game, objects) = test_game(map, objects);
+        game.seed = seed;
+        (game, objects)
+    }
+
+    #[test]
+    fn left_level_survives_a_save() {
+        let (mut game, mut objects) = first_level(4);
+        game.map.get_mut(3, 4).explored = true;
+        let state = LevelState {
+            map: game.map,
+            objects: objects.split_off(1),
+            genocided: vec!["orc".into()],
+        };
+        let saved = serde_json::to_string(&state).unwrap();
+        let loaded: LevelState = serde_json::from_str(&saved).unwrap();
+        assert!(loaded.map.get(3, 4).explored);
+        assert_eq!(loaded.genocided, vec!["orc".to_string()]);
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), saved);
+    }
+
+    #[test]
+    fn level_comes_back_as_it_was_left() {
+        let (mut game, mut objects) = first_level(5);
+        let mut tcod = Tcod::new(Box::new(NullRenderer::default()));
+        for x in 0..MAP_WIDTH / 2 {
+            game.map.get_mut(x, 7).explored = true;
+        }
+        let map_before = serde_json::to_string(&game.map).unwrap();
+        let others_before = serde_json::to_string(&objects[1..]).unwrap();
+
+        next_level(Branch::Main, &mut tcod, &mut game, &mut objects);
+        assert_eq!(game.dungeon_level, 2);
+        previous_level(&mut tcod, &mut game, &mut objects);
+        assert_eq!(game.dungeon_level, 1);
+
+        assert_eq!(serde_json::to_string(&game.map).unwrap(), map_before);
+        assert_eq!(serde_json::to_string(&objects[1..]).unwrap(), others_before);
+        // and the player is back on the stairs they went down
+        let stairs = objects.iter().find(|object| object.name == "stairs").unwrap();
+        assert_eq!(objects[PLAYER].pos(), stairs.pos());
+    }
+
+    #[test]
+    fn orphaned_room_gets_tunneled_to() {
+        let mut floor: Vec<(i32, i32)> = (10..=20).map(|x| (x, 10)).collect();
+        floor.extend((40..=45).map(|x| (x, 30)));
+        let mut map = map_with_floor(&floor);
+        let player = Object::new(10, 10, '@', "player", WHITE, true);
+        let stairs = Object::new(44, 30, '<', "stairs", WHITE, false);
+        connect_orphans(&mut map, &[player, stairs], (10, 10));
+        assert!(is_reachable(&map, (10, 10), (44, 30)));
+        assert!(is_reachable(&map, (10, 10), (40, 30)));
+    }
+
+    #[test]
+    fn generated_levels_are_one_connected_piece() {
+        for seed in 0..30 {
+            for &branch in BRANCHES {
+                for level in 1..8 {
+                    let generator = MapGenerator::RandomRooms;
+                    let (map, objects) = generate_level(seed, branch, level, generator);
+                    let reachable = reachable_from(&map, objects[PLAYER].pos()).unwrap();
+                    let context = format!("seed {} level {} of {:?}", seed, level, branch);
+                    // every room, and everything in them, stairs included
+                    let open = (0..MAP_WIDTH)
+                        .flat_map(|x| (0..MAP_HEIGHT).map(move |y| (x, y)))
+                        .filter(|&(x, y)| !map.get(x, y).blocked)
+                        .count();
+                    assert_eq!(reachable.len(), open, "{}", context);
+                    for object in &objects {
+                        assert!(reachable.contains(&object.pos()), "{}: {}", context, object.name);
+                    }
+                }
+            }
+        }
+    }
+
+    /// a game on `map` with only a player standing at (x, y)
+    fn lone_player(map: Map, x: i32, y: i32) -> (Game, Vec<Object>) {
+        test_game(map, vec![Object::new(x, y, '@', "player", WHITE, true)])
+    }
+
+    #[test]
+    fn state_with_a_wrong_sized_map_is_rejected() {
+        let (mut game, mut objects) = lone_player(Map::new(10, 10, Tile::empty()), 1, 1);
+        let result = validate_game_state(&mut game, &mut objects);
+        assert_eq!(result, Err("the map is the wrong size".to_string()));
+    }
+
+    #[test]
+    fn state_with_a_torn_map_is_rejected() {
+        // the size is right but tiles have gone missing from the buffer
+        let mut saved = serde_json::to_value(map_with_floor(&[(1, 1)])).unwrap();
+        saved["tiles"].as_array_mut().unwrap().truncate(100);
+        let map: Map = serde_json::from_value(saved).unwrap();
+        let (mut game, mut objects) = lone_player(map, 1, 1);
+        let result = validate_game_state(&mut game, &mut objects);
+        assert_eq!(result, Err("the map is the wrong size".to_string()));
+    }
+
+    #[test]
+    fn state_with_no_floor_is_rejected() {
+        let (mut game, mut objects) = lone_player(map_with_floor(&[]), 1, 1);
+        let result = validate_game_state(&mut game, &mut objects);
+        assert_eq!(result, Err("the map has no floor".to_string()));
+    }
+
+    #[test]
+    fn state_without_a_player_is_rejected() {
+        let missing = Err("the player is missing".to_string());
+        let (mut game, mut objects) = lone_player(map_with_floor(&[(1, 1)]), 1, 1);
+
+        objects[PLAYER].fighter = None;
+        assert_eq!(validate_game_state(&mut game, &mut objects), missing);
+
+        objects[PLAYER] = Object::new(1, 1, 'o', "orc", WHITE, true);
+        objects[PLAYER].fighter = Some(PlayerClass::Warrior.fighter());
+        assert_eq!(validate_game_state(&mut game, &mut objects), missing);
+
+        objects.clear();
+        assert_eq!(validate_game_state(&mut game, &mut objects), missing);
+    }
+
+    #[test]
+    fn fixable_state_gets_repaired() {
+        let floor: Vec<(i32, i32)> = (10..=20).map(|x| (x, 10)).collect();
+        let (mut game, mut objects) = lone_player(map_with_floor(&floor), 5, 5);
+        objects.push(Object::new(-3, MAP_HEIGHT + 4, '!', "potion", WHITE, false));
+        let repairs = validate_game_state(&mut game, &mut objects).unwrap();
+        assert_eq!(repairs.len(), 3);
+        assert_eq!(objects[PLAYER].pos(), (10, 10));
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[1].name, "stairs");
+        assert_eq!(objects[1].pos(), (20, 10));
+        // and once it's sound there's nothing left to do
+        assert_eq!(validate_game_state(&mut game, &mut objects), Ok(vec![]));
+    }
+
+    #[test]
+    fn wanderers_come_from_the_game_seed_and_keep_their_ai() {
+        let spawn = || {
+            let (mut game, mut objects) = first_level(7);
+            game.stats.turns = 0;
+            let count = objects.len();
+            spawn_wanderer(&FovMap::new(MAP_WIDTH, MAP_HEIGHT), &mut game, &mut objects);
+            assert_eq!(objects.len(), count + 1);
+            objects.pop().unwrap()
+        };
+        let (first, second) = (spawn(), spawn());
+        assert_eq!(serde_json::to_string(&first).unwrap(), serde_json::to_string(&second).unwrap());
+        match first.ai {
+            Some(Ai::Hunting { then, .. }) => assert!(!matches!(*then, Ai::Hunting { .. })),
+            ai => panic!("a wanderer should be hunting, not {:?}", ai),
+        }
+    }
+
+    /// the player at (10, 10) and an orc at (12, 12), on an open map with
+    /// walls at the given tiles
+    fn orc_behind(walls: &[(i32, i32)]) -> (Game, Vec<Object>) {
+        let mut map = Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::empty());
+        for &(x, y) in walls {
+            map.set(x, y, Tile::wall());
+        }
+        let player = Object::new(10, 10, '@', "player", WHITE, true);
+        let orc = Object::new(12, 12, 'o', "orc", DESATURATED_GREEN, true);
+        test_game(map, vec![player, orc])
+    }
+
+    #[test]
+    fn monster_slides_around_a_corner_in_its_way() {
+        // the diagonal is walled off, so it steps along the row instead
+        let (mut game, mut objects) = orc_behind(&[(11, 11)]);
+        move_towards(1, 10, 10, &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (11, 12));
+        // straight up is the wall again, so round it the other way
+        move_towards(1, 10, 10, &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (10, 12));
+        move_towards(1, 10, 10, &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (10, 11));
+
+        // with the row walled off too, up the column
+        let (mut game, mut objects) = orc_behind(&[(11, 11), (11, 12)]);
+        move_towards(1, 10, 10, &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (12, 11));
+
+        // and with nowhere closer to go, it stays put
+        let (mut game, mut objects) = orc_behind(&[(11, 11), (11, 12), (12, 11)]);
+        move_towards(1, 10, 10, &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (12, 12));
+    }
+
+    #[test]
+    fn level_with_no_room_to_spare_gets_one_in_the_middle() {
+        // a digger that never manages to fit a room anywhere
+        let (map, rooms, start) = lay_out_rooms(&mut rng(8), |_, _| vec![]);
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(start, rooms[0].center());
+        assert!(!map.get(start.0, start.1).blocked);
+        let open = (0..MAP_WIDTH)
+            .flat_map(|x| (0..MAP_HEIGHT).map(move |y| (x, y)))
+            .filter(|&(x, y)| !map.get(x, y).blocked)
+            .count();
+        assert_eq!(reachable_from(&map, start).unwrap().len(), open);
+    }
+
+    #[test]
+    fn map_accessors_match_indexing_by_column_then_row() {
+        // the way the map used to be kept: map[x][y]
+        let mut old = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        let mut map = Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+        let spots = [(0, 0), (MAP_WIDTH - 1, 0), (0, MAP_HEIGHT - 1), (3, 7), (7, 3)];
+        for (i, &(x, y)) in spots.iter().enumerate() {
+            let tile = Tile { explored: i % 2 == 0, ..Tile::empty() };
+            old[x as usize][y as usize] = tile;
+            map.set(x, y, tile);
+        }
+        map.get_mut(MAP_WIDTH - 1, MAP_HEIGHT - 1).explored = true;
+        old[MAP_WIDTH as usize - 1][MAP_HEIGHT as usize - 1].explored = true;
+
+        for x in 0..MAP_WIDTH {
+            for y in 0..MAP_HEIGHT {
+                assert_eq!(*map.get(x, y), old[x as usize][y as usize], "({}, {})", x, y);
+            }
+        }
+        assert_eq!(map.width(), old.len() as i32);
+        assert_eq!(map.height(), old[0].len() as i32);
+    }
+
+    #[test]
+    #[should_panic(expected = "off the")]
+    fn map_refuses_a_tile_past_the_end_of_a_row() {
+        // which would otherwise be the first tile of the next row
+        let map = Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+        map.get(MAP_WIDTH, 0);
+    }
+
+    #[test]
+    fn scent_is_kept_per_tile() {
+        let mut scent = ScentMap::new();
+        scent.mark((3, 7), 10);
+        scent.mark((MAP_WIDTH - 1, MAP_HEIGHT - 1), 10);
+        assert_eq!(scent.strength(3, 7, 10), SCENT_DURATION);
+        assert_eq!(scent.strength(7, 3, 10), 0);
+        assert_eq!(scent.strength(3, 7, 12), SCENT_DURATION - 2);
+        assert_eq!(scent.strength(MAP_WIDTH - 1, MAP_HEIGHT - 1, 10), SCENT_DURATION);
+        // nothing to smell off the map
+        scent.mark((-1, 5), 10);
+        assert_eq!(scent.strength(-1, 5, 10), 0);
+        assert_eq!(scent.strength(MAP_WIDTH, 0, 10), 0);
+    }
+
+    #[test]
+    fn mimic_found_out_underfoot_steps_aside() {
+        let map = Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::empty());
+        let (mut game, mut objects) = lone_player(map, 10, 10);
+        objects.push(create_mimic(10, 10, 1, &mut rng(1)));
+        objects.push(create_item(Item::Heal, 10, 10));
+        objects.push(create_item(Item::Heal, 10, 10));
+        game.positions.rebuild(&objects);
+        let mut tcod = Tcod::new(Box::new(NullRenderer::default()));
+        pick_up_all(&mut tcod, &mut game, &mut objects, |_| true);
+        let mimic = objects.iter().find(|object| object.name == "mimic").unwrap();
+        assert!(mimic.blocks);
+        assert_ne!(mimic.pos(), (10, 10));
+        assert!(mimic.distance(10, 10) < 2.0);
+        assert!(game.positions.is_in_sync(&objects));
+        // it was a mimic that stopped the pickup, not a lack of room
+        assert!(game.messages.iter().any(|m| m.text.contains("all along")));
+        assert!(!game.messages.iter().any(|m| m.text.contains("left on the floor")));
+    }
+
+    #[test]
+    fn opening_a_chest_names_it_as_it_was() {
+        let map = Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::empty());
+        let (mut game, mut objects) = lone_player(map, 10, 10);
+        let said = |game: &Game, text: &str| game.messages.iter().any(|m| m.text == text);
+        for (x, contents) in [(11, vec![]), (9, vec![create_item(Item::Heal, 0, 0)])] {
+            let mut chest = Object::new(x, 10, '=', "locked chest", WHITE, true);
+            chest.contents = Some(contents);
+            game.inventory.push(create_item(Item::Key, 0, 0));
+            objects.push(chest);
+            game.positions.rebuild(&objects);
+            open_container(objects.len() - 1, &mut game, &mut objects);
+        }
+        assert!(said(&game, "The chest is empty."));
+        assert!(said(&game, "You open the chest."));
+        assert_eq!(objects[1].name, "open chest");
+    }
 }
@@ -0,0 +1,328 @@
+use rand::Rng;
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub mod backend;
+use crate::backend::colors::*;
+use crate::backend::{FovAlgorithm, FovMap};
+
+pub mod object;
+use crate::object::*;
+use crate::object_types::*;
+
+pub mod render;
+use crate::render::*;
+
+pub mod game;
+use crate::game::*;
+
+pub mod renderer;
+use crate::renderer::*;
+
+pub mod plugin;
+use crate::plugin::*;
+
+pub use crate::game::{Game, LaunchOptions, Map, Messages, Severity, Settings, Tcod, Tile};
+pub use crate::object::Object;
+pub use crate::render::main_menu;
+pub use crate::renderer::Renderer;
+#[cfg(feature = "tcod")]
+pub use crate::renderer::TcodRenderer;
+
+// actual size of the window
+pub const SCREEN_WIDTH: i32 = 80;
+pub const SCREEN_HEIGHT: i32 = 50;
+
+// size of the map
+const MAP_WIDTH: i32 = 80;
+const MAP_HEIGHT: i32 = 43;
+
+// sizes and coords relevant to the GUI
+const BAR_WIDTH: i32 = 20;
+const PANEL_HEIGHT: i32 = 7;
+const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
+
+const MSG_X: i32 = BAR_WIDTH + 2;
+const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
+const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
+// how many actors the turn order in the panel lists before trailing off
+const TURN_ORDER_LENGTH: usize = 5;
+
+const INVENTORY_WIDTH: i32 = 50;
+// a menu shows at most one option per letter at a time
+const MENU_PAGE_SIZE: usize = 26;
+
+// player will always be the first object
+const PLAYER: usize = 0;
+
+// experience and level-ups, for each of the curves in the options
+const LEVEL_UP_BASE: i32 = 200;
+const LEVEL_UP_FACTOR: i32 = 150;
+const LEVEL_UP_QUADRATIC_FACTOR: i32 = 50;
+// past the end of the table, each level costs as much more as the last did
+const LEVEL_UP_TABLE: [i32; 8] = [300, 450, 650, 900, 1200, 1600, 2100, 2700];
+// monsters are worth this many percent more xp for each level below the first
+const XP_PERCENT_PER_LEVEL: i32 = 10;
+
+const CHARACTER_SCREEN_WIDTH: i32 = 30;
+
+const LEVEL_SCREEN_WIDTH: i32 = 40;
+
+const OPTIONS_WIDTH: i32 = 50;
+
+const CLASS_SCREEN_WIDTH: i32 = 50;
+
+const SUMMARY_SCREEN_WIDTH: i32 = 30;
+
+const OVERWRITE_SCREEN_WIDTH: i32 = 40;
+
+// warn the player when HP drops to this percentage of max HP
+const LOW_HP_THRESHOLD: i32 = 25;
+const LOW_HP_THRESHOLD_CHOICES: [i32; 3] = [10, 25, 50];
+
+// how many items the player can carry, and the presets in the options menu
+const INVENTORY_CAP: i32 = 26;
+const INVENTORY_CAP_CHOICES: [i32; 4] = [10, 26, 40, 52];
+
+// how much weight the player can carry: a fixed amount plus some per point of
+// base power. every started step over that costs a point of defense
+const CARRY_CAPACITY_BASE: i32 = 20;
+const CARRY_CAPACITY_PER_POWER: i32 = 5;
+const ENCUMBRANCE_STEP: i32 = 5;
+
+// the longest a rest can go on, whatever the interrupt settings
+const REST_TURN_CAP: u32 = 100;
+// health percentages that can stop a rest; 0 is off
+const REST_HP_CHOICES: [i32; 4] = [0, 25, 50, 75];
+
+const HEAL_AMOUNT: i32 = 40;
+// how far away a healing potion can reach an ally
+const HEAL_RANGE: i32 = 8;
+// what a bite of emergency ration restores
+const RATION_HEAL_AMOUNT: i32 = 15;
+
+const POISON_POTION_DAMAGE: i32 = 10;
+// what a hit with a poisoned weapon does every turn, and for how many turns
+const WEAPON_POISON_DAMAGE: i32 = 2;
+const WEAPON_POISON_TURNS: i32 = 5;
+
+// what unidentified potions look like; shuffled for every new game
+const POTION_APPEARANCES: &[&str] = &["murky", "fizzy", "bubbling", "cloudy", "glowing", "oily"];
+// and the labels on unidentified scrolls; there have to be at least as many as
+// there are kinds of scroll
+const SCROLL_LABELS: &[&str] = &[
+    "ARKU VEN",
+    "MOLDRA TESH",
+    "KIV ZANTHOR",
+    "OLLO PRAM",
+    "SEFRU DAK",
+    "NUMA VEX",
+    "TARRAK OSH",
+    "BELLIM RO",
+    "QUEZZ AMAR",
+    "HOLVEN DRU",
+];
+// the most kinds an unidentified item's name will guess it might be
+const MAX_GUESSES: usize = 3;
+
+// where what the player learns carries over from game to game. tests that
+// identify things keep theirs away from the player's
+#[cfg(not(test))]
+const LORE_FILE: &str = "lore.json";
+#[cfg(test)]
+const LORE_FILE: &str = "target/test_lore.json";
+
+// the save written on exit, and the one kept by the quick-save key
+const SAVE_FILE: &str = "savegame";
+const QUICKSAVE_FILE: &str = "quicksave";
+// how many of the latest key presses and messages go into a bug report
+const BUG_REPORT_INPUTS: usize = 50;
+const BUG_REPORT_MESSAGES: usize = 50;
+
+// how many turns a monster keeps after the player once they're out of sight
+const PURSUIT_TURNS: i32 = 5;
+// how far an archer can shoot
+const ARCHER_RANGE: i32 = 6;
+// how many turns the player's scent lingers on a tile
+const SCENT_DURATION: u32 = 20;
+// how close others of its kind have to be to keep up a monster's morale
+const MORALE_RADIUS: f32 = 8.0;
+// a cowardly monster runs once its HP drops under this share of its max, in percent
+const COWARD_HP_PERCENT: i32 = 25;
+// a slime only splits if both halves get at least this much max HP, and
+// never while there are this many slimes on the level already
+const SLIME_MIN_HP: i32 = 6;
+const SLIME_CAP: usize = 16;
+// how far a shaman's blessing reaches and how long it lasts, how much
+// stronger it makes a monster, and how close a shaman lets the player get
+const SHAMAN_RANGE: f32 = 6.0;
+const SHAMAN_BUFF_TURNS: u32 = 8;
+const SHAMAN_POWER_BONUS: i32 = 3;
+const SHAMAN_SAFE_DISTANCE: f32 = 4.0;
+
+// the chance, in percent, that an item from this level on is really a mimic
+const MIMIC_MIN_LEVEL: u32 = 3;
+const MIMIC_CHANCE: u32 = 8;
+
+// percent chance that a room has a chest or barrel in it, how much it can
+// hold, and the chance a chest is locked from the level locks turn up on
+const CONTAINER_CHANCE: u32 = 15;
+const CONTAINER_MAX_ITEMS: u32 = 3;
+const LOCKED_MIN_LEVEL: u32 = 2;
+const LOCKED_CHANCE: u32 = 30;
+
+const LIGHTNING_DAMAGE: i32 = 40;
+const LIGHTNING_RANGE: i32 = 5;
+
+const CONFUSE_RANGE: i32 = 8;
+const CONFUSE_NUM_TURNS: u32 = 10;
+
+const ENTANGLE_RANGE: i32 = 8;
+const ENTANGLE_NUM_TURNS: u32 = 5;
+
+const STONE_RANGE: i32 = 8;
+
+const PROTECTION_POINTS: i32 = 20;
+const PROTECTION_NUM_TURNS: i32 = 30;
+
+// how far a phase door can take the player, and how much further it
+// reaches when there's nowhere to land that close
+const PHASE_RADIUS: i32 = 5;
+const PHASE_FALLBACK_RADIUS: i32 = 8;
+
+const FIREBALL_RADIUS: i32 = 3;
+const FIREBALL_DAMAGE: i32 = 25;
+
+// caltrops and oil are thrown this far, and spread over the tiles around
+// where they land
+const HAZARD_RANGE: i32 = 5;
+const CALTROPS_NUM_TURNS: i32 = 40;
+const CALTROPS_DAMAGE: i32 = 3;
+const OIL_NUM_TURNS: i32 = 30;
+// percent chance to skid another tile when stepping onto oil
+const OIL_SLIP_CHANCE: i32 = 33;
+// what burning oil does to anything standing in it
+const OIL_FIRE_DAMAGE: i32 = 10;
+
+// how many times a freshly found wand can be zapped, which is also as many
+// charges as a wand holds
+const WAND_CHARGES: i32 = 3;
+// charges a scroll of recharging puts back
+const RECHARGE_AMOUNT: i32 = 2;
+
+// what each class starts out carrying; equipment is put on right away,
+// unless something earlier in the list already took its slot
+const WARRIOR_LOADOUT: &[Item] = &[Item::Sword, Item::Shield, Item::Heal];
+const ROGUE_LOADOUT: &[Item] = &[Item::Dagger, Item::Heal, Item::Heal, Item::Confuse];
+const MAGE_LOADOUT: &[Item] = &[Item::Dagger, Item::Heal, Item::Lightning, Item::Confuse];
+
+// class perks
+const ROGUE_CRITICAL_CHANCE: f32 = 0.15;
+const MAGE_SPELL_BONUS: i32 = 50; // percent
+
+// parameters for dungeon generator
+const ROOM_MAX_SIZE: i32 = 10;
+const ROOM_MIN_SIZE: i32 = 6;
+const MAX_ROOMS: i32 = 30;
+// size ranges for randomly placed rooms, with their odds: mostly small
+// chambers, now and then a great hall
+const ROOM_SIZES: &[(i32, i32, u32)] =
+    &[(ROOM_MIN_SIZE, 7, 60), (8, ROOM_MAX_SIZE, 30), (11, 16, 10)];
+// percent chances of a room being round or cross-shaped instead of square
+const CIRCLE_ROOM_CHANCE: i32 = 20;
+const CROSS_ROOM_CHANCE: i32 = 20;
+// percent chance of a corridor cutting diagonally between two rooms
+// instead of turning a corner
+const DIAGONAL_TUNNEL_CHANCE: i32 = 25;
+// from this level on, the percent chance of a level being a cave instead of rooms
+const CAVE_MIN_LEVEL: u32 = 3;
+const CAVE_CHANCE: i32 = 25;
+// a cave starts out as this percentage of wall, and gets smoothed over so
+// many times. pockets smaller than the minimum are filled back in
+const CAVE_WALL_CHANCE: i32 = 45;
+const CAVE_SMOOTHING_PASSES: i32 = 4;
+const CAVE_MIN_REGION: usize = 12;
+// caves are stocked in squares this big, about as many as a level has rooms
+const CAVE_AREA_SIZE: i32 = 16;
+// BSP regions are split until they are at most this big,
+// and never split into pieces smaller than a room needs
+const BSP_MAX_LEAF: i32 = 20;
+const BSP_MIN_LEAF: i32 = ROOM_MIN_SIZE + 2;
+
+// every level gets at least this many monsters and useful items
+const MIN_MONSTERS_PER_LEVEL: i32 = 2;
+const MIN_ITEMS_PER_LEVEL: i32 = 1;
+// attempts at finding a free spot for one of them before giving up
+const FREE_SPOT_TRIES: i32 = 100;
+
+// wandering monsters turn up every so many turns, more often deeper down,
+// up to a cap for each stay on a level
+const WANDER_BASE_TURNS: u32 = 120;
+const WANDER_TURNS_PER_LEVEL: u32 = 10;
+const WANDER_MIN_TURNS: u32 = 40;
+const WANDERER_CAP: u32 = 5;
+// how long a wanderer keeps heading for where the player was when it came
+const WANDER_PURSUIT_TURNS: i32 = 30;
+
+// the level of the main dungeon with the stairs down into the crypt
+const CRYPT_ENTRANCE_LEVEL: u32 = 3;
+// the town sits above the first level, and its stash holds this many items
+const TOWN_LEVEL: u32 = 0;
+const STASH_CAP: usize = 26;
+
+// percent chance that a level gets a vault, and how hard to look for a spot
+const VAULT_CHANCE: i32 = 30;
+const VAULT_PLACEMENT_TRIES: i32 = 50;
+
+// hand-designed rooms: '#' wall, '.' floor, 'D' entrance, 'M' monster, 'I' item
+const VAULTS: &[&[&str]] = &[
+    &[
+        "###########",
+        "#M...#...I#",
+        "#.##...##.#",
+        "#....M....#",
+        "#####D#####",
+    ],
+    &[
+        "#########",
+        "#I.#.#.I#",
+        "#.......#",
+        "##.#M#.##",
+        "#I.....I#",
+        "####D####",
+    ],
+];
+
+// glyph for a tile holding more than one item
+const PILE_CHAR: char = '&';
+
+const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
+const COLOR_LIGHT_WALL: Color = Color { r: 130, g: 110, b: 50 };
+const COLOR_DARK_GROUND: Color = Color { r: 50, g: 50, b: 150 };
+const COLOR_LIGHT_GROUND: Color = Color { r: 200, g: 180, b: 50 };
+
+const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic; // default FOV algorithm
+const FOV_LIGHT_WALLS: bool = true; // light walls or not
+const TORCH_RADIUS: i32 = 10;
+
+pub const LIMIT_FPS: i32 = 20; // 20 frames-per-second maximum
+// the FPS setting is always kept within these bounds
+const MIN_FPS: i32 = 5;
+const MAX_FPS: i32 = 120;
+const FPS_CHOICES: [i32; 4] = [10, 20, 30, 60];
+
+// speed of real-time effects, in percent of normal
+const ANIMATION_SPEED: i32 = 100;
+const ANIMATION_SPEED_CHOICES: [i32; 3] = [50, 100, 200];
+
+// length of each on/off phase of the low HP flash at normal speed
+const HP_FLASH_MILLIS: u128 = 250;
+// how long newly explored tiles take to fade in
+const REVEAL_MILLIS: u128 = 300;
+// how long the map takes to fade to red when the player dies
+const DEATH_FADE_MILLIS: u128 = 1200;
@@ -1,78 +1,6 @@
-use tcod::colors::*;
-use tcod::console::*;
-use rand::Rng;
-use tcod::map::{FovAlgorithm, Map as FovMap};
-use tcod::input::{self, Event, Key, Mouse};
-use std::error::Error;
-use std::fs::File;
-use std::io::{Read, Write};
+use tcod::console::{FontLayout, FontType, Root};
 
-mod object;
-use crate::object::*;
-use crate::object_types::*;
-
-mod render;
-use crate::render::*;
-
-mod game;
-use crate::game::*;
-
-// actual size of the window
-const SCREEN_WIDTH: i32 = 80;
-const SCREEN_HEIGHT: i32 = 50;
-
-// size of the map
-const MAP_WIDTH: i32 = 80;
-const MAP_HEIGHT: i32 = 43;
-
-// sizes and coords relevant to the GUI
-const BAR_WIDTH: i32 = 20;
-const PANEL_HEIGHT: i32 = 7;
-const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
-
-const MSG_X: i32 = BAR_WIDTH + 2;
-const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
-const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
-
-const INVENTORY_WIDTH: i32 = 50;
-
-// player will always be the first object
-const PLAYER: usize = 0;
-
-// experience and level-ups
-const LEVEL_UP_BASE: i32 = 200;
-const LEVEL_UP_FACTOR: i32 = 150;
-
-const CHARACTER_SCREEN_WIDTH: i32 = 30;
-
-const LEVEL_SCREEN_WIDTH: i32 = 40;
-
-const HEAL_AMOUNT: i32 = 40;
-
-const LIGHTNING_DAMAGE: i32 = 40;
-const LIGHTNING_RANGE: i32 = 5;
-
-const CONFUSE_RANGE: i32 = 8;
-const CONFUSE_NUM_TURNS: i32 = 10;
-
-const FIREBALL_RADIUS: i32 = 3;
-const FIREBALL_DAMAGE: i32 = 25;
-
-// parameters for dungeon generator
-const ROOM_MAX_SIZE: i32 = 10;
-const ROOM_MIN_SIZE: i32 = 6;
-const MAX_ROOMS: i32 = 30;
-
-const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
-const COLOR_LIGHT_WALL: Color = Color { r: 130, g: 110, b: 50 };
-const COLOR_DARK_GROUND: Color = Color { r: 50, g: 50, b: 150 };
-const COLOR_LIGHT_GROUND: Color = Color { r: 200, g: 180, b: 50 };
-
-const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic; // default FOV algorithm
-const FOV_LIGHT_WALLS: bool = true; // light walls or not
-const TORCH_RADIUS: i32 = 10;
-
-const LIMIT_FPS: i32 = 20; // 20 frames-per-second maximum
+use roguelike::*;
 
 fn main() {
     tcod::system::set_fps(LIMIT_FPS);
@@ -84,14 +12,8 @@ fn main() {
         .title("Rust-like Dungeons")
         .init();
 
-    let mut tcod = Tcod {
-        root,
-        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
-        panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
-        fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
-        key: Default::default(),
-        mouse: Default::default(),
-    };
+    let mut tcod = Tcod::new(Box::new(TcodRenderer::new(root)));
 
-    main_menu(&mut tcod);
+    let options = LaunchOptions::from_args(std::env::args().skip(1));
+    main_menu(&mut tcod, options);
 }
@@ -1,11 +1,28 @@
-use tcod::colors::*;
-use tcod::console::*;
+use std::cmp;
+
+use crate::backend::colors::*;
 
 use serde::{Serialize, Deserialize};
 
-pub(crate) mod object_types;
+pub mod object_types;
 use crate::object_types::*;
-use crate::{Game, Messages};
+use crate::renderer::{Layer, Renderer};
+use crate::{
+    Game, Messages, PlayerClass, Severity, CARRY_CAPACITY_BASE, CARRY_CAPACITY_PER_POWER,
+    ENCUMBRANCE_STEP, ROGUE_CRITICAL_CHANCE,
+};
+
+/// What shows on top when several objects share a tile, lowest first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RenderPriority {
+    /// corpses and anything else just lying around
+    Scenery,
+    Item,
+    Stairs,
+    /// monsters, chests, and whatever else is in the way
+    Blocking,
+    Player,
+}
 
 /// This is a generic object: the player, a monster, an item, the stairs...
 /// It's always represented by a character on screen.
@@ -23,7 +40,32 @@ pub struct Object {
     pub item: Option<Item>,
     pub always_visible: bool,
     pub level: i32,
-    pub equipment: Option<Equipment>
+    pub equipment: Option<Equipment>,
+    /// fights on the player's side
+    pub ally: bool,
+    /// one of a kind, like a boss, so no scroll can wipe it out
+    pub unique: bool,
+    pub speed: Speed,
+    /// uses left, for items that aren't used up all at once
+    pub charges: Option<i32>,
+    /// for monsters that run when their pack thins out
+    pub morale: Option<Morale>,
+    /// runs from the player once badly hurt
+    pub cowardly: bool,
+    /// divides in two when wounded, while it's big enough
+    pub splits: bool,
+    /// got hurt since splitting was last checked
+    pub split_pending: bool,
+    /// what it really is, for a monster lying in wait as something else
+    pub disguise: Option<Disguise>,
+    /// the items inside, for a chest or barrel that hasn't been opened yet
+    pub contents: Option<Vec<Object>>,
+    /// needs a key to open
+    pub locked: bool,
+    /// a temporary boost, like a shaman's blessing
+    pub buff: Option<Buff>,
+    /// what's wearing on it turn by turn, like poison
+    pub statuses: Vec<Status>,
 }
 
 impl Object {
@@ -42,13 +84,59 @@ impl Object {
             always_visible: false,
             level: 1,
             equipment: None,
+            ally: false,
+            unique: false,
+            speed: Speed::Normal,
+            charges: None,
+            morale: None,
+            cowardly: false,
+            splits: false,
+            split_pending: false,
+            disguise: None,
+            contents: None,
+            locked: false,
+            buff: None,
+            statuses: vec![],
         }
     }
 
-    /// set the color and then draw the character that represents this object at its position
-    pub fn draw(&self, con: &mut dyn Console) {
-        con.set_default_foreground(self.color);
-        con.put_char(self.x, self.y, self.char, BackgroundFlag::None);
+    /// draw the character that represents this object at its position, in its color
+    pub fn draw(&self, renderer: &mut dyn Renderer) {
+        renderer.put_char(Layer::Map, self.x, self.y, self.char, self.color);
+    }
+
+    /// drop the disguise, if there is one, and become the monster underneath.
+    /// Returns whether there was anything to reveal
+    pub fn reveal(&mut self) -> bool {
+        match self.disguise.take() {
+            Some(disguise) => {
+                self.char = disguise.char;
+                self.color = disguise.color;
+                self.name = disguise.name;
+                self.fighter = Some(disguise.fighter);
+                self.ai = Some(disguise.ai);
+                self.item = None;
+                self.charges = None;
+                self.blocks = true;
+                self.alive = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn render_priority(&self) -> RenderPriority {
+        if self.fighter.map(|f| f.on_death) == Some(DeathCallback::Player) {
+            RenderPriority::Player
+        } else if self.blocks {
+            RenderPriority::Blocking
+        } else if self.name.ends_with("stairs") {
+            RenderPriority::Stairs
+        } else if self.item.is_some() {
+            RenderPriority::Item
+        } else {
+            RenderPriority::Scenery
+        }
     }
 
     pub fn pos(&self) -> (i32, i32) {
@@ -72,11 +160,40 @@ impl Object {
         (((x - self.x).pow(2) + (y - self.y).pow(2)) as f32).sqrt()
     }
 
+    /// hurt the object, returning the xp it was worth if this killed it. Only
+    /// the blow that kills pays out, so a monster caught by several effects
+    /// at once is never counted twice
     pub fn take_damage(&mut self, damage: i32, game: &mut Game) -> Option<i32> {
+        if !self.alive {
+            // already dead, whatever is left of it
+            return None;
+        }
         // apply damage if possible
         if let Some(fighter) = self.fighter.as_mut() {
+            if fighter.on_death == DeathCallback::Player && game.invulnerable {
+                // debug invulnerability
+                return None;
+            }
+            let damage = if fighter.on_death == DeathCallback::Player {
+                // a magical shield takes the hit first; whatever it can't
+                // hold goes through to the HP
+                let was_up = game.shield.is_up();
+                let left_over = game.shield.absorb(damage);
+                if was_up && !game.shield.is_up() {
+                    game.messages.add("Your magical shield shatters!", Severity::Warning);
+                }
+                left_over
+            } else {
+                damage
+            };
             if damage > 0 {
                 fighter.hp -= damage;
+                // keep score of the damage going each way
+                if fighter.on_death == DeathCallback::Player {
+                    game.stats.damage_taken += damage;
+                } else if !self.ally {
+                    game.stats.damage_dealt += damage;
+                }
             }
         }
         // check for death, call the death function
@@ -87,12 +204,15 @@ impl Object {
                 return Some(fighter.xp);
             }
         }
+        if self.splits && damage > 0 {
+            // it lived, so it may divide; see `split_slimes`
+            self.split_pending = true;
+        }
         None
     }
 
     pub fn attack(&mut self, target: &mut Object, game: &mut Game) {
-        // a simple formula for attack damage
-        let damage = self.power(game) - target.defense(game);
+        let damage = self.damage_against(target, game);
         if damage > 0 {
             // make the target take some damage
             game.messages.add(
@@ -100,23 +220,73 @@ impl Object {
                     "{} attacks {} for {} hit damage",
                     self.name, target.name, damage
                 ),
-                WHITE,
+                Severity::Info,
             );
-            if let Some(xp) = target.take_damage(damage, game) {
-                // yield xp to the player
-                self.fighter.as_mut().unwrap().xp += xp;
-            }
+            self.deal_damage(target, damage, game);
+            self.inflict_on_hit(target, game);
         } else {
             game.messages.add(
                 format!(
                     "{} attacks {} but it has no effect!",
                     self.name, target.name
                 ),
-                WHITE,
+                Severity::Info,
             );
         }
     }
 
+    /// roll how much damage a blow against the target does
+    pub fn damage_against(&self, target: &Object, game: &mut Game) -> i32 {
+        // a simple formula for attack damage
+        let mut damage = self.power(game) - target.defense(game);
+        // rogues now and then find a weak spot
+        let is_player = self.fighter.map(|f| f.on_death) == Some(DeathCallback::Player);
+        if is_player
+            && game.class == PlayerClass::Rogue
+            && damage > 0
+            && rand::random::<f32>() < ROGUE_CRITICAL_CHANCE
+        {
+            damage *= 2;
+            game.messages.add("A critical hit!", Severity::Good);
+        }
+        damage
+    }
+
+    /// hurt the target without reporting the blow, collecting xp if it dies
+    pub fn deal_damage(&mut self, target: &mut Object, damage: i32, game: &mut Game) {
+        if damage > 0 {
+            if let Some(xp) = target.take_damage(damage, game) {
+                // yield xp to the player
+                self.grant_xp(xp);
+            }
+        }
+    }
+
+    /// leave whatever the equipped weapons do on a hit on the target, if it
+    /// lived through the blow. a status it already has starts over
+    pub fn inflict_on_hit(&self, target: &mut Object, game: &mut Game) {
+        if !target.alive {
+            return;
+        }
+        for status in self.get_all_equipped(game).iter().filter_map(|e| e.on_hit) {
+            target.statuses.retain(|old| !old.same_kind(&status));
+            target.statuses.push(status);
+            match status {
+                Status::Poison { .. } => game.messages.add(
+                    format!("The {} is poisoned!", target.name),
+                    Severity::Good,
+                ),
+            }
+        }
+    }
+
+    /// credit the xp a kill was worth; everything that kills goes through here
+    pub fn grant_xp(&mut self, xp: i32) {
+        if let Some(fighter) = self.fighter.as_mut() {
+            fighter.xp += xp;
+        }
+    }
+
     /// heal by the given amount, without going over the max
     pub fn heal(&mut self, amount: i32, game: &Game) {
         let max_hp = self.max_hp(game);
@@ -133,7 +303,7 @@ impl Object {
         if self.item.is_none() {
             messages.add(
                 format!("Cant equip {:?} because it's not an Item.", self),
-                RED,
+                Severity::Danger,
             );
             return;
         };
@@ -142,13 +312,13 @@ impl Object {
                 equipment.equipped = true;
                 messages.add(
                     format!("Equipped {} on {}.", self.name, equipment.slot),
-                    LIGHT_GREEN,
+                    Severity::Good,
                 );
             }
         } else {
             messages.add(
                 format!("Can't equip {:?} because it's not an Equipment.", self),
-                RED,
+                Severity::Danger,
             );
         }
     }
@@ -158,7 +328,7 @@ impl Object {
         if self.item.is_none() {
             messages.add(
                 format!("Cant dequip {:?} because it's not an Item.", self),
-                RED,
+                Severity::Danger,
             );
             return;
         };
@@ -167,13 +337,13 @@ impl Object {
                 equipment.equipped = false;
                 messages.add(
                     format!("Dequipped {} on {}.", self.name, equipment.slot),
-                    LIGHT_YELLOW,
+                    Severity::Warning,
                 );
             }
         } else {
             messages.add(
                 format!("Can't dequip {:?} because it's not an Equipment.", self),
-                RED,
+                Severity::Danger,
             );
         }
     }
@@ -185,7 +355,8 @@ impl Object {
             .iter()
             .map(|e| e.power_bonus)
             .sum();
-        base_power + bonus
+        let buff = self.buff.map_or(0, |buff| buff.power);
+        base_power + bonus + buff
     }
 
     pub fn defense(&self, game: &Game) -> i32 {
@@ -195,7 +366,27 @@ impl Object {
             .iter()
             .map(|e| e.defense_bonus)
             .sum();
-        base_defense + bonus
+        // a heavy pack can wear defense down to nothing, but not below
+        let total = base_defense + bonus;
+        total - self.encumbrance(game).min(total.max(0))
+    }
+
+    /// how much this object can carry before it gets weighed down
+    pub fn carrying_capacity(&self) -> i32 {
+        CARRY_CAPACITY_BASE + CARRY_CAPACITY_PER_POWER * self.fighter.map_or(0, |f| f.base_power)
+    }
+
+    /// how much defense carrying too much costs; only the player has a pack
+    pub fn encumbrance(&self, game: &Game) -> i32 {
+        if self.name != "player" || !game.settings.encumbrance {
+            return 0;
+        }
+        let over = carried_weight(&game.inventory) - self.carrying_capacity();
+        if over > 0 {
+            (over - 1) / ENCUMBRANCE_STEP + 1
+        } else {
+            0
+        }
     }
 
     pub fn max_hp(&self, game: &Game) -> i32 {
@@ -208,6 +399,14 @@ impl Object {
         base_max_hp + bonus
     }
 
+    /// how far away this object can hit: one tile, unless it wields a reach weapon
+    pub fn reach(&self, game: &Game) -> i32 {
+        self.get_all_equipped(game)
+            .iter()
+            .map(|e| e.reach)
+            .fold(1, cmp::max)
+    }
+
     /// returns a list of equipped items
     pub fn get_all_equipped(&self, game: &Game) -> Vec<Equipment> {
         if self.name == "player" {
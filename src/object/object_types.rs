@@ -1,4 +1,7 @@
-use serde::{Serialize, Deserialize};
+use std::fmt::Debug;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use crate::*;
 use crate::object::Object;
 use rand::Rng;
@@ -14,73 +17,814 @@ pub struct Fighter {
     pub on_death: DeathCallback,
 }
 
+/// How much a monster that fights in packs relies on its pack. Monsters
+/// without it never run, which suits loners like trolls
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Morale {
+    /// the share of its pack, in percent, it needs around it to keep fighting
+    pub breaking_point: i32,
+    /// the most others of its kind it has fought beside
+    pub pack: i32,
+}
+
+/// What a monster passing itself off as something else really is, kept
+/// aside until it gives itself away
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Disguise {
+    pub char: char,
+    pub color: Color,
+    pub name: String,
+    pub fighter: Fighter,
+    pub ai: Ai,
+}
+
+/// Extra power for a while, lasting up to and including the game turn
+/// `last_turn`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Buff {
+    pub power: i32,
+    pub last_turn: u32,
+}
+
+/// Something wearing on a creature turn by turn, until it runs its course
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Status {
+    Poison {
+        damage_per_turn: i32,
+        turns_left: i32,
+    },
+}
+
+impl Status {
+    /// whether the two are the same kind of status, whatever their numbers
+    pub fn same_kind(&self, other: &Status) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+/// Let every status take its toll for one turn, and drop the ones that ran
+/// out. Poison can kill, and a monster killed by the player's poison is the
+/// player's kill
+pub fn apply_statuses(objects: &mut [Object], game: &mut Game) {
+    for id in 0..objects.len() {
+        if !objects[id].alive {
+            objects[id].statuses.clear();
+            continue;
+        }
+        let statuses = std::mem::take(&mut objects[id].statuses);
+        let mut lasting = vec![];
+        for status in statuses {
+            match status {
+                Status::Poison { damage_per_turn, turns_left } => {
+                    if id == PLAYER {
+                        game.messages.add("The poison burns in your veins.", Severity::Danger);
+                    }
+                    if let Some(xp) = objects[id].take_damage(damage_per_turn, game) {
+                        if id != PLAYER && !objects[id].ally {
+                            objects[PLAYER].grant_xp(xp);
+                        }
+                    }
+                    if turns_left > 1 {
+                        let turns_left = turns_left - 1;
+                        lasting.push(Status::Poison { damage_per_turn, turns_left });
+                    } else if id == PLAYER && objects[id].alive {
+                        game.messages.add("The poison wears off.", Severity::Good);
+                    }
+                }
+            }
+        }
+        if objects[id].alive {
+            objects[id].statuses = lasting;
+        }
+    }
+}
+
+/// How quickly a monster moves compared to the player
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Speed {
+    /// acts every other turn
+    Slow,
+    Normal,
+    /// acts twice a turn
+    Fast,
+}
+
+impl Speed {
+    /// how many times to act on the given turn
+    pub fn actions(self, turn: u32) -> u32 {
+        match self {
+            Speed::Slow => turn % 2,
+            Speed::Normal => 1,
+            Speed::Fast => 2,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Ai {
     Basic,
+    /// lost sight of the player, and heads for where it last saw them. Once
+    /// it finds them, or gives up, it goes back to `then`
+    Hunting {
+        last_seen: (i32, i32),
+        num_turns: i32,
+        #[serde(default = "basic_ai")]
+        then: Box<Ai>,
+    },
+    /// follows the player's scent, even where it can't see them
+    Tracker,
+    /// lost its nerve, and keeps away from the player until it gets it back
+    Fleeing,
+    Pet,
+    /// stumbles around at random up to and including the game turn
+    /// `last_turn`, however often it gets to act until then
     Confused {
         previous_ai: Box<Ai>,
-        num_turns: i32,
+        last_turn: u32,
+    },
+    /// held in place: can still hit whatever is next to it, but can't move.
+    /// Lasts up to and including the game turn `last_turn`
+    Entangled {
+        previous_ai: Box<Ai>,
+        last_turn: u32,
+    },
+    /// shoots at the player from up to `range` tiles away, and only walks
+    /// up to them when they're further off or out of a clear shot
+    Ranged {
+        range: i32,
     },
+    /// a behavior that lives outside this enum; see `MonsterBrain`
+    Custom(Brain),
 }
 
-pub fn ai_take_turn(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
-    use Ai::*;
-    if let Some(ai) = objects[monster_id].ai.take() {
-        let new_ai = match ai {
-            Basic => ai_basic(monster_id, tcod, game, objects),
-            Confused {
-                previous_ai,
-                num_turns,
-            } => ai_confused(monster_id, tcod, game, objects, previous_ai, num_turns),
+/// what a hunt saved before hunts remembered anything falls back to
+fn basic_ai() -> Box<Ai> {
+    Box::new(Ai::Basic)
+}
+
+/// A monster behavior as a type of its own, for anything that doesn't need
+/// to be one of the built-in `Ai`s. Add it to `BRAINS` so saved games can
+/// bring it back.
+pub trait MonsterBrain: Debug + Send {
+    /// what it's saved as; has to match its entry in `BRAINS`
+    fn kind(&self) -> &'static str;
+    /// act for one turn. Returning an `Ai` hands the monster over to it
+    fn take_turn(
+        &mut self,
+        monster_id: usize,
+        fov: &dyn Visibility,
+        game: &mut Game,
+        objects: &mut [Object],
+    ) -> Option<Ai>;
+    /// take in what it can see on a turn it doesn't get to act. Returning an
+    /// `Ai` hands the monster over to it, just like `take_turn`
+    fn notice(
+        &mut self,
+        _monster_id: usize,
+        _fov: &dyn Visibility,
+        _objects: &[Object],
+    ) -> Option<Ai> {
+        None
+    }
+    /// everything it needs to remember, for the save file
+    fn save(&self) -> serde_json::Value;
+    fn clone_brain(&self) -> Box<dyn MonsterBrain>;
+}
+
+/// brings a `MonsterBrain` back from what it saved, if it makes sense
+pub type LoadBrain = fn(serde_json::Value) -> Option<Box<dyn MonsterBrain>>;
+
+/// Every kind of `MonsterBrain`, with how to load one back
+pub const BRAINS: &[(&str, LoadBrain)] = &[("guard", load_guard), ("shaman", load_shaman)];
+
+/// A boxed `MonsterBrain`, saved under its kind so it can be loaded back
+#[derive(Debug)]
+pub struct Brain(pub Box<dyn MonsterBrain>);
+
+impl Clone for Brain {
+    fn clone(&self) -> Self {
+        Brain(self.0.clone_brain())
+    }
+}
+
+impl PartialEq for Brain {
+    fn eq(&self, other: &Brain) -> bool {
+        self.0.kind() == other.0.kind() && self.0.save() == other.0.save()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedBrain {
+    kind: String,
+    state: serde_json::Value,
+}
+
+impl Serialize for Brain {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let saved = SavedBrain {
+            kind: self.0.kind().to_string(),
+            state: self.0.save(),
+        };
+        saved.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Brain {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let SavedBrain { kind, state } = SavedBrain::deserialize(deserializer)?;
+        BRAINS
+            .iter()
+            .find(|(known, _)| *known == kind)
+            .and_then(|(_, load)| load(state))
+            .map(Brain)
+            .ok_or_else(|| D::Error::custom(format!("unknown monster brain {}", kind)))
+    }
+}
+
+/// Keeps a monster at its post until it sees the player, then lets its own
+/// AI take over
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Guard {
+    pub post: (i32, i32),
+    pub then: Ai,
+}
+
+impl MonsterBrain for Guard {
+    fn kind(&self) -> &'static str {
+        "guard"
+    }
+
+    fn take_turn(
+        &mut self,
+        monster_id: usize,
+        fov: &dyn Visibility,
+        game: &mut Game,
+        objects: &mut [Object],
+    ) -> Option<Ai> {
+        let (monster_x, monster_y) = objects[monster_id].pos();
+        if fov.is_in_fov(monster_x, monster_y) {
+            return Some(self.then.clone());
+        }
+        if (monster_x, monster_y) != self.post {
+            // knocked or lured away, so head back
+            move_towards(monster_id, self.post.0, self.post.1, game, objects);
+        }
+        None
+    }
+
+    fn notice(
+        &mut self,
+        monster_id: usize,
+        fov: &dyn Visibility,
+        objects: &[Object],
+    ) -> Option<Ai> {
+        let (monster_x, monster_y) = objects[monster_id].pos();
+        Some(self.then.clone()).filter(|_| fov.is_in_fov(monster_x, monster_y))
+    }
+
+    fn save(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    fn clone_brain(&self) -> Box<dyn MonsterBrain> {
+        Box::new(self.clone())
+    }
+}
+
+fn load_guard(state: serde_json::Value) -> Option<Box<dyn MonsterBrain>> {
+    let guard: Guard = serde_json::from_value(state).ok()?;
+    Some(Box::new(guard))
+}
+
+/// Never fights if it can help it: blesses the monsters around it with
+/// extra power instead, and keeps its distance from the player
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Shaman;
+
+impl MonsterBrain for Shaman {
+    fn kind(&self) -> &'static str {
+        "shaman"
+    }
+
+    fn take_turn(
+        &mut self,
+        monster_id: usize,
+        fov: &dyn Visibility,
+        game: &mut Game,
+        objects: &mut [Object],
+    ) -> Option<Ai> {
+        let (monster_x, monster_y) = objects[monster_id].pos();
+        if !fov.is_in_fov(monster_x, monster_y) {
+            return None;
+        }
+
+        // too close for comfort comes first
+        if objects[monster_id].distance_to(&objects[PLAYER]) < SHAMAN_SAFE_DISTANCE
+            && step_away_from_player(monster_id, game, objects)
+        {
+            return None;
+        }
+
+        // bless the nearest fighter around it that isn't blessed already
+        let shaman = &objects[monster_id];
+        let target = (0..objects.len())
+            .filter(|&id| {
+                let other = &objects[id];
+                id != PLAYER
+                    && id != monster_id
+                    && other.alive
+                    && other.fighter.is_some()
+                    && !other.ally
+                    && other.buff.is_none()
+                    && shaman.distance_to(other) <= SHAMAN_RANGE
+            })
+            .min_by(|&a, &b| {
+                let (a, b) = (shaman.distance_to(&objects[a]), shaman.distance_to(&objects[b]));
+                a.partial_cmp(&b).unwrap_or(cmp::Ordering::Equal)
+            });
+        if let Some(target) = target {
+            objects[target].buff = Some(Buff {
+                power: SHAMAN_POWER_BONUS,
+                last_turn: game.stats.turns + SHAMAN_BUFF_TURNS,
+            });
+            game.messages.add(
+                format!(
+                    "The {} chants, and the {} grows fierce!",
+                    objects[monster_id].name, objects[target].name
+                ),
+                Severity::Warning,
+            );
+        }
+        None
+    }
+
+    fn save(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    fn clone_brain(&self) -> Box<dyn MonsterBrain> {
+        Box::new(self.clone())
+    }
+}
+
+fn load_shaman(state: serde_json::Value) -> Option<Box<dyn MonsterBrain>> {
+    let shaman: Shaman = serde_json::from_value(state).ok()?;
+    Some(Box::new(shaman))
+}
+
+/// End the blessings that ran out, and those with no living shaman left
+/// nearby to keep them up
+pub fn tick_buffs(fov: &dyn Visibility, game: &mut Game, objects: &mut [Object]) {
+    let shamans: Vec<(i32, i32)> = objects
+        .iter()
+        .filter(|object| object.alive && object.name == "shaman")
+        .map(Object::pos)
+        .collect();
+    for object in objects.iter_mut() {
+        let buff = match object.buff {
+            Some(buff) => buff,
+            None => continue,
         };
+        let upheld = shamans.iter().any(|&(x, y)| object.distance(x, y) <= SHAMAN_RANGE);
+        if object.alive && upheld && game.stats.turns <= buff.last_turn {
+            continue;
+        }
+        object.buff = None;
+        if object.alive && fov.is_in_fov(object.x, object.y) {
+            game.messages.add(format!("The {} looks less fierce.", object.name), Severity::Good);
+        }
+    }
+}
+
+/// Give a monster its turn: fast monsters act twice, slow ones only every
+/// other turn. Between a slow monster's moves it still notices the player
+pub fn monster_turn(
+    monster_id: usize,
+    fov: &dyn Visibility,
+    game: &mut Game,
+    objects: &mut [Object],
+) {
+    let actions = objects[monster_id].speed.actions(game.stats.turns);
+    if actions == 0 {
+        ai_perceive(monster_id, fov, objects);
+    }
+    for _ in 0..actions {
+        ai_take_turn(monster_id, fov, game, objects);
+    }
+}
+
+/// What a monster takes in without acting: one in sight of the player wakes
+/// up and keeps track of where they are, though it can't move or attack yet
+fn ai_perceive(monster_id: usize, fov: &dyn Visibility, objects: &mut [Object]) {
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    if !fov.is_in_fov(monster_x, monster_y) {
+        return;
+    }
+    let last_seen = objects[PLAYER].pos();
+    let ai = match objects[monster_id].ai.take() {
+        Some(Ai::Basic) => Some(Ai::Hunting {
+            last_seen,
+            num_turns: PURSUIT_TURNS,
+            then: basic_ai(),
+        }),
+        Some(Ai::Hunting { then, .. }) => Some(Ai::Hunting {
+            last_seen,
+            num_turns: PURSUIT_TURNS,
+            then,
+        }),
+        Some(Ai::Custom(mut brain)) => Some(
+            brain
+                .0
+                .notice(monster_id, fov, objects)
+                .unwrap_or(Ai::Custom(brain)),
+        ),
+        ai => ai,
+    };
+    objects[monster_id].ai = ai;
+}
+
+pub fn ai_take_turn(
+    monster_id: usize,
+    fov: &dyn Visibility,
+    game: &mut Game,
+    objects: &mut [Object],
+) {
+    if let Some(ai) = objects[monster_id].ai.take() {
+        let new_ai = ai_act(ai, monster_id, fov, game, objects);
         objects[monster_id].ai = Some(new_ai);
     }
 }
 
-fn ai_basic(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> Ai {
+/// one turn of `ai`, returning the AI the monster has after it
+fn ai_act(
+    ai: Ai,
+    monster_id: usize,
+    fov: &dyn Visibility,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> Ai {
+    use Ai::*;
+    match ai {
+        Basic => ai_basic(monster_id, fov, game, objects),
+        Hunting {
+            last_seen,
+            num_turns,
+            then,
+        } => ai_hunting(monster_id, fov, game, objects, last_seen, num_turns, then),
+        Tracker => ai_tracker(monster_id, fov, game, objects),
+        Fleeing => ai_fleeing(monster_id, fov, game, objects),
+        Pet => ai_pet(monster_id, fov, game, objects),
+        Ranged { range } => ai_ranged(monster_id, fov, game, objects, range),
+        Confused {
+            previous_ai,
+            last_turn,
+        } => ai_confused(monster_id, fov, game, objects, previous_ai, last_turn),
+        Entangled {
+            previous_ai,
+            last_turn,
+        } => ai_entangled(monster_id, fov, game, objects, previous_ai, last_turn),
+        Custom(mut brain) => brain
+            .0
+            .take_turn(monster_id, fov, game, objects)
+            .unwrap_or(Custom(brain)),
+    }
+}
+
+fn ai_basic(monster_id: usize, fov: &dyn Visibility, game: &mut Game, objects: &mut [Object]) -> Ai {
     // a basic monster takes its turn. If you can see it, it can see you
     let (monster_x, monster_y) = objects[monster_id].pos();
-    if tcod.fov.is_in_fov(monster_x, monster_y) {
-        if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
+    if fov.is_in_fov(monster_x, monster_y) {
+        if should_flee(monster_id, objects) {
+            game.messages.add(
+                format!("The {} turns to flee!", objects[monster_id].name),
+                Severity::Good,
+            );
+            return Ai::Fleeing;
+        }
+        if !in_melee_reach(&objects[monster_id], &objects[PLAYER], game.settings.movement) {
             // move towards player if too far away
             let (player_x, player_y) = objects[PLAYER].pos();
-            move_towards(monster_id, player_x, player_y, &game.map, objects);
+            move_towards(monster_id, player_x, player_y, game, objects);
         } else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
             // close enough, attack! (if the player is still alive.)
             let (monster, player) = mut_two(monster_id, PLAYER, objects);
             monster.attack(player, game);
         }
+        // remember where the player was, in case they slip out of sight
+        return Ai::Hunting {
+            last_seen: objects[PLAYER].pos(),
+            num_turns: PURSUIT_TURNS,
+            then: basic_ai(),
+        };
     }
     Ai::Basic
 }
 
+fn ai_ranged(
+    monster_id: usize,
+    fov: &dyn Visibility,
+    game: &mut Game,
+    objects: &mut [Object],
+    range: i32,
+) -> Ai {
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    let player_alive = objects[PLAYER].fighter.is_some_and(|f| f.hp > 0);
+    if !fov.is_in_fov(monster_x, monster_y) || !player_alive {
+        return Ai::Ranged { range };
+    }
+    let player_pos = objects[PLAYER].pos();
+    let distance = objects[monster_id].distance_to(&objects[PLAYER]);
+    // the shot has to get all the way to the player, past walls and creatures
+    let clear_shot =
+        line_of_fire((monster_x, monster_y), player_pos, game, objects).last() == Some(&player_pos);
+    if in_melee_reach(&objects[monster_id], &objects[PLAYER], game.settings.movement) {
+        // too close to shoot; hit them instead
+        let (monster, player) = mut_two(monster_id, PLAYER, objects);
+        monster.attack(player, game);
+    } else if distance > 2.0 && distance <= range as f32 && clear_shot {
+        let damage = objects[monster_id].power(game);
+        game.messages.add(
+            format!("The {} shoots you for {} hit points.", objects[monster_id].name, damage),
+            Severity::Danger,
+        );
+        objects[PLAYER].take_damage(damage, game);
+    } else {
+        move_towards(monster_id, player_pos.0, player_pos.1, game, objects);
+    }
+    Ai::Ranged { range }
+}
+
+fn ai_hunting(
+    monster_id: usize,
+    fov: &dyn Visibility,
+    game: &mut Game,
+    objects: &mut [Object],
+    last_seen: (i32, i32),
+    num_turns: i32,
+    then: Box<Ai>,
+) -> Ai {
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    if fov.is_in_fov(monster_x, monster_y) {
+        // back in sight, so its own AI deals with the player again
+        return ai_act(*then, monster_id, fov, game, objects);
+    }
+    if num_turns <= 0 || (monster_x, monster_y) == last_seen {
+        // got there, or gave up, without finding the player
+        return *then;
+    }
+    move_towards(monster_id, last_seen.0, last_seen.1, game, objects);
+    Ai::Hunting {
+        last_seen,
+        num_turns: num_turns - 1,
+        then,
+    }
+}
+
+fn ai_tracker(monster_id: usize, fov: &dyn Visibility, game: &mut Game, objects: &mut [Object]) -> Ai {
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    if fov.is_in_fov(monster_x, monster_y) {
+        // no need to sniff around with the player right there
+        ai_basic(monster_id, fov, game, objects);
+        return Ai::Tracker;
+    }
+
+    // step onto whichever free tile next to it smells the strongest, as long
+    // as that's stronger than where it stands
+    let turn = game.stats.turns;
+    let mut best = (0, 0);
+    let mut best_strength = game.scent.strength(monster_x, monster_y, turn);
+    for &(dx, dy) in neighbor_steps(game.settings.movement) {
+        let (x, y) = (monster_x + dx, monster_y + dy);
+        let on_map = (0..MAP_WIDTH).contains(&x) && (0..MAP_HEIGHT).contains(&y);
+        if !on_map || is_blocked(x, y, &game.map, objects) {
+            continue;
+        }
+        let strength = game.scent.strength(x, y, turn);
+        if strength > best_strength {
+            best = (dx, dy);
+            best_strength = strength;
+        }
+    }
+    if best != (0, 0) {
+        move_by(monster_id, best.0, best.1, game, objects);
+    }
+    Ai::Tracker
+}
+
+fn ai_fleeing(monster_id: usize, fov: &dyn Visibility, game: &mut Game, objects: &mut [Object]) -> Ai {
+    if !should_flee(monster_id, objects) {
+        // enough of its pack turned up again to make a stand, or it's
+        // healed up enough to fight
+        let (monster_x, monster_y) = objects[monster_id].pos();
+        if fov.is_in_fov(monster_x, monster_y) {
+            game.messages.add(
+                format!("The {} rallies!", objects[monster_id].name),
+                Severity::Warning,
+            );
+        }
+        return ai_basic(monster_id, fov, game, objects);
+    }
+
+    if !step_away_from_player(monster_id, game, objects)
+        && in_melee_reach(&objects[monster_id], &objects[PLAYER], game.settings.movement)
+        && objects[PLAYER].fighter.map_or(0, |f| f.hp) > 0
+    {
+        // cornered, so it fights after all
+        let (monster, player) = mut_two(monster_id, PLAYER, objects);
+        monster.attack(player, game);
+    }
+    Ai::Fleeing
+}
+
+/// back off to whichever free tile is farthest from the player. Returns
+/// whether there was one further away than where it stands
+fn step_away_from_player(monster_id: usize, game: &mut Game, objects: &mut [Object]) -> bool {
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    let mut best = (0, 0);
+    let mut best_distance = objects[PLAYER].distance(monster_x, monster_y);
+    for &(dx, dy) in neighbor_steps(game.settings.movement) {
+        let (x, y) = (monster_x + dx, monster_y + dy);
+        let on_map = (0..MAP_WIDTH).contains(&x) && (0..MAP_HEIGHT).contains(&y);
+        if !on_map || is_blocked(x, y, &game.map, objects) {
+            continue;
+        }
+        let distance = objects[PLAYER].distance(x, y);
+        if distance > best_distance {
+            best = (dx, dy);
+            best_distance = distance;
+        }
+    }
+    if best == (0, 0) {
+        return false;
+    }
+    move_by(monster_id, best.0, best.1, game, objects);
+    true
+}
+
+/// whether a monster would rather run than fight: its pack has thinned out,
+/// or it's a coward and badly hurt
+fn should_flee(monster_id: usize, objects: &mut [Object]) -> bool {
+    let monster = &objects[monster_id];
+    let badly_hurt = monster
+        .fighter
+        .is_some_and(|f| f.hp * 100 < f.base_max_hp * COWARD_HP_PERCENT);
+    // always check the morale, so the monster keeps track of its pack
+    let morale_broken = morale_broken(monster_id, objects);
+    morale_broken || (objects[monster_id].cowardly && badly_hurt)
+}
+
+/// whether a pack monster has seen too much of its pack fall to keep
+/// fighting. Every check also notes how big the pack has been, so
+/// reinforcements both steady it and raise what it expects to have around
+fn morale_broken(monster_id: usize, objects: &mut [Object]) -> bool {
+    let monster = &objects[monster_id];
+    let allies = objects
+        .iter()
+        .enumerate()
+        .filter(|&(id, other)| {
+            id != monster_id
+                && other.alive
+                && !other.ally
+                && other.name == monster.name
+                && other.distance_to(monster) <= MORALE_RADIUS
+        })
+        .count() as i32;
+    match objects[monster_id].morale.as_mut() {
+        Some(morale) => {
+            morale.pack = cmp::max(morale.pack, allies);
+            allies * 100 < morale.pack * morale.breaking_point
+        }
+        None => false,
+    }
+}
+
+/// the steps anything can take in one move
+fn neighbor_steps(movement: MovementMode) -> &'static [(i32, i32)] {
+    match movement {
+        MovementMode::EightWay => &[
+            (-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1),
+        ],
+        MovementMode::FourWay => &[(0, -1), (-1, 0), (1, 0), (0, 1)],
+    }
+}
+
+/// whether `other` is a single step away from `object`, close enough to hit.
+/// Where nothing moves diagonally, nothing hits diagonally either
+fn in_melee_reach(object: &Object, other: &Object, movement: MovementMode) -> bool {
+    neighbor_steps(movement).contains(&(other.x - object.x, other.y - object.y))
+}
+
+fn ai_pet(monster_id: usize, _fov: &dyn Visibility, game: &mut Game, objects: &mut [Object]) -> Ai {
+    // a pet defends the player: bite any hostile next to it, otherwise tag along
+    let target_id = objects.iter().enumerate().position(|(id, object)| {
+        id != PLAYER
+            && id != monster_id
+            && !object.ally
+            && object.ai.is_some()
+            && object.fighter.is_some()
+            && in_melee_reach(&objects[monster_id], object, game.settings.movement)
+    });
+    if let Some(target_id) = target_id {
+        let (pet, target) = mut_two(monster_id, target_id, objects);
+        pet.attack(target, game);
+    } else if objects[monster_id].distance_to(&objects[PLAYER]) >= 3.0 {
+        let (player_x, player_y) = objects[PLAYER].pos();
+        move_towards(monster_id, player_x, player_y, game, objects);
+    }
+    Ai::Pet
+}
+
 fn ai_confused(
     monster_id: usize,
-    _tcod: &Tcod,
+    _fov: &dyn Visibility,
     game: &mut Game,
     objects: &mut [Object],
     previous_ai: Box<Ai>,
-    num_turns: i32
+    last_turn: u32,
 ) -> Ai {
-    if num_turns >= 0 {
+    if game.stats.turns <= last_turn {
         // still confused ...
-        // move in a random direction, and decrease the number of turns confused
-        move_by(
-            monster_id,
-            rand::thread_rng().gen_range(-1, 2),
-            rand::thread_rng().gen_range(-1, 2),
-            &game.map,
-            objects,
-        );
+        // move in a random direction, out of the ones it can actually take
+        if let Some((dx, dy)) = stumble_direction(monster_id, game, objects) {
+            move_by(monster_id, dx, dy, game, objects);
+        }
         Ai::Confused {
             previous_ai: previous_ai,
-            num_turns: num_turns - 1,
+            last_turn,
         }
     } else {
-        // restore the previous AI (this one will be deleted)
+        // restore the previous AI (this one will be deleted); whatever it was
+        // chasing before is forgotten in the confusion
         game.messages.add(
             format!("The {} is no longer confused!", objects[monster_id].name),
-            RED,
+            Severity::Danger,
+        );
+        match *previous_ai {
+            Ai::Hunting { then, .. } => *then,
+            ai => ai,
+        }
+    }
+}
+
+/// a random open direction for a confused monster to stumble in, steering
+/// clear of hazards if the settings say so and there's another way. boxed
+/// in, it stays put
+fn stumble_direction(monster_id: usize, game: &Game, objects: &[Object]) -> Option<(i32, i32)> {
+    let (x, y) = objects[monster_id].pos();
+    let four_way = game.settings.movement == MovementMode::FourWay;
+    let mut open = vec![];
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            // no stumbling diagonally when nothing can step that way
+            let diagonal = dx != 0 && dy != 0;
+            if (dx, dy) == (0, 0) || (four_way && diagonal) {
+                continue;
+            }
+            if !is_tile_blocked(x + dx, y + dy, game, objects) {
+                open.push((dx, dy));
+            }
+        }
+    }
+    if game.settings.confused_avoid_hazards {
+        let safe: Vec<(i32, i32)> = open
+            .iter()
+            .copied()
+            .filter(|&(dx, dy)| hazard_at(game, x + dx, y + dy).is_none())
+            .collect();
+        if !safe.is_empty() {
+            open = safe;
+        }
+    }
+    rand::thread_rng().choose(&open).copied()
+}
+
+fn ai_entangled(
+    monster_id: usize,
+    _fov: &dyn Visibility,
+    game: &mut Game,
+    objects: &mut [Object],
+    previous_ai: Box<Ai>,
+    last_turn: u32,
+) -> Ai {
+    if game.stats.turns <= last_turn {
+        // stuck where it is, but still dangerous up close
+        let hostile = !objects[monster_id].ally;
+        if hostile
+            && in_melee_reach(&objects[monster_id], &objects[PLAYER], game.settings.movement)
+            && objects[PLAYER].fighter.map_or(0, |f| f.hp) > 0
+        {
+            let (monster, player) = mut_two(monster_id, PLAYER, objects);
+            monster.attack(player, game);
+        }
+        Ai::Entangled {
+            previous_ai,
+            last_turn,
+        }
+    } else {
+        // restore the previous AI (this one will be deleted)
+        game.messages.add(
+            format!("The {} breaks free!", objects[monster_id].name),
+            Severity::Danger,
         );
         *previous_ai
     }
@@ -90,6 +834,7 @@ fn ai_confused(
 pub enum DeathCallback {
     Player,
     Monster,
+    Pet,
 }
 
 impl DeathCallback {
@@ -98,14 +843,16 @@ impl DeathCallback {
         let callback = match self {
             Player => player_death,
             Monster => monster_death,
+            Pet => pet_death,
         };
+        game.events.push(GameEvent::Death { name: object.name.clone(), pos: object.pos() });
         callback(object, game);
     }
 }
 
 pub fn level_up(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
     let player = &mut objects[PLAYER];
-    let level_up_xp = LEVEL_UP_BASE + player.level * LEVEL_UP_FACTOR;
+    let level_up_xp = game.settings.xp_curve.level_up_xp(player.level);
     // see if the player's xp is enough to level up
     if player.fighter.as_ref().map_or(0, |f| f.xp) >= level_up_xp {
         // it is! level up
@@ -115,7 +862,7 @@ pub fn level_up(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
                 "Your battle skills grow stronger! You reached level {}!",
                 player.level
             ),
-            YELLOW,
+            Severity::Good,
         );
         let fighter = player.fighter.as_mut().unwrap();
         let mut choice = None;
@@ -129,7 +876,7 @@ pub fn level_up(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
                     format!("Agility (+1 defense, from {})", fighter.base_defense),
                 ],
                 LEVEL_SCREEN_WIDTH,
-                &mut tcod.root,
+                &mut *tcod.renderer,
             );
             if let Some(select) = choice {
                 let select_str = match select {
@@ -142,7 +889,7 @@ pub fn level_up(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
                     &*format!("Are you sure you want to upgrade your {}?\n", select_str),
                     &["no", "yes"],
                     LEVEL_SCREEN_WIDTH,
-                    &mut tcod.root,
+                    &mut *tcod.renderer,
                 );
                 if let Some(confirm) = confirm{
                     if confirm != 1 { choice = None; }
@@ -169,47 +916,229 @@ pub fn level_up(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Item {
     Heal,
+    Ration,
+    Caltrops,
+    Oil,
+    Poison,
     Lightning,
     Confuse,
+    Entangle,
     Fireball,
+    Genocide,
+    Stone,
+    Protection,
+    Recharge,
+    PhaseDoor,
+    Key,
+    Dagger,
+    PoisonedDagger,
     Sword,
     Shield,
+    Spear,
+    LightningWand,
+    FireballWand,
+}
+
+/// every kind of item there is
+pub const ALL_ITEMS: &[Item] = &[
+    Item::Heal,
+    Item::Ration,
+    Item::Caltrops,
+    Item::Oil,
+    Item::Poison,
+    Item::Lightning,
+    Item::Confuse,
+    Item::Entangle,
+    Item::Fireball,
+    Item::Genocide,
+    Item::Stone,
+    Item::Protection,
+    Item::Recharge,
+    Item::PhaseDoor,
+    Item::Key,
+    Item::Dagger,
+    Item::PoisonedDagger,
+    Item::Sword,
+    Item::Shield,
+    Item::Spear,
+    Item::LightningWand,
+    Item::FireballWand,
+];
+
+/// every kind of potion, which start out unidentified
+pub const POTIONS: &[Item] = &[Item::Heal, Item::Poison];
+
+/// every kind of scroll, which start out unidentified too
+pub const SCROLLS: &[Item] = &[
+    Item::Lightning,
+    Item::Confuse,
+    Item::Entangle,
+    Item::Fireball,
+    Item::Genocide,
+    Item::Stone,
+    Item::Protection,
+    Item::Recharge,
+    Item::PhaseDoor,
+];
+
+impl Item {
+    /// the real name of this kind of item
+    pub fn name(self) -> &'static str {
+        use Item::*;
+        match self {
+            Heal => "healing potion",
+            Ration => "emergency ration",
+            Caltrops => "bag of caltrops",
+            Oil => "flask of oil",
+            Poison => "potion of poison",
+            Lightning => "scroll of lightning bolt",
+            Confuse => "scroll of confusion",
+            Entangle => "scroll of entanglement",
+            Fireball => "scroll of fireball",
+            Genocide => "scroll of genocide",
+            Stone => "scroll of stone",
+            Protection => "scroll of protection",
+            Recharge => "scroll of recharging",
+            PhaseDoor => "scroll of phase door",
+            Key => "iron key",
+            Dagger => "dagger",
+            PoisonedDagger => "poisoned dagger",
+            Sword => "sword",
+            Shield => "shield",
+            Spear => "spear",
+            LightningWand => "wand of lightning",
+            FireballWand => "wand of fireball",
+        }
+    }
+
+    pub fn is_potion(self) -> bool {
+        POTIONS.contains(&self)
+    }
+
+    pub fn is_scroll(self) -> bool {
+        SCROLLS.contains(&self)
+    }
+
+    /// rough worth of an item, used to decide what a pile of items looks like
+    pub fn value(self) -> i32 {
+        use Item::*;
+        match self {
+            Poison => 0,
+            Heal | Ration => 1,
+            Confuse | Dagger | Caltrops | Oil | Key => 2,
+            Lightning | Stone | Entangle | Protection | Spear | PoisonedDagger => 3,
+            Sword | Shield | Recharge | PhaseDoor => 4,
+            Fireball => 5,
+            LightningWand => 6,
+            FireballWand => 8,
+            Genocide => 10,
+        }
+    }
+
+    /// how heavy the item is to carry around
+    pub fn weight(self) -> i32 {
+        use Item::*;
+        match self {
+            Heal | Poison | Confuse | Lightning | Fireball | Entangle | Stone | Protection
+            | Recharge | PhaseDoor | Key | Genocide => 1,
+            Ration | Caltrops | Oil | LightningWand | FireballWand => 2,
+            Dagger | PoisonedDagger => 3,
+            Spear => 6,
+            Sword => 8,
+            Shield => 10,
+        }
+    }
 }
 
-enum UseResult {
+/// How using an item went
+pub enum UseResult {
     UsedUp,
     UsedAndKept,
     Cancelled,
+    /// the player backed out of picking a target, and may want another item
+    TargetCancelled,
 }
 
-/// add to the player's inventory and remove from map
-pub fn pick_item_up(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
-    if game.inventory.len() >= 26 {
+/// add to the player's inventory and remove from map. returns whether there
+/// was room for it
+pub fn pick_item_up(
+    object_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> bool {
+    if objects[object_id].disguise.is_some() {
+        // it was never an item to begin with
+        reveal_mimic(object_id, game, objects);
+        return false;
+    }
+    let cap = game.settings.inventory_cap;
+    if game.inventory.len() as i32 >= cap {
+        // a lowered cap never takes anything away, it just stops new pickups
         game.messages.add(
             format!(
-                "Your inventory is full, cannot pick up {}.",
+                "Your inventory is full ({}/{}), cannot pick up {}.",
+                game.inventory.len(),
+                cap,
                 objects[object_id].name
             ),
-            RED,
+            Severity::Warning,
         );
+        false
     } else {
         let item = objects.swap_remove(object_id);
+        game.positions.swap_removed(object_id, item.pos(), objects);
         game.messages
-            .add(format!("You picked up a {}!", item.name), GREEN);
+            .add(format!("You picked up a {}!", item.name), Severity::Good);
         let index = game.inventory.len();
         let slot = item.equipment.map(|e| e.slot);
+        let was_encumbered = objects[PLAYER].encumbrance(game) > 0;
         game.inventory.push(item);
+        if !was_encumbered && objects[PLAYER].encumbrance(game) > 0 {
+            game.messages
+                .add("You are carrying too much, and your defense suffers.", Severity::Warning);
+        }
 
         // automatically equip, if the corresponding eqipment slot is unused
+        // and the settings allow it
         if let Some(slot) = slot {
             if get_equipped_in_slot(slot, &game.inventory).is_none() {
-                game.inventory[index].equip(&mut game.messages);
+                let equip = match game.settings.auto_equip {
+                    AutoEquip::Off => false,
+                    AutoEquip::EmptySlots => true,
+                    AutoEquip::Prompt => {
+                        let question = format!("Equip the {}?\n", game.inventory[index].name);
+                        let answer =
+                            menu(&question, &["no", "yes"], INVENTORY_WIDTH, &mut *tcod.renderer);
+                        answer == Some(1)
+                    }
+                };
+                if equip {
+                    game.inventory[index].equip(&mut game.messages);
+                }
             }
         }
+        true
     }
 }
 
-fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
+/// the total weight of an inventory. worn equipment sits better than
+/// anything in the pack, so it only counts for half
+pub fn carried_weight(inventory: &[Object]) -> i32 {
+    inventory
+        .iter()
+        .map(|object| {
+            let weight = object.item.map_or(0, Item::weight);
+            if object.equipment.map(|e| e.equipped) == Some(true) {
+                (weight + 1) / 2
+            } else {
+                weight
+            }
+        })
+        .sum()
+}
+
+pub fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
     for (inventory_id, item) in inventory.iter().enumerate() {
         if item
             .equipment
@@ -222,33 +1151,64 @@ fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
     None
 }
 
-pub fn use_item(inventory_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+/// use an item from the inventory. Returns whether the player backed out of
+/// aiming it, in which case they get the inventory back to choose again
+pub fn use_item(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> bool {
     use Item::*;
     // just call the "use function" if it is defined
     if let Some(item) = game.inventory[inventory_id].item {
         let on_use = match item {
             Heal => cast_heal,
+            Ration => eat_ration,
+            Caltrops => throw_caltrops,
+            Oil => throw_oil,
+            Poison => cast_poison,
             Lightning => cast_lightning,
             Confuse => cast_confuse,
+            Entangle => cast_entangle,
             Fireball => cast_fireball,
-            Sword | Shield => toggle_equipment,
+            Genocide => cast_genocide,
+            Stone => cast_stone,
+            Protection => cast_protection,
+            Recharge => cast_recharge,
+            PhaseDoor => cast_phase_door,
+            Key => turn_key,
+            Dagger | PoisonedDagger | Sword | Shield | Spear => toggle_equipment,
+            LightningWand => zap_lightning_wand,
+            FireballWand => zap_fireball_wand,
         };
-        match on_use(inventory_id, tcod, game, objects) {
+        let result = match plugin_use_item(item, inventory_id, tcod, game, objects) {
+            Some(result) => result,
+            None => on_use(inventory_id, tcod, game, objects),
+        };
+        match result {
             UseResult::UsedUp => {
                 // destroy after use, unless it was cancelled
                 game.inventory.remove(inventory_id);
+                game.stats.items_used += 1;
+                // drinking a potion or reading a scroll gives away what it was
+                if item.is_potion() || item.is_scroll() {
+                    identify(item, game, objects);
+                }
             }
             UseResult::UsedAndKept => {} // do nothing
             UseResult::Cancelled => {
-                game.messages.add("Cancelled", WHITE);
+                game.messages.add("Cancelled", Severity::Info);
             }
+            UseResult::TargetCancelled => return true,
         }
     } else {
         game.messages.add(
             format!("The {} cannot be used.", game.inventory[inventory_id].name),
-            WHITE,
+            Severity::Info,
         );
     }
+    false
 }
 
 pub fn drop_item(inventory_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
@@ -258,71 +1218,192 @@ pub fn drop_item(inventory_id: usize, game: &mut Game, objects: &mut Vec<Object>
     }
     item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
     game.messages
-        .add(format!("You dropped a {}.", item.name), YELLOW);
+        .add(format!("You dropped a {}.", item.name), Severity::Info);
+    game.positions.added(objects.len(), item.pos());
     objects.push(item);
 }
 
-fn cast_heal(
-    _inventory_id: usize,
-    _tcod: &mut Tcod,
+/// whether the item being used is a scroll the player hasn't identified yet.
+/// Such a scroll mustn't give away what it does until it's been read, so
+/// backing out of it or finding nothing for it to do still uses it up
+fn unknown_scroll(inventory_id: usize, game: &Game) -> bool {
+    let item = game.inventory[inventory_id].item;
+    item.is_some_and(|item| item.is_scroll() && !game.identified.contains(&item))
+}
+
+/// ask for a target, without naming the effect if the scroll is unknown
+fn target_prompt(inventory_id: usize, game: &mut Game, prompt: &str) {
+    if unknown_scroll(inventory_id, game) {
+        game.messages.add_colored(
+            "Left-click a target (or Tab, then Enter) for the scroll, or right-click to cancel.",
+            LIGHT_CYAN,
+        );
+    } else {
+        game.messages.add_colored(prompt, LIGHT_CYAN);
+    }
+}
+
+/// an unknown scroll read for nothing is gone all the same; a known one
+/// can be put away again, with `known` as the reason
+fn scroll_wasted(inventory_id: usize, game: &mut Game, known: UseResult) -> UseResult {
+    if unknown_scroll(inventory_id, game) {
+        game.messages
+            .add_colored("The scroll crumbles to dust, but nothing seems to happen.", LIGHT_GREY);
+        return UseResult::UsedUp;
+    }
+    known
+}
+
+/// the scroll found nothing to work on: a known one says why and is put away
+fn scroll_failed(inventory_id: usize, game: &mut Game, why: &str) -> UseResult {
+    if !unknown_scroll(inventory_id, game) {
+        game.messages.add(why, Severity::Warning);
+    }
+    scroll_wasted(inventory_id, game, UseResult::Cancelled)
+}
+
+fn cast_heal(
+    _inventory_id: usize,
+    tcod: &mut Tcod,
     game: &mut Game,
     objects: &mut [Object],
 ) -> UseResult {
-    // heal the player
-    let player = &mut objects[PLAYER];
-    if let Some(fighter) = player.fighter {
-        if fighter.hp == player.max_hp(game) {
-            game.messages.add("You are already at full health.", RED);
+    // with an ally in sight, a player who knows the potion can choose who
+    // to heal. One who doesn't just drinks it
+    let known = game.identified.contains(&Item::Heal);
+    let ally_in_sight = objects.iter().any(|object| {
+        object.ally
+            && object.alive
+            && tcod.fov.is_in_fov(object.x, object.y)
+            && objects[PLAYER].distance_to(object) <= HEAL_RANGE as f32
+    });
+    let target_id = if known && ally_in_sight {
+        game.messages.add_colored(
+            "Left-click yourself or an ally to heal, or right-click to cancel.",
+            LIGHT_CYAN,
+        );
+        match target_ally(tcod, game, objects, Some(HEAL_RANGE as f32)) {
+            Some(id) => id,
+            None => return UseResult::TargetCancelled,
+        }
+    } else {
+        PLAYER
+    };
+
+    let target = &mut objects[target_id];
+    if let Some(fighter) = target.fighter {
+        if fighter.hp == target.max_hp(game) {
+            if target_id != PLAYER {
+                game.messages.add(
+                    format!("The {} is already at full health.", target.name),
+                    Severity::Warning,
+                );
+                return UseResult::Cancelled;
+            }
+            if !known {
+                // the player didn't know what they were drinking
+                game.messages.add_colored(
+                    "A soothing warmth washes over you, but you are already at full health.",
+                    LIGHT_VIOLET,
+                );
+                return UseResult::UsedUp;
+            }
+            game.messages.add("You are already at full health.", Severity::Warning);
             return UseResult::Cancelled;
         }
-        game.messages
-            .add("Your wounds start to feel better!", LIGHT_VIOLET);
-        player.heal(HEAL_AMOUNT, game);
+        if target_id == PLAYER {
+            game.messages
+                .add_colored("Your wounds start to feel better!", LIGHT_VIOLET);
+        } else {
+            game.messages.add_colored(
+                format!("The {}'s wounds start to close!", target.name),
+                LIGHT_VIOLET,
+            );
+        }
+        target.heal(HEAL_AMOUNT, game);
         return UseResult::UsedUp;
     }
     UseResult::Cancelled
 }
 
-fn cast_lightning(
+fn eat_ration(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    let player = &mut objects[PLAYER];
+    if player.fighter.map_or(0, |f| f.hp) >= player.max_hp(game) {
+        // save it for when it's needed
+        game.messages.add("You are not hungry right now.", Severity::Warning);
+        return UseResult::Cancelled;
+    }
+    game.messages
+        .add("You wolf down the ration, and feel a little better.", Severity::Good);
+    player.heal(RATION_HEAL_AMOUNT, game);
+    UseResult::UsedUp
+}
+
+fn cast_poison(
     _inventory_id: usize,
     _tcod: &mut Tcod,
     game: &mut Game,
     objects: &mut [Object],
+) -> UseResult {
+    game.messages.add("You feel violently sick!", Severity::Danger);
+    objects[PLAYER].take_damage(POISON_POTION_DAMAGE, game);
+    UseResult::UsedUp
+}
+
+/// Damage of an attack spell, boosted when a mage casts it
+fn spell_damage(base: i32, game: &Game) -> i32 {
+    if game.class == PlayerClass::Mage {
+        base * (100 + MAGE_SPELL_BONUS) / 100
+    } else {
+        base
+    }
+}
+
+fn cast_lightning(
+    inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
 ) -> UseResult {
     // find closest enemy (inside a maximum range and damage it)
-    let monster_id = closest_monster(_tcod, objects, LIGHTNING_RANGE);
+    let monster_id = closest_monster(&_tcod.fov, objects, LIGHTNING_RANGE);
     if let Some(monster_id) = monster_id {
         // zap it!
-        game.messages.add(
+        let damage = spell_damage(LIGHTNING_DAMAGE, game);
+        game.messages.add_colored(
             format!(
                 "A lightning bolt strikes the {} with a loud thunder! \
                 The damage is {} hit points.",
-                objects[monster_id].name, LIGHTNING_DAMAGE
+                objects[monster_id].name, damage
             ),
             LIGHT_BLUE,
         );
-        if let Some(xp) = objects[monster_id].take_damage(LIGHTNING_DAMAGE, game) {
-            objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
+        if let Some(xp) = objects[monster_id].take_damage(damage, game) {
+            objects[PLAYER].grant_xp(xp);
         }
         UseResult::UsedUp
     } else {
         // no enemy found within maximum range
-        game.messages
-            .add("No enemy is close enough to strike.", RED);
-        UseResult::Cancelled
+        scroll_failed(inventory_id, game, "No enemy is close enough to strike.")
     }
 }
 
 fn cast_confuse(
-    _inventory_id: usize,
+    inventory_id: usize,
     _tcod: &mut Tcod,
     game: &mut Game,
     objects: &mut [Object],
 ) -> UseResult {
     // ask the player for a target to confuse
-    game.messages.add(
-        "Left click an enemy to confuse it, or right-click to cancel.",
-        LIGHT_CYAN,
+    target_prompt(
+        inventory_id,
+        game,
+        "Left-click an enemy (or Tab, then Enter) to confuse it, or right-click to cancel.",
     );
     let monster_id = target_monster(_tcod, game, objects, Some(CONFUSE_RANGE as f32));
     if let Some(monster_id) = monster_id {
@@ -331,9 +1412,9 @@ fn cast_confuse(
         // some turns it will restore the old AI
         objects[monster_id].ai = Some(Ai::Confused {
             previous_ai: Box::new(old_ai),
-            num_turns: CONFUSE_NUM_TURNS,
+            last_turn: game.stats.turns + CONFUSE_NUM_TURNS,
         });
-        game.messages.add(
+        game.messages.add_colored(
             format!(
                 "The eyes of {} look vacant, as he starts to stumble around!",
                 objects[monster_id].name
@@ -342,29 +1423,68 @@ fn cast_confuse(
         );
         UseResult::UsedUp
     } else {
-        // no enemy found within maximum range
-        game.messages
-            .add("No enemy is close enough to strike.", RED);
-        UseResult::Cancelled
+        // picking a monster only ends without one when the player backs out
+        scroll_wasted(inventory_id, game, UseResult::TargetCancelled)
+    }
+}
+
+fn cast_entangle(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    // ask the player for a target to hold in place
+    target_prompt(
+        inventory_id,
+        game,
+        "Left-click an enemy (or Tab, then Enter) to entangle it, or right-click to cancel.",
+    );
+    let monster_id = target_monster(tcod, game, objects, Some(ENTANGLE_RANGE as f32));
+    if let Some(monster_id) = monster_id {
+        let old_ai = objects[monster_id].ai.take().unwrap_or(Ai::Basic);
+        // it can't move until the entangling wears off, then the old AI is back
+        objects[monster_id].ai = Some(Ai::Entangled {
+            previous_ai: Box::new(old_ai),
+            last_turn: game.stats.turns + ENTANGLE_NUM_TURNS,
+        });
+        game.messages.add_colored(
+            format!(
+                "Vines burst from the ground and wrap around the {}!",
+                objects[monster_id].name
+            ),
+            LIGHT_GREEN,
+        );
+        UseResult::UsedUp
+    } else {
+        scroll_wasted(inventory_id, game, UseResult::TargetCancelled)
     }
 }
 
 fn cast_fireball(
-    _inventory_id: usize,
+    inventory_id: usize,
     _tcod: &mut Tcod,
     game: &mut Game,
     objects: &mut [Object],
 ) -> UseResult {
     // ask the player for a target tile to throw a fireball at
-    game.messages.add(
-        "Left-click a target tile for the fireball, or right-click to cancel.",
-        LIGHT_CYAN,
+    target_prompt(
+        inventory_id,
+        game,
+        "Left-click a target tile (or Tab, then Enter) for the fireball, or right-click to cancel.",
     );
     let (x, y) = match target_tile(_tcod, game, objects, None) {
         Some(tile_pos) => tile_pos,
-        None => return UseResult::Cancelled,
+        None => return scroll_wasted(inventory_id, game, UseResult::TargetCancelled),
     };
-    game.messages.add(
+    fireball_blast(x, y, game, objects);
+    UseResult::UsedUp
+}
+
+/// Burn everything within the fireball's reach of (x, y), setting any oil
+/// there alight. The player earns xp for whatever dies, except themselves
+fn fireball_blast(x: i32, y: i32, game: &mut Game, objects: &mut [Object]) {
+    game.messages.add_colored(
         format!(
             "The fireball explodes, burning everything within {} tiles!",
             FIREBALL_RADIUS
@@ -372,17 +1492,18 @@ fn cast_fireball(
         ORANGE,
     );
 
+    let damage = spell_damage(FIREBALL_DAMAGE, game);
     let mut xp_to_gain = 0;
     for (id, obj) in objects.iter_mut().enumerate() {
         if obj.distance(x, y) <= FIREBALL_RADIUS as f32 && obj.fighter.is_some() {
-            game.messages.add(
+            game.messages.add_colored(
                 format!(
                     "The {} gets burned for {} hit points.",
-                    obj.name, FIREBALL_DAMAGE
+                    obj.name, damage
                 ),
                 ORANGE,
             );
-            if let Some(xp) = obj.take_damage(FIREBALL_DAMAGE, game) {
+            if let Some(xp) = obj.take_damage(damage, game) {
                 if id != PLAYER {
                     // don't reward the player for burning themself!
                     xp_to_gain += xp;
@@ -390,8 +1511,323 @@ fn cast_fireball(
             }
         }
     }
-    objects[PLAYER].fighter.as_mut().unwrap().xp += xp_to_gain;
+    objects[PLAYER].grant_xp(xp_to_gain);
+
+    // oil in the blast goes up in flames, scorching whatever stands in it
+    let in_blast = |&(hazard_x, hazard_y): &(i32, i32)| {
+        ((hazard_x - x).pow(2) + (hazard_y - y).pow(2)) as f32 <= (FIREBALL_RADIUS.pow(2)) as f32
+    };
+    let burning: Vec<(i32, i32)> = game
+        .hazards
+        .iter()
+        .filter(|hazard| hazard.kind == HazardKind::Oil && in_blast(&hazard.pos))
+        .map(|hazard| hazard.pos)
+        .collect();
+    if !burning.is_empty() {
+        game.messages.add_colored("The oil catches fire!", ORANGE);
+        game.hazards.retain(|hazard| !burning.contains(&hazard.pos));
+        for id in 0..objects.len() {
+            if objects[id].fighter.is_none() || !burning.contains(&objects[id].pos()) {
+                continue;
+            }
+            if let Some(xp) = objects[id].take_damage(OIL_FIRE_DAMAGE, game) {
+                if id != PLAYER {
+                    objects[PLAYER].grant_xp(xp);
+                }
+            }
+        }
+    }
+}
+
+fn cast_genocide(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    // list every kind of monster still alive on this level; the player's
+    // pets aren't monsters to be wiped out
+    let is_monster =
+        |object: &Object| object.ai.is_some() && object.fighter.is_some() && !object.ally;
+    let mut kinds: Vec<String> = vec![];
+    for object in objects.iter() {
+        if is_monster(object) && !kinds.contains(&object.name) {
+            kinds.push(object.name.clone());
+        }
+    }
+    if kinds.is_empty() {
+        return scroll_failed(inventory_id, game, "There are no monsters here to wipe out.");
+    }
+
+    let header = if unknown_scroll(inventory_id, game) {
+        "The scroll asks for a name:\n"
+    } else {
+        "Choose a kind of monster to wipe out:\n"
+    };
+    let choice = menu(
+        header,
+        &kinds,
+        INVENTORY_WIDTH,
+        &mut *tcod.renderer,
+    );
+    let kind = match choice {
+        Some(index) => kinds[index].clone(),
+        None => return scroll_wasted(inventory_id, game, UseResult::Cancelled),
+    };
+    // there's no wiping out what there's only one of
+    if objects.iter().any(|object| is_monster(object) && object.unique && object.name == kind) {
+        let why = format!("The {} is too mighty for the scroll to wipe out.", kind);
+        return scroll_failed(inventory_id, game, &why);
+    }
+
+    // only living monsters go; the player, their pets and any corpses are
+    // left alone. They drop dead where they stand, worth nothing to the player
+    for object in objects.iter_mut() {
+        if is_monster(object) && object.name == kind {
+            game.events.push(GameEvent::Death { name: kind.clone(), pos: object.pos() });
+            object.alive = false;
+            object.fighter = None;
+            object.ai = None;
+            object.blocks = false;
+            object.char = '%';
+            object.color = DARK_RED;
+            object.always_visible = true;
+            object.name = format!("remains of {}", kind);
+        }
+    }
+    game.messages.add_colored(
+        format!(
+            "A terrible wail echoes through the level as every {} is wiped out!",
+            kind
+        ),
+        CRIMSON,
+    );
+    // and no more of them may show up on this level
+    game.genocided.push(kind);
+    UseResult::UsedUp
+}
+
+fn turn_key(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut [Object],
+) -> UseResult {
+    // keys work by walking into what they open
+    game.messages.add("Walk into a locked chest to open it with the key.", Severity::Info);
+    UseResult::Cancelled
+}
+
+fn cast_protection(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut [Object],
+) -> UseResult {
+    // a fresh shield, or a stronger one if there's one up already
+    game.shield.points = cmp::max(game.shield.points, PROTECTION_POINTS);
+    game.shield.turns = PROTECTION_NUM_TURNS;
+    game.messages.add_colored(
+        "A shimmering shield of force springs up around you!",
+        LIGHT_BLUE,
+    );
+    UseResult::UsedUp
+}
+
+fn cast_phase_door(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    // somewhere close by to land, further out if there's nowhere that close
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let landing_spots = |radius: i32| -> Vec<(i32, i32)> {
+        let mut spots = vec![];
+        for y in player_y - radius..=player_y + radius {
+            for x in player_x - radius..=player_x + radius {
+                let on_map = (0..MAP_WIDTH).contains(&x) && (0..MAP_HEIGHT).contains(&y);
+                if on_map
+                    && (x, y) != (player_x, player_y)
+                    && objects[PLAYER].distance(x, y) <= radius as f32
+                    && !is_tile_blocked(x, y, game, objects)
+                    && hazard_at(game, x, y).is_none()
+                {
+                    spots.push((x, y));
+                }
+            }
+        }
+        spots
+    };
+    let mut spots = landing_spots(PHASE_RADIUS);
+    if spots.is_empty() {
+        spots = landing_spots(PHASE_FALLBACK_RADIUS);
+    }
+    if spots.is_empty() {
+        return scroll_failed(inventory_id, game, "There is nowhere to phase to.");
+    }
+
+    // away from the nearest threat, if there is one in sight
+    if let Some(monster_id) = closest_monster(&tcod.fov, objects, PHASE_FALLBACK_RADIUS * 2) {
+        let monster = &objects[monster_id];
+        let now = monster.distance(player_x, player_y);
+        let away: Vec<(i32, i32)> =
+            spots.iter().copied().filter(|&(x, y)| monster.distance(x, y) > now).collect();
+        if !away.is_empty() {
+            spots = away;
+        }
+    }
+    let (x, y) = spots[rand::thread_rng().gen_range(0, spots.len())];
+    move_object(PLAYER, x, y, game, objects);
+    game.messages.add_colored("The world lurches, and you are somewhere else!", LIGHT_VIOLET);
+    UseResult::UsedUp
+}
+
+fn throw_caltrops(
+    _inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    throw_hazard(HazardKind::Caltrops, CALTROPS_NUM_TURNS, "caltrops", tcod, game, objects)
+}
+
+fn throw_oil(
+    _inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    throw_hazard(HazardKind::Oil, OIL_NUM_TURNS, "oil", tcod, game, objects)
+}
+
+/// ask for a tile, and spread the hazard around it for a while
+fn throw_hazard(
+    kind: HazardKind,
+    turns: i32,
+    name: &str,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    game.messages.add_colored(
+        format!(
+            "Left-click a floor tile (or Tab, then Enter) to throw the {} at, \
+            or right-click to cancel.",
+            name
+        ),
+        LIGHT_CYAN,
+    );
+    let target = match target_line(tcod, game, objects, Some(HAZARD_RANGE as f32)) {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::TargetCancelled,
+    };
+    // it comes down on whatever is in the way, or just short of a wall
+    let mut path = line_of_fire(objects[PLAYER].pos(), target, game, objects);
+    if path.last().map(|&(x, y)| game.map.get(x, y).blocked) == Some(true) {
+        path.pop();
+    }
+    let (x, y) = match path.last() {
+        Some(&tile_pos) => tile_pos,
+        None if target == objects[PLAYER].pos() => target,
+        None => {
+            game.messages.add("There is a wall in the way.", Severity::Warning);
+            return UseResult::Cancelled;
+        }
+    };
+    spread_hazard(kind, (x, y), turns, game);
+    game.messages.add(format!("You scatter the {} across the floor.", name), Severity::Info);
+    UseResult::UsedUp
+}
+
+fn cast_recharge(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut [Object],
+) -> UseResult {
+    // only wands and the like can take a charge
+    let chargeable: Vec<usize> = (0..game.inventory.len())
+        .filter(|&id| game.inventory[id].charges.is_some())
+        .collect();
+    if chargeable.is_empty() {
+        return scroll_failed(inventory_id, game, "You have nothing to recharge.");
+    }
+    let options: Vec<String> = chargeable
+        .iter()
+        .map(|&id| {
+            let item = &game.inventory[id];
+            format!("{} ({} charges)", item.name, item.charges.unwrap_or(0))
+        })
+        .collect();
+    let header = if unknown_scroll(inventory_id, game) {
+        "Choose an item for the scroll, or any other key to cancel.\n"
+    } else {
+        "Choose an item to recharge, or any other key to cancel.\n"
+    };
+    let choice = menu(header, &options, INVENTORY_WIDTH, &mut *tcod.renderer);
+    let item_id = match choice {
+        Some(index) => chargeable[index],
+        None => return scroll_wasted(inventory_id, game, UseResult::Cancelled),
+    };
+    let charges = game.inventory[item_id].charges.unwrap_or(0);
+    if charges >= WAND_CHARGES {
+        let why = format!("The {} is already fully charged.", game.inventory[item_id].name);
+        return scroll_failed(inventory_id, game, &why);
+    }
+    let item = &mut game.inventory[item_id];
+    item.charges = Some(cmp::min(charges + RECHARGE_AMOUNT, WAND_CHARGES));
+    game.messages.add_colored(
+        format!("The {} hums with renewed power.", item.name),
+        LIGHT_BLUE,
+    );
+    UseResult::UsedUp
+}
+
+fn cast_stone(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    // ask the player for a floor tile to raise a wall on
+    target_prompt(
+        inventory_id,
+        game,
+        "Left-click a floor tile to raise a wall on, or right-click to cancel.",
+    );
+    let (x, y) = match target_tile(tcod, game, objects, Some(STONE_RANGE as f32)) {
+        Some(tile_pos) => tile_pos,
+        None => return scroll_wasted(inventory_id, game, UseResult::TargetCancelled),
+    };
+    let tile = game.map.get_mut(x, y);
+    if tile.blocked {
+        return scroll_failed(inventory_id, game, "There is already a wall there.");
+    }
+    if objects.iter().any(|object| object.pos() == (x, y)) {
+        return scroll_failed(inventory_id, game, "Something is in the way.");
+    }
+
+    // don't let the player wall themselves off from any of the stairs, up,
+    // down or into another branch
+    tile.blocked = true;
+    let is_stairs =
+        |object: &Object| object.name == "upstairs" || game.branch.below(&object.name).is_some();
+    let cut_off = objects.iter().filter(|object| is_stairs(object)).any(|stairs| {
+        !is_reachable(&game.map, objects[PLAYER].pos(), stairs.pos())
+    });
+    if cut_off {
+        game.map.get_mut(x, y).blocked = false;
+        return scroll_failed(inventory_id, game, "That would cut you off from the stairs.");
+    }
 
+    game.map.get_mut(x, y).block_sight = true;
+    tcod.fov.set(x, y, false, false);
+    tcod.render.map_changed();
+    game.messages.add_colored(
+        "The floor heaves as a wall of stone grinds up out of it!",
+        LIGHT_GREY,
+    );
     UseResult::UsedUp
 }
 
@@ -417,6 +1853,123 @@ fn toggle_equipment(
     UseResult::UsedAndKept
 }
 
+fn zap_lightning_wand(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    zap_wand(inventory_id, tcod, game, objects, cast_lightning)
+}
+
+fn zap_fireball_wand(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    zap_wand(inventory_id, tcod, game, objects, cast_fireball)
+}
+
+/// cast a spell from a wand, spending a charge instead of the wand itself
+fn zap_wand(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+    spell: fn(usize, &mut Tcod, &mut Game, &mut [Object]) -> UseResult,
+) -> UseResult {
+    let charges = game.inventory[inventory_id].charges.unwrap_or(0);
+    if charges <= 0 {
+        game.messages.add(
+            format!("You wave the {}, but nothing happens.", game.inventory[inventory_id].name),
+            Severity::Info,
+        );
+        return UseResult::UsedAndKept;
+    }
+    match spell(inventory_id, tcod, game, objects) {
+        UseResult::UsedUp => {
+            game.inventory[inventory_id].charges = Some(charges - 1);
+            game.stats.items_used += 1;
+            UseResult::UsedAndKept
+        }
+        result => result,
+    }
+}
+
+/// give every potion and scroll kind a random look for this game
+pub fn assign_appearances(game: &mut Game) {
+    let mut rng = rand::thread_rng();
+    let mut looks = POTION_APPEARANCES.to_vec();
+    rng.shuffle(&mut looks);
+    let mut labels = SCROLL_LABELS.to_vec();
+    rng.shuffle(&mut labels);
+    let potions = POTIONS.iter().zip(looks).map(|(&item, look)| (item, format!("{} potion", look)));
+    let scrolls = SCROLLS
+        .iter()
+        .zip(labels)
+        .map(|(&item, label)| (item, format!("scroll labeled {}", label)));
+    game.appearances = potions.chain(scrolls).collect();
+}
+
+/// name unidentified items after their appearance, along with the kinds they
+/// could be that the player learned about in earlier games. The looks are
+/// dealt out afresh each game, so that's all the lore can say: every unknown
+/// potion gets the same hint, and so does every unknown scroll
+pub fn disguise_unidentified(game: &Game, objects: &mut [Object]) {
+    let hint = |kinds: &[Item]| {
+        let unknown: Vec<&Item> =
+            kinds.iter().filter(|item| !game.identified.contains(item)).collect();
+        let mut guesses: Vec<&str> = unknown
+            .iter()
+            .filter(|item| game.lore.identified.contains(item))
+            .map(|item| item.name())
+            .collect();
+        // none, or too many to narrow anything down
+        if guesses.is_empty() || guesses.len() > MAX_GUESSES {
+            return String::new();
+        }
+        if guesses.len() < unknown.len() {
+            guesses.push("something new");
+        }
+        format!(" (one of: {})", guesses.join(", "))
+    };
+    let (potion_hint, scroll_hint) = (hint(POTIONS), hint(SCROLLS));
+    for object in objects.iter_mut() {
+        if let Some(item) = object.item {
+            if !game.identified.contains(&item) {
+                if let Some((_, look)) = game.appearances.iter().find(|(kind, _)| *kind == item) {
+                    let hint = if item.is_scroll() { &scroll_hint } else { &potion_hint };
+                    object.name = format!("{}{}", look, hint);
+                }
+            }
+        }
+    }
+}
+
+/// learn what a kind of item is, revealing the real name of every one of them
+pub fn identify(item: Item, game: &mut Game, objects: &mut [Object]) {
+    if game.identified.contains(&item) {
+        return;
+    }
+    game.identified.push(item);
+    game.lore.learn(item);
+    let known = |object: &mut Object| {
+        if object.item == Some(item) {
+            object.name = item.name().into();
+        }
+    };
+    game.inventory.iter_mut().for_each(known);
+    objects.iter_mut().for_each(known);
+    // one less thing the rest could be
+    let mut inventory = std::mem::take(&mut game.inventory);
+    disguise_unidentified(game, &mut inventory);
+    game.inventory = inventory;
+    disguise_unidentified(game, objects);
+    game.messages
+        .add(format!("It was a {}!", item.name()), Severity::Info);
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 /// an object that can be equipped, yielding bonuses.
 pub struct Equipment {
@@ -425,6 +1978,10 @@ pub struct Equipment {
     pub power_bonus: i32,
     pub defense_bonus: i32,
     pub max_hp_bonus: i32,
+    /// how many tiles away its wielder can hit, in a straight line
+    pub reach: i32,
+    /// what a hit with it leaves on the target, like poison
+    pub on_hit: Option<Status>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
@@ -442,4 +1999,306 @@ impl std::fmt::Display for Slot {
             Slot::Head => write!(f, "head"),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An FOV that sees exactly the tiles it's given
+    struct StubFov(Vec<(i32, i32)>);
+
+    impl Visibility for StubFov {
+        fn is_in_fov(&self, x: i32, y: i32) -> bool {
+            self.0.contains(&(x, y))
+        }
+    }
+
+    /// the player at (10, 10) and an orc at the given spot, on an open map
+    fn setup(orc_x: i32, orc_y: i32) -> (Game, Vec<Object>) {
+        let player = Object::new(10, 10, '@', "player", WHITE, true);
+        let mut orc = Object::new(orc_x, orc_y, 'o', "orc", DESATURATED_GREEN, true);
+        orc.alive = true;
+        orc.fighter = Some(Fighter {
+            base_max_hp: 20,
+            hp: 20,
+            base_defense: 0,
+            base_power: 4,
+            xp: 35,
+            on_death: DeathCallback::Monster,
+        });
+        orc.ai = Some(Ai::Basic);
+        test_game(Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::empty()), vec![player, orc])
+    }
+
+    #[test]
+    fn monster_in_fov_moves_closer() {
+        let (mut game, mut objects) = setup(15, 10);
+        let fov = StubFov(vec![(15, 10)]);
+        ai_take_turn(1, &fov, &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (14, 10));
+        assert!(matches!(objects[1].ai, Some(Ai::Hunting { last_seen: (10, 10), .. })));
+    }
+
+    #[test]
+    fn monster_adjacent_attacks() {
+        let (mut game, mut objects) = setup(11, 10);
+        let hp = objects[PLAYER].fighter.unwrap().hp;
+        let fov = StubFov(vec![(11, 10)]);
+        ai_take_turn(1, &fov, &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (11, 10));
+        let damage = 4 - objects[PLAYER].defense(&game);
+        assert_eq!(objects[PLAYER].fighter.unwrap().hp, hp - damage);
+    }
+
+    #[test]
+    fn monster_out_of_fov_idles() {
+        let (mut game, mut objects) = setup(15, 10);
+        let hp = objects[PLAYER].fighter.unwrap().hp;
+        let fov = StubFov(vec![]);
+        ai_take_turn(1, &fov, &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (15, 10));
+        assert_eq!(objects[PLAYER].fighter.unwrap().hp, hp);
+        assert!(matches!(objects[1].ai, Some(Ai::Basic)));
+    }
+
+    #[test]
+    fn slow_monster_notices_between_moves() {
+        let (mut game, mut objects) = setup(15, 10);
+        objects[1].speed = Speed::Slow;
+        let fov = StubFov(vec![(15, 10), (14, 10)]);
+        // an even turn is one it sits out, but it still spots the player
+        game.stats.turns = 2;
+        monster_turn(1, &fov, &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (15, 10));
+        assert!(matches!(objects[1].ai, Some(Ai::Hunting { last_seen: (10, 10), .. })));
+        game.stats.turns = 3;
+        monster_turn(1, &fov, &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (14, 10));
+    }
+
+    #[test]
+    fn overlapping_blasts_pay_out_once() {
+        let (mut game, mut objects) = setup(12, 10);
+        objects[PLAYER].set_pos(30, 30);
+        game.positions.rebuild(&objects);
+        // the orc stands in oil, so the blast and the fire both reach it
+        game.hazards.push(Hazard {
+            kind: HazardKind::Oil,
+            pos: (12, 10),
+            turns: OIL_NUM_TURNS,
+        });
+        fireball_blast(12, 10, &mut game, &mut objects);
+        fireball_blast(11, 10, &mut game, &mut objects);
+        assert!(!objects[1].alive);
+        assert_eq!(objects[PLAYER].fighter.unwrap().xp, 35);
+        // and a blow at what's left of it is worth nothing
+        assert_eq!(objects[1].take_damage(10, &mut game), None);
+    }
+
+    #[test]
+    fn hunt_ends_in_the_monster_s_own_ai() {
+        let (mut game, mut objects) = setup(15, 10);
+        let hunting = |num_turns| Ai::Hunting {
+            last_seen: (12, 10),
+            num_turns,
+            then: Box::new(Ai::Ranged { range: 8 }),
+        };
+        // out of sight it heads for where the player was
+        objects[1].ai = Some(hunting(3));
+        ai_take_turn(1, &StubFov(vec![]), &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (14, 10));
+        // and with the player in sight again, it shoots rather than closes in
+        objects[1].ai = Some(hunting(3));
+        let hp = objects[PLAYER].fighter.unwrap().hp;
+        ai_take_turn(1, &StubFov(vec![(14, 10)]), &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (14, 10));
+        assert!(objects[PLAYER].fighter.unwrap().hp < hp);
+        assert_eq!(objects[1].ai, Some(Ai::Ranged { range: 8 }));
+        // giving up hands it back too
+        objects[1].ai = Some(hunting(0));
+        ai_take_turn(1, &StubFov(vec![]), &mut game, &mut objects);
+        assert_eq!(objects[1].ai, Some(Ai::Ranged { range: 8 }));
+    }
+
+    #[test]
+    fn brains_come_back_from_a_save() {
+        let guard = Ai::Custom(Brain(Box::new(Guard {
+            post: (20, 10),
+            then: Ai::Ranged { range: 6 },
+        })));
+        let brains = vec![
+            guard.clone(),
+            Ai::Custom(Brain(Box::new(Shaman))),
+            Ai::Confused {
+                previous_ai: Box::new(guard),
+                last_turn: 12,
+            },
+        ];
+        for ai in brains {
+            let saved = serde_json::to_string(&ai).unwrap();
+            let loaded: Ai = serde_json::from_str(&saved).unwrap();
+            assert_eq!(loaded, ai);
+        }
+
+        // and the loaded guard still knows its post
+        let (mut game, mut objects) = setup(15, 10);
+        let saved = r#"{"Custom":{"kind":"guard","state":{"post":[20,10],"then":"Basic"}}}"#;
+        objects[1].ai = Some(serde_json::from_str(saved).unwrap());
+        ai_take_turn(1, &StubFov(vec![]), &mut game, &mut objects);
+        assert_eq!(objects[1].pos(), (16, 10));
+
+        let unknown = r#"{"Custom":{"kind":"lich","state":null}}"#;
+        assert!(serde_json::from_str::<Ai>(unknown).is_err());
+    }
+
+    #[test]
+    fn unknown_scroll_with_nothing_to_do_is_used_up() {
+        // the orc is out of sight, so a lightning bolt has nothing to strike
+        let (mut game, mut objects) = setup(40, 40);
+        let mut tcod = Tcod::new(Box::new(NullRenderer::default()));
+        let scroll = || {
+            let mut scroll = Object::new(0, 0, '#', "scroll of lightning bolt", WHITE, false);
+            scroll.item = Some(Item::Lightning);
+            scroll
+        };
+        let said = |game: &Game, text: &str| game.messages.iter().any(|m| m.text.contains(text));
+
+        game.inventory.push(scroll());
+        use_item(0, &mut tcod, &mut game, &mut objects);
+        assert!(game.inventory.is_empty());
+        assert!(game.identified.contains(&Item::Lightning));
+        assert!(!said(&game, "No enemy"));
+
+        // known for what it is, the next one can be put away again
+        game.inventory.push(scroll());
+        use_item(0, &mut tcod, &mut game, &mut objects);
+        assert_eq!(game.inventory.len(), 1);
+        assert!(said(&game, "No enemy"));
+    }
+
+    /// a scroll of genocide the player has read before
+    fn known_genocide(game: &mut Game) {
+        let mut scroll = Object::new(0, 0, '#', "scroll of genocide", WHITE, false);
+        scroll.item = Some(Item::Genocide);
+        game.inventory.push(scroll);
+        game.identified.push(Item::Genocide);
+    }
+
+    #[test]
+    fn genocide_spares_the_players_pets() {
+        let (mut game, mut objects) = setup(15, 10);
+        // a tame orc and a dog, neither of them up for wiping out
+        for &(x, name) in &[(11, "orc"), (9, "dog")] {
+            let mut pet = Object::new(x, 10, 'p', name, WHITE, true);
+            pet.alive = true;
+            pet.ally = true;
+            pet.fighter = objects[1].fighter;
+            pet.ai = Some(Ai::Pet);
+            objects.push(pet);
+        }
+        known_genocide(&mut game);
+        // the first and only kind on offer is the orc
+        let mut tcod = Tcod::new(Box::new(NullRenderer::typing("a")));
+        use_item(0, &mut tcod, &mut game, &mut objects);
+        assert!(game.inventory.is_empty());
+        assert!(!objects[1].alive);
+        assert!(objects[2].alive && objects[2].ai.is_some());
+        assert!(objects[3].alive && objects[3].ai.is_some());
+        assert_eq!(game.genocided, vec!["orc".to_string()]);
+    }
+
+    #[test]
+    fn genocide_refuses_a_unique_monster() {
+        let (mut game, mut objects) = setup(15, 10);
+        objects[1].unique = true;
+        known_genocide(&mut game);
+        let mut tcod = Tcod::new(Box::new(NullRenderer::typing("a")));
+        use_item(0, &mut tcod, &mut game, &mut objects);
+        // the scroll is put away again, and the orc lives on
+        assert_eq!(game.inventory.len(), 1);
+        assert!(objects[1].alive && objects[1].ai.is_some());
+        assert!(game.genocided.is_empty());
+        assert!(game.messages.iter().any(|m| m.text.contains("too mighty")));
+    }
+
+    #[test]
+    fn unknown_recharge_scroll_keeps_its_secret() {
+        let (mut game, mut objects) = setup(15, 10);
+        let mut tcod = Tcod::new(Box::new(NullRenderer::default()));
+        let said = |game: &Game, text: &str| game.messages.iter().any(|m| m.text.contains(text));
+
+        // with nothing to recharge, an unknown scroll is spent for nothing
+        game.inventory.push(create_item(Item::Recharge, 0, 0));
+        use_item(0, &mut tcod, &mut game, &mut objects);
+        assert!(game.inventory.is_empty());
+        assert!(!said(&game, "nothing to recharge"));
+
+        // and so is one read at a full wand, once the menu is passed
+        game.identified.clear();
+        game.inventory.push(create_item(Item::LightningWand, 0, 0));
+        game.inventory.push(create_item(Item::Recharge, 0, 0));
+        tcod.renderer = Box::new(NullRenderer::typing("a"));
+        use_item(1, &mut tcod, &mut game, &mut objects);
+        assert_eq!(game.inventory.len(), 1);
+        assert!(!said(&game, "fully charged"));
+
+        // a known one says why, and is put away again
+        game.inventory.push(create_item(Item::Recharge, 0, 0));
+        tcod.renderer = Box::new(NullRenderer::typing("a"));
+        use_item(1, &mut tcod, &mut game, &mut objects);
+        assert_eq!(game.inventory.len(), 2);
+        assert!(said(&game, "fully charged"));
+    }
+
+    #[test]
+    fn four_way_monster_steps_round_instead_of_hitting_diagonally() {
+        let (mut game, mut objects) = setup(11, 11);
+        game.settings.movement = MovementMode::FourWay;
+        let hp = objects[PLAYER].fighter.unwrap().hp;
+        let fov = StubFov(vec![(11, 11)]);
+        ai_take_turn(1, &fov, &mut game, &mut objects);
+        assert_eq!(objects[PLAYER].fighter.unwrap().hp, hp);
+        assert!([(10, 11), (11, 10)].contains(&objects[1].pos()));
+
+        // once it's beside the player, it attacks
+        let fov = StubFov(vec![objects[1].pos()]);
+        ai_take_turn(1, &fov, &mut game, &mut objects);
+        assert!(objects[PLAYER].fighter.unwrap().hp < hp);
+    }
+
+    #[test]
+    fn line_of_fire_shown_only_stops_at_what_the_player_sees() {
+        let (game, objects) = setup(15, 10);
+        // the orc is out of sight, so the line runs on past it
+        let path = seen_line_of_fire((10, 10), (20, 10), &StubFov(vec![]), &game, &objects);
+        assert_eq!(path.last(), Some(&(20, 10)));
+        // while a real shot would still hit it
+        let path = line_of_fire((10, 10), (20, 10), &game, &objects);
+        assert_eq!(path.last(), Some(&(15, 10)));
+        // and once it's seen, the line shown stops there too
+        let fov = StubFov(vec![(15, 10)]);
+        let path = seen_line_of_fire((10, 10), (20, 10), &fov, &game, &objects);
+        assert_eq!(path.last(), Some(&(15, 10)));
+    }
+
+    #[test]
+    fn lore_hint_lists_what_an_unknown_item_could_be() {
+        let (mut game, _) = setup(15, 10);
+        assign_appearances(&mut game);
+        // healing potions were found out in an earlier game
+        game.lore.identified.push(Item::Heal);
+        let mut items = vec![
+            create_item(Item::Heal, 0, 0),
+            create_item(Item::Poison, 0, 0),
+            create_item(Item::Confuse, 0, 0),
+        ];
+        disguise_unidentified(&game, &mut items);
+        // any unknown potion could be either, and the lore can't say which
+        let hint = format!(" (one of: {}, something new)", Item::Heal.name());
+        assert!(items[0].name.ends_with(&hint), "{}", items[0].name);
+        assert!(items[1].name.ends_with(&hint), "{}", items[1].name);
+        // and there's nothing to go on for scrolls
+        assert!(!items[2].name.contains('('), "{}", items[2].name);
+    }
+}
@@ -0,0 +1,108 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::*;
+use crate::game::*;
+use crate::object::*;
+
+/// Something that happened in the game, for plugins to react to
+#[derive(Clone, Debug, PartialEq)]
+pub enum GameEvent {
+    /// a monster turned up on the level after it was made, at this index in
+    /// the objects
+    Spawn(usize),
+    /// something with a fighter died; what it was called and where it fell
+    Death { name: String, pos: (i32, i32) },
+    /// the player took a turn, and everything else has had its go
+    Turn,
+}
+
+/// Game logic that lives outside the core: reacting to events and giving
+/// items new effects. Add it to `PLUGINS` to switch it on; monster behaviors
+/// go in as a `MonsterBrain` instead.
+///
+/// A plugin can change the game and its objects as it likes, but anything it
+/// wants to keep has to be in there too, so it's saved with the game. Moving
+/// objects around should go through `move_object`. Errors and panics are
+/// caught and shown as warnings, so a broken plugin can't take the game down.
+pub trait Plugin: Sync {
+    fn name(&self) -> &'static str;
+
+    fn on_event(
+        &self,
+        _event: &GameEvent,
+        _game: &mut Game,
+        _objects: &mut Vec<Object>,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// use the item in the given inventory slot instead of the usual effect.
+    /// `None` leaves it to the game
+    fn use_item(
+        &self,
+        _item: Item,
+        _inventory_id: usize,
+        _tcod: &mut Tcod,
+        _game: &mut Game,
+        _objects: &mut Vec<Object>,
+    ) -> Result<Option<UseResult>, String> {
+        Ok(None)
+    }
+}
+
+/// Every plugin that's switched on, in the order they get to act
+pub const PLUGINS: &[&dyn Plugin] = &[];
+
+/// let every plugin know about everything that happened since the last call
+pub fn run_plugins(game: &mut Game, objects: &mut Vec<Object>) {
+    let events = std::mem::take(&mut game.events);
+    for event in &events {
+        for plugin in PLUGINS {
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(|| plugin.on_event(event, game, objects)));
+            contain(*plugin, result, game, objects);
+        }
+    }
+}
+
+/// the first plugin's take on using an item, if any of them has one
+pub fn plugin_use_item(
+    item: Item,
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> Option<UseResult> {
+    for plugin in PLUGINS {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            plugin.use_item(item, inventory_id, tcod, game, objects)
+        }));
+        if let Some(Some(use_result)) = contain(*plugin, result, game, objects) {
+            return Some(use_result);
+        }
+    }
+    None
+}
+
+/// turn whatever went wrong in a plugin into a warning
+fn contain<T>(
+    plugin: &dyn Plugin,
+    result: std::thread::Result<Result<T, String>>,
+    game: &mut Game,
+    objects: &[Object],
+) -> Option<T> {
+    let error = match result {
+        Ok(Ok(value)) => return Some(value),
+        Ok(Err(error)) => error,
+        Err(_) => {
+            // it may have stopped halfway through moving something
+            game.positions.rebuild(objects);
+            "it crashed".to_string()
+        }
+    };
+    game.messages.add(
+        format!("The {} plugin failed: {}", plugin.name(), error),
+        Severity::Warning,
+    );
+    None
+}
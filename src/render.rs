@@ -1,267 +1,857 @@
 use crate::*;
 use crate::game::*;
 
-pub fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recompute: bool) {
-    if fov_recompute {
-        // recompute FOV if needed (the player moved or something)
-        let player = &objects[PLAYER];
-        tcod.fov.compute_fov(
-            player.x,
-            player.y,
-            TORCH_RADIUS,
-            FOV_LIGHT_WALLS,
-            FOV_ALGO)
-    }
-    // go through all tiles, and set their background color
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            let visible = tcod.fov.is_in_fov(x, y);
-            let wall = game.map[x as usize][y as usize].block_sight;
-            let color = match(visible, wall) {
-                // outside of FOV
-                (false, true) => COLOR_DARK_WALL,
-                (false, false) => COLOR_DARK_GROUND,
-                // inside of FOV
-                (true, true) => COLOR_LIGHT_WALL,
-                (true, false) => COLOR_LIGHT_GROUND,
-            };
-            let explored = &mut game.map[x as usize][y as usize].explored;
-            if visible {
-                // since it's visible, explore it
-                *explored = true;
+/// What `render_all` remembers between frames, so that it only redraws what
+/// may have changed and doesn't allocate every frame
+pub struct RenderCache {
+    /// redraw every tile on the next frame, e.g. after the map changed
+    pub redraw_map: bool,
+    /// recompute the FOV even if the player hasn't moved
+    pub fov_dirty: bool,
+    /// where the player was when the FOV was last computed
+    fov_origin: (i32, i32),
+    /// the FOV changed since tiles were last drawn
+    relight: bool,
+    /// tiles that were lit when the FOV was last computed
+    lit: Vec<(i32, i32)>,
+    /// tiles that had a glyph drawn on them last frame
+    drawn: Vec<(i32, i32)>,
+    /// tiles explored recently enough to still be fading in, and since when
+    revealing: Vec<((i32, i32), Instant)>,
+    /// ids of the objects to draw this frame
+    visible: Vec<usize>,
+    /// tiles with several items: how many, and the id of the best one
+    piles: HashMap<(i32, i32), (usize, usize)>,
+    /// how long the last call to `render_all` took
+    pub frame_time: Duration,
+    /// a tile to highlight, like the monster being aimed at
+    pub target: Option<(i32, i32)>,
+    /// also show the line a shot at the target would take, and what stops it
+    pub line_of_fire: bool,
+    /// the tiles highlighted last frame, which need their own look back
+    highlighted: Vec<(i32, i32)>,
+}
+
+impl RenderCache {
+    pub fn new() -> Self {
+        RenderCache {
+            redraw_map: true,
+            fov_dirty: true,
+            fov_origin: (-1, -1),
+            relight: false,
+            lit: vec![],
+            drawn: vec![],
+            revealing: vec![],
+            visible: vec![],
+            piles: HashMap::new(),
+            frame_time: Duration::default(),
+            target: None,
+            line_of_fire: false,
+            highlighted: vec![],
+        }
+    }
+
+    /// The map changed (a wall was raised, say), so both what the player can
+    /// see and how every tile looks have to be worked out again. Anything else
+    /// that changes what's visible should set `fov_dirty`.
+    pub fn map_changed(&mut self) {
+        self.fov_dirty = true;
+        self.redraw_map = true;
+    }
+}
+
+impl Default for RenderCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// the background color of an explored tile, lit or not
+fn tile_color(fov: &FovMap, map: &Map, x: i32, y: i32) -> Color {
+    let visible = fov.is_in_fov(x, y);
+    let wall = map.get(x, y).block_sight;
+    match(visible, wall) {
+        // outside of FOV
+        (false, true) => COLOR_DARK_WALL,
+        (false, false) => COLOR_DARK_GROUND,
+        // inside of FOV
+        (true, true) => COLOR_LIGHT_WALL,
+        (true, false) => COLOR_LIGHT_GROUND,
+    }
+}
+
+/// set a tile's background color, exploring it if it's in view. tiles
+/// explored just now are added to `revealing`
+fn draw_tile(
+    renderer: &mut dyn Renderer,
+    fov: &FovMap,
+    map: &mut Map,
+    revealing: &mut Vec<((i32, i32), Instant)>,
+    x: i32,
+    y: i32,
+) {
+    let color = tile_color(fov, map, x, y);
+    let explored = &mut map.get_mut(x, y).explored;
+    if fov.is_in_fov(x, y) && !*explored {
+        // since it's visible, explore it
+        *explored = true;
+        revealing.push(((x, y), Instant::now()));
+    }
+    if *explored {
+        // show explored tiles only (any visible tile is explored already)
+        renderer.set_background(Layer::Map, x, y, color);
+    }
+}
+
+pub fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    update_fov(tcod, objects);
+
+    let cache = &mut tcod.render;
+    let revealing = &mut cache.revealing;
+    if cache.redraw_map {
+        // start from a blank slate and go through all tiles
+        tcod.renderer.clear(Layer::Map);
+        cache.lit.clear();
+        cache.drawn.clear();
+        for y in 0..MAP_HEIGHT {
+            for x in 0..MAP_WIDTH {
+                draw_tile(&mut *tcod.renderer, &tcod.fov, &mut game.map, revealing, x, y);
+                if tcod.fov.is_in_fov(x, y) {
+                    cache.lit.push((x, y));
+                }
             }
-            if *explored {
-                // show explored tiles only (any visible tile is explored already)
-                tcod.con.set_char_background(x, y, color, BackgroundFlag::Set);
+        }
+        cache.redraw_map = false;
+        cache.relight = false;
+    } else if cache.relight {
+        // only the tiles lit before and the ones lit now can look any different
+        for &(x, y) in &cache.lit {
+            draw_tile(&mut *tcod.renderer, &tcod.fov, &mut game.map, revealing, x, y);
+        }
+        cache.lit.clear();
+        // a radius of 0 means the light reaches as far as it can see
+        let radius = if TORCH_RADIUS > 0 { TORCH_RADIUS } else { cmp::max(MAP_WIDTH, MAP_HEIGHT) };
+        let (player_x, player_y) = objects[PLAYER].pos();
+        for y in cmp::max(0, player_y - radius)..cmp::min(MAP_HEIGHT, player_y + radius + 1) {
+            for x in cmp::max(0, player_x - radius)..cmp::min(MAP_WIDTH, player_x + radius + 1) {
+                if tcod.fov.is_in_fov(x, y) {
+                    draw_tile(&mut *tcod.renderer, &tcod.fov, &mut game.map, revealing, x, y);
+                    cache.lit.push((x, y));
+                }
             }
         }
+        cache.relight = false;
     }
 
-    let mut to_draw: Vec<_> = objects
-        .iter()
-        .filter(|o| {
-            tcod.fov.is_in_fov(o.x, o.y)
-                || (o.always_visible && game.map[o.x as usize][o.y as usize].explored)
-        })
-        .collect();
-    // sort so that non-blocking objects come first
-    to_draw.sort_by(|o1, o2| o1.blocks.cmp(&o2.blocks));
-    // draw the objects in the list
-    for object in &to_draw {
-        object.draw(&mut tcod.con);
+    // newly explored tiles fade in from black. they're explored all the same,
+    // this only changes how they look for a moment
+    if game.settings.reveal_animation {
+        let duration = game.settings.animation_millis(REVEAL_MILLIS).max(1);
+        let (fov, map) = (&tcod.fov, &game.map);
+        let renderer = &mut *tcod.renderer;
+        cache.revealing.retain(|&((x, y), since)| {
+            let elapsed = since.elapsed().as_millis();
+            let brightness = (elapsed as f32 / duration as f32).min(1.0);
+            let color = lerp(BLACK, tile_color(fov, map, x, y), brightness);
+            renderer.set_background(Layer::Map, x, y, color);
+            elapsed < duration
+        });
+    } else {
+        cache.revealing.clear();
     }
 
-    // prepare to rerender the GUI panel
-    tcod.panel.set_default_background(BLACK);
-    tcod.panel.clear();
+    // put back the tiles under last frame's highlight, then mark the target
+    for (x, y) in cache.highlighted.drain(..) {
+        draw_tile(&mut *tcod.renderer, &tcod.fov, &mut game.map, &mut cache.revealing, x, y);
+    }
+    if let Some(target) = cache.target {
+        if cache.line_of_fire {
+            // the line ends on whatever in view the shot would hit: red for
+            // a creature, orange for a wall, or the target itself if it's clear
+            let player = objects[PLAYER].pos();
+            let path = seen_line_of_fire(player, target, &tcod.fov, game, objects);
+            for (index, &(x, y)) in path.iter().enumerate() {
+                let seen = tcod.fov.is_in_fov(x, y);
+                let color = if index + 1 < path.len() {
+                    DARKER_YELLOW
+                } else if seen && game.map.get(x, y).blocked {
+                    DARK_ORANGE
+                } else if seen && blocks_fire(x, y, game, objects) {
+                    DARK_RED
+                } else {
+                    DARK_YELLOW
+                };
+                tcod.renderer.set_background(Layer::Map, x, y, color);
+                cache.highlighted.push((x, y));
+            }
+        } else {
+            tcod.renderer.set_background(Layer::Map, target.0, target.1, DARK_YELLOW);
+            cache.highlighted.push(target);
+        }
+    }
 
-    // print the game messages, one line at a time
-    let mut y = MSG_HEIGHT as i32;
-    for &(ref msg, color) in game.messages.iter().rev() {
-        let msg_height = tcod.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
-        y -= msg_height;
-        if y < 0 {
-            break;
+    // wipe last frame's glyphs, leaving the tiles' backgrounds alone
+    for &(x, y) in &cache.drawn {
+        tcod.renderer.put_char(Layer::Map, x, y, ' ', BLACK);
+    }
+    cache.drawn.clear();
+
+    // hazards lie underneath anything else on their tiles
+    for hazard in &game.hazards {
+        let (x, y) = hazard.pos;
+        if tcod.fov.is_in_fov(x, y) {
+            tcod.renderer.put_char(Layer::Map, x, y, hazard.kind.char(), hazard.kind.color());
+            cache.drawn.push((x, y));
         }
-        tcod.panel.set_default_foreground(color);
-        tcod.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
     }
 
+    let fov = &tcod.fov;
+    cache.visible.clear();
+    cache.visible.extend(
+        (0..objects.len()).filter(|&id| is_object_visible(&objects[id], fov, &game.map)),
+    );
+
+    // tiles with more than one item are drawn as a pile, in the color of the best item
+    cache.piles.clear();
+    for &id in cache.visible.iter().filter(|&&id| objects[id].item.is_some()) {
+        let pile = cache.piles.entry(objects[id].pos()).or_insert((0, id));
+        pile.0 += 1;
+        if objects[id].item.map_or(0, Item::value) > objects[pile.1].item.map_or(0, Item::value) {
+            pile.1 = id;
+        }
+    }
+    cache.piles.retain(|_, pile| pile.0 > 1);
+    let is_stairs = |o: &Object| o.name.ends_with("stairs");
+
+    // draw the objects from the bottom up, so that whatever matters most on
+    // a tile ends up on top. The ids break ties, which keeps the order the
+    // same from frame to frame. Piles go in with the items
+    cache.visible.sort_by_key(|&id| (objects[id].render_priority(), id));
+    let mut piles_drawn = false;
+    for &id in &cache.visible {
+        let object = &objects[id];
+        if !piles_drawn && object.render_priority() > RenderPriority::Item {
+            let renderer = &mut *tcod.renderer;
+            draw_piles(renderer, &cache.piles, &cache.visible, objects, &mut cache.drawn);
+            piles_drawn = true;
+        }
+        let in_pile =
+            cache.piles.contains_key(&object.pos()) && (object.item.is_some() || is_stairs(object));
+        if !in_pile {
+            object.draw(&mut *tcod.renderer);
+            cache.drawn.push(object.pos());
+        }
+    }
+    if !piles_drawn {
+        draw_piles(&mut *tcod.renderer, &cache.piles, &cache.visible, objects, &mut cache.drawn);
+    }
+
+    // prepare to rerender the GUI panel
+    tcod.renderer.clear(Layer::Panel);
+
+    // print the game messages, one line at a time
+    render_messages(&mut *tcod.renderer, game.messages.iter());
+
     // show the player's stats
     let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
     let max_hp = objects[PLAYER].max_hp(game);
+    // flash the HP bar while the player's health is critically low
+    let flash = is_low_hp(&objects[PLAYER], game)
+        && backend::elapsed_time().as_millis()
+            / game.settings.animation_millis(HP_FLASH_MILLIS)
+            % 2
+            == 1;
+    let (bar_color, back_color) = if flash {
+        (ORANGE, DARKEST_RED)
+    } else {
+        (LIGHT_RED, DARKER_RED)
+    };
     render_bar(
-        &mut tcod.panel,
+        &mut *tcod.renderer,
         1,
         1,
         BAR_WIDTH,
         "HP",
         hp,
         max_hp,
-        LIGHT_RED,
-        DARKER_RED,
+        bar_color,
+        back_color,
+        game.settings.bar_numbers,
     );
 
-    tcod.panel.print_ex(
+    // and how far along the player is to the next level
+    let xp = objects[PLAYER].fighter.map_or(0, |f| f.xp);
+    let level_up_xp = game.settings.xp_curve.level_up_xp(objects[PLAYER].level);
+    render_bar(
+        &mut *tcod.renderer,
         1,
-        3,
-        BackgroundFlag::None,
-        TextAlignment::Left,
-        format!("Dungeon level: {}", game.dungeon_level),
+        2,
+        BAR_WIDTH,
+        "XP",
+        xp,
+        level_up_xp,
+        LIGHT_VIOLET,
+        DARKER_VIOLET,
+        game.settings.bar_numbers,
     );
+    if game.shield.is_up() {
+        // whatever the shield has left, at the end of the HP bar
+        let text = format!("+{}", game.shield.points);
+        tcod.renderer.print(Layer::Panel, BAR_WIDTH, 1, Align::Right, LIGHT_BLUE, &text);
+    }
 
-    // display names of objects under the mouse
-    tcod.panel.set_default_foreground(LIGHT_GREY);
-    tcod.panel.print_ex(
+    tcod.renderer.print(
+        Layer::Panel,
         1,
-        0,
-        BackgroundFlag::None,
-        TextAlignment::Left,
-        get_names_under_mouse(tcod.mouse, objects, &tcod.fov),
+        3,
+        Align::Left,
+        WHITE,
+        &if game.dungeon_level == TOWN_LEVEL {
+            "Town".to_string()
+        } else {
+            format!("{} level: {}", game.branch.name(), game.dungeon_level)
+        },
     );
 
-    // blit the contents of "panel" to the root console
-    blit(
-        &tcod.panel,
-        (0, 0),
-        (SCREEN_WIDTH, PANEL_HEIGHT),
-        &mut tcod.root,
-        (0, PANEL_Y),
-        1.0,
-        1.0,
-    );
+    if game.settings.show_compass {
+        let text = compass(game, objects);
+        tcod.renderer.print(Layer::Panel, 1, 4, Align::Left, LIGHT_GREY, &text);
+    }
 
-    // blit the contents of "con" to the root console and present it
-    blit(
-        &tcod.con,
-        (0, 0),
-        (MAP_WIDTH, MAP_HEIGHT),
-        &mut tcod.root,
-        (0, 0),
-        1.0,
-        1.0,
-    );
-}
+    if game.settings.show_turn_order {
+        let order = turn_order(game, objects, &tcod.render.visible);
+        tcod.renderer.print_wrapped(Layer::Panel, 1, 5, BAR_WIDTH, LIGHT_GREY, &order);
+    }
 
-pub fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root) -> Option<usize> {
-    assert!(options.len() <= 26, "Cannot have a menu with more than 26 options.");
+    if game.settings.show_frame_time {
+        tcod.renderer.print(
+            Layer::Panel,
+            SCREEN_WIDTH - 1,
+            PANEL_HEIGHT - 1,
+            Align::Right,
+            WHITE,
+            &format!("{:.2} ms", tcod.render.frame_time.as_secs_f64() * 1000.0),
+        );
+    }
 
-    // calculate total height for the header (after auto-wrap) and one line per option
-    let header_height = if header.is_empty() {
-        0
+    // display names of objects under the mouse, or else what the player is
+    // standing on
+    let names = get_names_under_mouse(tcod.mouse, objects, &tcod.fov, &game.map);
+    if names.is_empty() {
+        if let Some(text) = items_here(game, objects) {
+            tcod.renderer.print(Layer::Panel, 1, 0, Align::Left, LIGHT_YELLOW, &text);
+        }
     } else {
-        root.get_height_rect(0, 0, width, SCREEN_HEIGHT, header)
-    };
-    let height = options.len() as i32 + header_height;
-
-    // create an off-screen console that represents the menu's window
-    let mut window = Offscreen::new(width, height);
-
-    // print the header, with auto-wrap
-    window.set_default_foreground(WHITE);
-    window.print_rect_ex(
-        0,
-        0,
-        width,
-        height,
-        BackgroundFlag::None,
-        TextAlignment::Left,
-        header,
-    );
+        tcod.renderer.print(Layer::Panel, 1, 0, Align::Left, LIGHT_GREY, &names);
+    }
 
-    // print all the options
-    for (index, option_text) in options.iter().enumerate() {
-        let menu_letter = (b'a' + index as u8) as char;
-        let text = format!("({}) {}", menu_letter, option_text.as_ref());
-        window.print_ex(
-            0,
-            header_height + index as i32,
-            BackgroundFlag::None,
-            TextAlignment::Left,
-            text,
-        );
+    // put the panel and the map on the screen
+    tcod.renderer.blit(Layer::Panel, 0, PANEL_Y);
+    tcod.renderer.blit(Layer::Map, 0, 0);
+}
+
+/// draw each pile of items as a single glyph, in the color of its best item
+fn draw_piles(
+    renderer: &mut dyn Renderer,
+    piles: &HashMap<(i32, i32), (usize, usize)>,
+    visible: &[usize],
+    objects: &[Object],
+    drawn: &mut Vec<(i32, i32)>,
+) {
+    for (&(x, y), &(_, best)) in piles {
+        // a pile on the stairs keeps the stairs glyph so the way down isn't hidden
+        let glyph = visible
+            .iter()
+            .map(|&id| &objects[id])
+            .find(|o| o.pos() == (x, y) && o.name.ends_with("stairs"))
+            .map_or(PILE_CHAR, |stairs| stairs.char);
+        renderer.put_char(Layer::Map, x, y, glyph, objects[best].color);
+        drawn.push((x, y));
     }
+}
 
-    // blit the contents of "window" to the root console
-    let x = SCREEN_WIDTH / 2 - width / 2;
-    let y = SCREEN_HEIGHT / 2 - width / 2;
-    blit(&window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
+/// recompute FOV if needed (the player moved or something)
+pub fn update_fov(tcod: &mut Tcod, objects: &[Object]) {
+    let player_pos = objects[PLAYER].pos();
+    if tcod.render.fov_dirty || tcod.render.fov_origin != player_pos {
+        recompute_fov(tcod, objects);
+        tcod.render.fov_dirty = false;
+        tcod.render.fov_origin = player_pos;
+        tcod.render.relight = true;
+    }
+}
 
-    // present the root console to the player and wait for keypress
-    root.flush();
-    let key = root.wait_for_keypress(true);
+/// recompute FOV around the player
+pub fn recompute_fov(tcod: &mut Tcod, objects: &[Object]) {
+    let player = &objects[PLAYER];
+    tcod.fov.compute_fov(
+        player.x,
+        player.y,
+        TORCH_RADIUS,
+        FOV_LIGHT_WALLS,
+        FOV_ALGO)
+}
 
-    // convert the ASCII code to an index; if it corresponds to an option, return it
-    if key.printable.is_alphabetic() {
-        let index = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
-        if index < options.len() {
-            Some(index)
-        } else {
-            None
+/// print the given messages to the panel, newest at the bottom
+fn render_messages<'a>(
+    renderer: &mut dyn Renderer,
+    messages: impl DoubleEndedIterator<Item = &'a Message>,
+) {
+    let mut y = MSG_HEIGHT as i32;
+    for msg in messages.rev() {
+        let msg_height = renderer.wrapped_height(MSG_WIDTH, &msg.text);
+        y -= msg_height;
+        if y < 0 {
+            break;
         }
-    } else {
-        None
+        // critical messages get highlighted so they can't be missed
+        if msg.severity == Severity::Critical {
+            renderer.fill(Layer::Panel, MSG_X, y, MSG_WIDTH, msg_height, DARKEST_RED);
+        }
+        renderer.print_wrapped(Layer::Panel, MSG_X, y, MSG_WIDTH, msg.color, &msg.text);
     }
 }
 
-pub fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option<usize> {
-    // how a menu with each item of the inventory as an option
-    let options = if inventory.len() == 0 {
-        vec!["Inventory is empty.".into()]
-    } else {
-        inventory
+/// show every message so far over the whole screen, newest at the bottom.
+/// Up and Down scroll by a message, PageUp and PageDown by a screenful, and
+/// Escape closes it
+pub fn message_log(messages: &Messages, renderer: &mut dyn Renderer) {
+    let messages: Vec<&Message> = messages.iter().collect();
+    let width = SCREEN_WIDTH - 2;
+    let heights: Vec<i32> =
+        messages.iter().map(|msg| renderer.wrapped_height(width, &msg.text)).collect();
+    // the lines between the title and the key help
+    let log_height = SCREEN_HEIGHT - 4;
+    // how many messages fit on screen, going up from the one at the bottom
+    let fitting = |bottom: usize| {
+        let mut used = 0;
+        heights[..bottom]
             .iter()
-            .map(|item| {
-                // show additional information, in case it's equipped
-                match item.equipment {
-                    Some(equipment) if equipment.equipped => {
-                        format!("{} (on {})", item.name, equipment.slot)
-                    }
-                    _ => item.name.clone(),
-                }
+            .rev()
+            .take_while(|&&height| {
+                used += height;
+                used <= log_height
             })
-            .collect()
+            .count()
     };
 
-    let inventory_index = menu(header, &options, INVENTORY_WIDTH, root);
+    // `bottom` is one past the newest message on screen. it can go up until
+    // the oldest message is at the top, but no further
+    let mut bottom = messages.len();
+    let scrolled_to_top = (0..=messages.len())
+        .take_while(|&bottom| fitting(bottom) == bottom)
+        .last()
+        .unwrap_or(0);
+    loop {
+        renderer.clear(Layer::Screen);
+        let title = format!("Message log ({} messages)", messages.len());
+        renderer.print(Layer::Screen, 1, 0, Align::Left, WHITE, &title);
+        let shown = fitting(bottom);
+        let mut y = 2 + log_height;
+        for index in (bottom - shown..bottom).rev() {
+            let msg = messages[index];
+            y -= heights[index];
+            // critical messages get highlighted so they can't be missed
+            if msg.severity == Severity::Critical {
+                renderer.fill(Layer::Screen, 1, y, width, heights[index], DARKEST_RED);
+            }
+            renderer.print_wrapped(Layer::Screen, 1, y, width, msg.color, &msg.text);
+        }
+        let help = "Up/Down and PageUp/PageDown to scroll, Escape to close";
+        renderer.print(Layer::Screen, 1, SCREEN_HEIGHT - 1, Align::Left, LIGHT_GREY, help);
+        renderer.blit(Layer::Screen, 0, 0);
+        renderer.flush();
+
+        match renderer.wait_for_keypress().code {
+            KeyCode::Up => bottom = cmp::max(bottom.saturating_sub(1), scrolled_to_top),
+            KeyCode::Down => bottom = cmp::min(bottom + 1, messages.len()),
+            KeyCode::PageUp => bottom = cmp::max(bottom - shown, scrolled_to_top),
+            KeyCode::PageDown => bottom = cmp::min(bottom + shown, messages.len()),
+            KeyCode::Escape => break,
+            _ => {}
+        }
+    }
+}
+
+/// if more messages arrived than fit in the panel, show them a page at a time
+/// and wait for a keypress on "--more--" between pages
+pub fn show_more_prompts(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    if !game.settings.more_prompt {
+        game.messages.mark_shown();
+        return;
+    }
+    while game.messages.unshown() > MSG_HEIGHT {
+        let end = game.messages.shown + MSG_HEIGHT;
+        render_all(tcod, game, objects);
+
+        // replace the message area with the current page
+        tcod.renderer.fill(Layer::Panel, MSG_X, 0, MSG_WIDTH, PANEL_HEIGHT, BLACK);
+        render_messages(&mut *tcod.renderer, game.messages.messages[..end].iter());
+        tcod.renderer.print(
+            Layer::Panel,
+            MSG_X,
+            MSG_HEIGHT as i32,
+            Align::Left,
+            LIGHT_GREY,
+            "--more--",
+        );
+        tcod.renderer.blit(Layer::Panel, 0, PANEL_Y);
+        tcod.renderer.flush();
+        tcod.renderer.wait_for_keypress();
+        game.messages.shown = end;
+    }
+    game.messages.mark_shown();
+}
+
+pub fn menu<T: AsRef<str>>(
+    header: &str,
+    options: &[T],
+    width: i32,
+    renderer: &mut dyn Renderer,
+) -> Option<usize> {
+    sectioned_menu(header, options, &[], width, renderer)
+}
+
+/// A menu with unlettered titles mixed in: each section is the index of the
+/// option it goes above, and the title to show there
+fn sectioned_menu<T: AsRef<str>>(
+    header: &str,
+    options: &[T],
+    sections: &[(usize, &str)],
+    width: i32,
+    renderer: &mut dyn Renderer,
+) -> Option<usize> {
+    // there's a letter for each option, so long menus are split into pages
+    let pages = cmp::max(1, options.len().div_ceil(MENU_PAGE_SIZE));
+    let mut page = 0;
+    loop {
+        let first = page * MENU_PAGE_SIZE;
+        let last = cmp::min(options.len(), first + MENU_PAGE_SIZE);
+        let page_options = &options[first..last];
+        let page_sections: Vec<_> = sections
+            .iter()
+            .filter(|&&(index, _)| index >= first && index < last)
+            .collect();
+
+        // calculate total height for the header (after auto-wrap), one line per
+        // option and section title, and a line for the page number if there's
+        // more than one page
+        let header_height = if header.is_empty() {
+            0
+        } else {
+            renderer.wrapped_height(width, header)
+        };
+        let footer_height = if pages > 1 { 1 } else { 0 };
+        let height =
+            (page_options.len() + page_sections.len()) as i32 + header_height + footer_height;
+
+        // start a blank window layer for the menu
+        renderer.open_window(width, height);
 
-    // if an item was chosen, return it
-    if inventory.len() > 0 {
-        inventory_index
+        // print the header, with auto-wrap
+        renderer.print_wrapped(Layer::Window, 0, 0, width, WHITE, header);
+
+        // print all the options on this page, with any section titles above them
+        let mut y = header_height;
+        for (index, option_text) in page_options.iter().enumerate() {
+            for &&(_, title) in page_sections.iter().filter(|s| s.0 == first + index) {
+                renderer.print(Layer::Window, 0, y, Align::Left, LIGHT_GREY, title);
+                y += 1;
+            }
+            let menu_letter = (b'a' + index as u8) as char;
+            let text = format!("({}) {}", menu_letter, option_text.as_ref());
+            renderer.print(Layer::Window, 0, y, Align::Left, WHITE, &text);
+            y += 1;
+        }
+        if pages > 1 {
+            let text = format!("Page {}/{}, < and > to turn", page + 1, pages);
+            renderer.print(Layer::Window, 0, height - 1, Align::Left, LIGHT_GREY, &text);
+        }
+
+        // put the window on the screen
+        let x = SCREEN_WIDTH / 2 - width / 2;
+        let y = SCREEN_HEIGHT / 2 - width / 2;
+        renderer.blit(Layer::Window, x, y);
+
+        // present the screen to the player and wait for keypress
+        renderer.flush();
+        let key = renderer.wait_for_keypress();
+
+        // convert the ASCII code to an index; if it corresponds to an option, return it
+        match key.code {
+            KeyCode::Char('>') if page + 1 < pages => page += 1,
+            KeyCode::Char('<') if page > 0 => page -= 1,
+            KeyCode::Char(letter) if letter.is_ascii_alphabetic() => {
+                let index = letter.to_ascii_lowercase() as usize - 'a' as usize;
+                return if index < page_options.len() {
+                    Some(first + index)
+                } else {
+                    None
+                };
+            }
+            _ => return None,
+        }
+    }
+}
+
+pub fn inventory_menu(
+    game: &Game,
+    player: &Object,
+    header: &str,
+    renderer: &mut dyn Renderer,
+) -> Option<usize> {
+    let inventory = &game.inventory;
+    // put the load above the list, when weight matters
+    let header = if game.settings.encumbrance {
+        format!(
+            "{}Weight: {}/{}\n",
+            header,
+            carried_weight(inventory),
+            player.carrying_capacity()
+        )
     } else {
-        None
+        header.to_string()
+    };
+    let header = &header;
+    if inventory.len() == 0 {
+        menu(header, &["Inventory is empty."], INVENTORY_WIDTH, renderer);
+        return None;
     }
+
+    // list what's worn first, then everything else, each under its own title
+    let (equipped, carried): (Vec<usize>, Vec<usize>) = (0..inventory.len())
+        .partition(|&id| inventory[id].equipment.map(|e| e.equipped) == Some(true));
+    let mut sections = vec![];
+    if !equipped.is_empty() {
+        sections.push((0, "Equipped:"));
+    }
+    if !carried.is_empty() {
+        sections.push((equipped.len(), "Carried:"));
+    }
+    let order: Vec<usize> = equipped.into_iter().chain(carried).collect();
+
+    let options: Vec<String> = order
+        .iter()
+        .map(|&id| {
+            let item = &inventory[id];
+            // show additional information, in case it's equipped or charged
+            match (item.equipment, item.charges) {
+                (Some(equipment), _) if equipment.equipped => {
+                    format!("{} (on {})", item.name, equipment.slot)
+                }
+                (_, Some(charges)) => format!("{} ({} charges)", item.name, charges),
+                _ => item.name.clone(),
+            }
+        })
+        .collect();
+
+    // map the chosen line back to where the item sits in the inventory
+    sectioned_menu(header, &options, &sections, INVENTORY_WIDTH, renderer)
+        .map(|index| order[index])
 }
 
-pub fn msgbox(text: &str, width: i32, root: &mut Root) {
+pub fn msgbox(text: &str, width: i32, renderer: &mut dyn Renderer) {
     let options: &[&str] = &[];
-    menu(text, options, width, root);
+    menu(text, options, width, renderer);
 }
 
-pub fn main_menu(tcod: &mut Tcod) {
-    let img = tcod::image::Image::from_file("menu_background.png")
-        .ok()
-        .expect("Background image not found");
+/// show the options, toggling whichever one is picked until the menu is cancelled
+pub fn options_menu(settings: &mut Settings, renderer: &mut dyn Renderer) {
+    let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+    loop {
+        let options = &[
+            format!("--more-- prompt: {}", on_off(settings.more_prompt)),
+            format!("Low HP warning at: {}%", settings.low_hp_threshold),
+            format!("Pause on low HP: {}", on_off(settings.low_hp_pause)),
+            format!("Auto-pickup potions and scrolls: {}", on_off(settings.auto_pickup)),
+            format!("Auto-pickup equipment: {}", on_off(settings.auto_pickup_equipment)),
+            format!("FPS limit: {}", settings.fps),
+            format!("Animation speed: {}%", settings.animation_speed),
+            format!("Level layout: {}", settings.map_generator.name()),
+            format!("Show frame time: {}", on_off(settings.show_frame_time)),
+            format!("Inventory size: {}", settings.inventory_cap),
+            format!("Show turn order: {}", on_off(settings.show_turn_order)),
+            format!("Show compass: {}", on_off(settings.show_compass)),
+            format!("Reveal animation: {}", on_off(settings.reveal_animation)),
+            format!("Movement: {}", settings.movement.name()),
+            format!("Encumbrance: {}", on_off(settings.encumbrance)),
+            format!("Experience curve: {}", settings.xp_curve.name()),
+            format!("Auto-equip on pickup: {}", settings.auto_equip.name()),
+            format!("Wandering monsters: {}", on_off(settings.wandering_monsters)),
+            format!("Town above the dungeon: {}", on_off(settings.town)),
+            format!("Numbers on bars: {}", on_off(settings.bar_numbers)),
+            format!(
+                "Confused monsters avoid hazards: {}",
+                on_off(settings.confused_avoid_hazards)
+            ),
+            "Rest interruptions...".to_string(),
+        ];
+        match menu("Options\n", options, OPTIONS_WIDTH, renderer) {
+            Some(0) => settings.more_prompt = !settings.more_prompt,
+            Some(1) => {
+                settings.low_hp_threshold =
+                    next_choice(&LOW_HP_THRESHOLD_CHOICES, settings.low_hp_threshold)
+            }
+            Some(2) => settings.low_hp_pause = !settings.low_hp_pause,
+            Some(3) => settings.auto_pickup = !settings.auto_pickup,
+            Some(4) => settings.auto_pickup_equipment = !settings.auto_pickup_equipment,
+            Some(5) => settings.fps = next_choice(&FPS_CHOICES, settings.fps),
+            Some(6) => {
+                settings.animation_speed =
+                    next_choice(&ANIMATION_SPEED_CHOICES, settings.animation_speed)
+            }
+            Some(7) => {
+                settings.map_generator = match settings.map_generator {
+                    MapGenerator::RandomRooms => MapGenerator::Bsp,
+                    MapGenerator::Bsp => MapGenerator::RandomRooms,
+                }
+            }
+            Some(8) => settings.show_frame_time = !settings.show_frame_time,
+            Some(9) => {
+                settings.inventory_cap =
+                    next_choice(&INVENTORY_CAP_CHOICES, settings.inventory_cap)
+            }
+            Some(10) => settings.show_turn_order = !settings.show_turn_order,
+            Some(11) => settings.show_compass = !settings.show_compass,
+            Some(12) => settings.reveal_animation = !settings.reveal_animation,
+            Some(13) => {
+                settings.movement = match settings.movement {
+                    MovementMode::EightWay => MovementMode::FourWay,
+                    MovementMode::FourWay => MovementMode::EightWay,
+                }
+            }
+            Some(14) => settings.encumbrance = !settings.encumbrance,
+            Some(15) => {
+                settings.xp_curve = match settings.xp_curve {
+                    XpCurve::Linear => XpCurve::Quadratic,
+                    XpCurve::Quadratic => XpCurve::Table,
+                    XpCurve::Table => XpCurve::Linear,
+                }
+            }
+            Some(16) => {
+                settings.auto_equip = match settings.auto_equip {
+                    AutoEquip::Off => AutoEquip::EmptySlots,
+                    AutoEquip::EmptySlots => AutoEquip::Prompt,
+                    AutoEquip::Prompt => AutoEquip::Off,
+                }
+            }
+            Some(17) => settings.wandering_monsters = !settings.wandering_monsters,
+            Some(18) => settings.town = !settings.town,
+            Some(19) => settings.bar_numbers = !settings.bar_numbers,
+            Some(20) => settings.confused_avoid_hazards = !settings.confused_avoid_hazards,
+            Some(21) => interrupts_menu(&mut settings.interrupts, renderer),
+            _ => break,
+        }
+    }
+}
 
-    while !tcod.root.window_closed() {
+fn interrupts_menu(interrupts: &mut Interrupts, renderer: &mut dyn Renderer) {
+    let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+    loop {
+        let hp_text = match interrupts.hp_percent {
+            0 => "off".to_string(),
+            percent => format!("{}%", percent),
+        };
+        let severity_text = match interrupts.message_severity {
+            Some(severity) => format!("{:?} or worse", severity),
+            None => "off".to_string(),
+        };
+        let options = &[
+            format!("Monster in view: {}", on_off(interrupts.monster_in_view)),
+            format!("Taking damage: {}", on_off(interrupts.damage)),
+            format!("Health drops to: {}", hp_text),
+            format!("Item in view: {}", on_off(interrupts.item_in_view)),
+            format!("Messages: {}", severity_text),
+        ];
+        match menu("Stop resting on\n", options, OPTIONS_WIDTH, renderer) {
+            Some(0) => interrupts.monster_in_view = !interrupts.monster_in_view,
+            Some(1) => interrupts.damage = !interrupts.damage,
+            Some(2) => interrupts.hp_percent = next_choice(&REST_HP_CHOICES, interrupts.hp_percent),
+            Some(3) => interrupts.item_in_view = !interrupts.item_in_view,
+            Some(4) => {
+                interrupts.message_severity = match interrupts.message_severity {
+                    None => Some(Severity::Warning),
+                    Some(Severity::Warning) => Some(Severity::Danger),
+                    Some(Severity::Danger) => Some(Severity::Critical),
+                    Some(_) => None,
+                }
+            }
+            _ => break,
+        }
+    }
+}
+
+/// Cycle to the preset after `current`, or the first one if it isn't a preset
+fn next_choice(choices: &[i32], current: i32) -> i32 {
+    let next = choices
+        .iter()
+        .position(|&choice| choice == current)
+        .map_or(0, |index| index + 1);
+    choices[next % choices.len()]
+}
+
+fn class_menu(renderer: &mut dyn Renderer) -> Option<PlayerClass> {
+    let options: Vec<String> = CLASSES
+        .iter()
+        .map(|class| format!("{}: {}", class.name(), class.description()))
+        .collect();
+    menu("Choose your class\n", &options, CLASS_SCREEN_WIDTH, renderer).map(|index| CLASSES[index])
+}
+
+pub fn main_menu(tcod: &mut Tcod, options: LaunchOptions) {
+    while !tcod.renderer.window_closed() {
         // show the background image, at twice the regular console resolution
-        tcod::image::blit_2x(&img, (0, 0), (-1, -1), &mut tcod.root, (0, 0));
+        tcod.renderer.draw_image(Layer::Screen, "menu_background.png");
 
-        tcod.root.set_default_foreground(LIGHT_YELLOW);
-        tcod.root.print_ex(
+        tcod.renderer.print(
+            Layer::Screen,
             SCREEN_WIDTH / 2,
             SCREEN_HEIGHT / 2 - 4,
-            BackgroundFlag::None,
-            TextAlignment::Center,
+            Align::Center,
+            LIGHT_YELLOW,
             "Rust-like Dungeons",
         );
-        tcod.root.print_ex(
+        tcod.renderer.print(
+            Layer::Screen,
             SCREEN_WIDTH / 2,
             SCREEN_HEIGHT - 2,
-            BackgroundFlag::None,
-            TextAlignment::Center,
+            Align::Center,
+            LIGHT_YELLOW,
             "Built by AnonymousAAArdark",
         );
+        tcod.renderer.blit(Layer::Screen, 0, 0);
 
         // show options and wait for the player's choice
         let choices = &["Play a new game", "Continue last game", "Quit"];
-        let choice = menu("", choices, 24, &mut tcod.root);
+        let choice = menu("", choices, 24, &mut *tcod.renderer);
 
         match choice {
             Some(0) => {
-                // new game
-                let (mut game, mut objects) = new_game(tcod);
-                play_game(tcod, &mut game, &mut objects);
+                // a new game takes the place of the saved one once it's saved
+                if save_exists(SAVE_FILE) && !confirm_overwrite(&mut *tcod.renderer) {
+                    continue;
+                }
+                // new game, as whoever the player picks
+                if let Some(class) = class_menu(&mut *tcod.renderer) {
+                    let (mut game, mut objects) = new_game(tcod, class, options.start_level);
+                    play_game(tcod, &mut game, &mut objects);
+                }
             }
             Some(1) => {
                 // load game
-                match load_game() {
+                match load_game(SAVE_FILE) {
                     Ok((mut game, mut objects)) => {
                         initialize_fov(tcod, &game.map);
                         play_game(tcod, &mut game, &mut objects);
                     }
-                    Err(_e) => {
-                        msgbox("\nNo saved data to load.\n", 24, &mut tcod.root);
+                    Err(e) => {
+                        // a missing save is usual; anything else is worth explaining
+                        if e.downcast_ref::<std::io::Error>().is_some() {
+                            msgbox("\nNo saved data to load.\n", 24, &mut *tcod.renderer);
+                        } else {
+                            let msg = format!("\nThe saved game can't be loaded: {}.\n", e);
+                            msgbox(&msg, 40, &mut *tcod.renderer);
+                        }
                         continue;
                     }
                 }
@@ -276,7 +866,7 @@ pub fn main_menu(tcod: &mut Tcod) {
 }
 
 fn render_bar(
-    panel: &mut Offscreen,
+    renderer: &mut dyn Renderer,
     x: i32,
     y: i32,
     total_width: i32,
@@ -285,87 +875,182 @@ fn render_bar(
     maximum: i32,
     bar_color: Color,
     back_color: Color,
+    show_numbers: bool,
 ) {
     // render a bar (HP, exp, etc). First calculate the width of the bar
     let bar_width = (value as f32 / maximum as f32 * total_width as f32) as i32;
+    let bar_width = cmp::min(bar_width, total_width);
 
     // render the background first
-    panel.set_default_background(back_color);
-    panel.rect(x, y, total_width, 1, false, BackgroundFlag::Screen);
+    renderer.fill(Layer::Panel, x, y, total_width, 1, back_color);
 
     // now render the bar on top
-    panel.set_default_background(bar_color);
     if bar_width > 0 {
-        panel.rect(x, y, bar_width, 1, false, BackgroundFlag::Screen);
+        renderer.fill(Layer::Panel, x, y, bar_width, 1, bar_color);
     }
 
-    // finally, some centered text with the values
-    panel.set_default_foreground(WHITE);
-    panel.print_ex(
-        x + total_width / 2,
-        y,
-        BackgroundFlag::None,
-        TextAlignment::Center,
-        &format!("{}, {}/{}", name, value, maximum),
-    );
+    // finally, some centered text with the values, in white so it reads
+    // against either color
+    let text = if show_numbers {
+        format!("{}, {}/{}", name, value, maximum)
+    } else {
+        name.to_string()
+    };
+    renderer.print(Layer::Panel, x + total_width / 2, y, Align::Center, WHITE, &text);
+}
+
+/// the player's position, and once the stairs down have been seen, which way
+/// they are
+fn compass(game: &Game, objects: &[Object]) -> String {
+    let (x, y) = objects[PLAYER].pos();
+    let stairs = objects
+        .iter()
+        .find(|o| o.name == "stairs" && game.map.get(o.x, o.y).explored);
+    let direction = match stairs {
+        Some(stairs) if stairs.pos() == (x, y) => "here",
+        Some(stairs) => {
+            // split the circle into eight slices, starting east and going clockwise
+            let angle = f64::from(stairs.y - y).atan2(f64::from(stairs.x - x));
+            let slice = (angle / std::f64::consts::FRAC_PI_4).round() as i32;
+            ["E", "SE", "S", "SW", "W", "NW", "N", "NE"][slice.rem_euclid(8) as usize]
+        }
+        None => return format!("@ {},{}", x, y),
+    };
+    format!("@ {},{} stairs: {}", x, y, direction)
+}
+
+/// who acts next turn and in what order: the player first, then every monster
+/// in view as often as its speed lets it. monsters out of view are left out
+fn turn_order(game: &Game, objects: &[Object], visible: &[usize]) -> String {
+    let next_turn = game.stats.turns + 1;
+    let mut actors = vec![String::from("you")];
+    // monsters take their turns in the order they're kept in, which isn't
+    // the order they're drawn in
+    let mut ids = visible.to_vec();
+    ids.sort_unstable();
+    for id in ids {
+        let object = &objects[id];
+        if object.ai.is_none() || object.fighter.is_none() {
+            continue;
+        }
+        match object.speed.actions(next_turn) {
+            0 => {}
+            1 => actors.push(object.name.clone()),
+            actions => actors.push(format!("{} x{}", object.name, actions)),
+        }
+    }
+    if actors.len() > TURN_ORDER_LENGTH {
+        actors.truncate(TURN_ORDER_LENGTH);
+        actors.push(String::from("..."));
+    }
+    format!("Next: {}", actors.join(", "))
+}
+
+/// objects are seen when in FOV, or anywhere explored if they're always visible
+fn is_object_visible(object: &Object, fov_map: &FovMap, map: &Map) -> bool {
+    fov_map.is_in_fov(object.x, object.y)
+        || (object.always_visible && map.get(object.x, object.y).explored)
 }
 
 /// return a string with the names of all objects under the mouse
-fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap) -> String {
-    let (x, y) = (mouse.cx as i32, mouse.cy as i32);
+fn get_names_under_mouse(
+    mouse: MouseState,
+    objects: &[Object],
+    fov_map: &FovMap,
+    map: &Map,
+) -> String {
+    let (x, y) = (mouse.x, mouse.y);
 
-    // create a list with the names of all objects at the mouse coords and in FOV
+    // create a list with the names of all visible objects at the mouse coords
     let names = objects
         .iter()
-        .filter(|obj| obj.pos() == (x, y) && fov_map.is_in_fov(obj.x, obj.y))
+        .filter(|obj| obj.pos() == (x, y) && is_object_visible(obj, fov_map, map))
         .map(|obj| obj.name.clone())
         .collect::<Vec<_>>();
 
     names.join(", ") // join the names, separated by commas
 }
 
-fn new_game(tcod: &mut Tcod) -> (Game, Vec<Object>) {
+/// a reminder of the items on the player's tile, if there are any
+fn items_here(game: &Game, objects: &[Object]) -> Option<String> {
+    let (x, y) = objects[PLAYER].pos();
+    let items: Vec<&Object> = game
+        .positions
+        .at(x, y)
+        .iter()
+        .map(|&id| &objects[id])
+        .filter(|object| object.item.is_some())
+        .collect();
+    match items.len() {
+        0 => None,
+        1 => Some(format!("There is a {} here (g to pick up).", items[0].name)),
+        count => Some(format!("There are {} items here (g to pick up).", count)),
+    }
+}
+
+fn new_game(tcod: &mut Tcod, class: PlayerClass, start_level: u32) -> (Game, Vec<Object>) {
     // create object representing the player
     let mut player = Object::new(0, 0, '@', "player", WHITE, true);
     player.alive = true;
-    player.fighter = Some(Fighter {
-        base_max_hp: 100,
-        hp: 100,
-        base_defense: 1,
-        base_power: 2,
-        xp: 0,
-        on_death: DeathCallback::Player,
-    });
+    let mut fighter = class.fighter();
+    scale_to_depth(&mut fighter, start_level);
+    player.fighter = Some(fighter);
 
     // the list of objects with just the player
     let mut objects = vec![player];
 
-    let mut game = Game {
-        // generate map (at this point it's not drawn to the screen)
-        map: make_map(&mut objects, 1),
-        messages: Messages::new(),
-        inventory: vec![],
-        dungeon_level: 1,
-    };
+    let settings = Settings::new();
+    let seed = rand::random();
+    // generate map (at this point it's not drawn to the screen)
+    let map = make_map(
+        &mut objects,
+        Branch::Main,
+        start_level,
+        LevelTheme::of(seed, Branch::Main, start_level),
+        settings.map_generator,
+        &[],
+        &mut level_rng(seed, Branch::Main, start_level),
+    );
+    let mut game = Game::new(map, settings, class, start_level, seed);
+    game.lore = Lore::load();
+    assign_appearances(&mut game);
+    disguise_unidentified(&game, &mut objects);
 
-    // initial equipment: a dagger
-    let mut dagger = Object::new(0, 0, '-', "dagger", SKY, false);
-    dagger.item = Some(Item::Sword);
-    dagger.equipment = Some(Equipment {
-        equipped: true,
-        slot: Slot::LeftHand,
-        max_hp_bonus: 0,
-        defense_bonus: 0,
-        power_bonus: 2,
+    // a loyal dog to keep the player company
+    let mut dog = Object::new(0, 0, 'd', "dog", LIGHT_SEPIA, true);
+    dog.alive = true;
+    dog.ally = true;
+    dog.fighter = Some(Fighter {
+        base_max_hp: 20,
+        hp: 20,
+        base_defense: 0,
+        base_power: 3,
+        xp: 0,
+        on_death: DeathCallback::Pet,
     });
-    game.inventory.push(dagger);
+    dog.ai = Some(Ai::Pet);
+    place_near_player(dog, &game.map, &mut objects);
+
+    // starting gear, ready to use
+    let mut inventory = vec![];
+    for &kind in class.loadout() {
+        let mut item = create_item(kind, 0, 0);
+        if let Some(slot) = item.equipment.map(|e| e.slot) {
+            if get_equipped_in_slot(slot, &inventory).is_none() {
+                item.equip(&mut game.messages);
+            }
+        }
+        inventory.push(item);
+    }
+    disguise_unidentified(&game, &mut inventory);
+    game.inventory = inventory;
 
     initialize_fov(tcod, &game.map);
 
     // a warm welcoming message!
     game.messages.add(
         "Welcome stranger! Prepare to perish in the Rust-like Dungeons.",
-        RED,
+        Severity::Danger,
     );
 
     (game, objects)
@@ -378,71 +1063,386 @@ pub fn initialize_fov(tcod: &mut Tcod, map: &Map) {
             tcod.fov.set(
                 x,
                 y,
-                !map[x as usize][y as usize].block_sight,
-                !map[x as usize][y as usize].blocked,
+                !map.get(x, y).block_sight,
+                !map.get(x, y).blocked,
             );
         }
     }
 
-    // unexplored areas start black (which is the default background color)
-    tcod.con.clear();
+    // unexplored areas start black (which is the default background color),
+    // and explored ones are drawn as explored on the very first frame. a new
+    // map also starts a new render cache: tiles still fading in, lit or
+    // highlighted on the old map would otherwise be painted over this one
+    tcod.render = RenderCache::new();
 }
 
 fn play_game(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
-    // force FOV "recompute" first time through game loop
-    let mut previous_player_position = (-1, -1);
-
     let mut menu_open = false;
+    let mut resting: Option<Rest> = None;
 
-    while !tcod.root.window_closed() {
-        // clear the screen of the previous frame
-        tcod.con.clear();
+    game.settings.apply_fps();
+    game.positions.rebuild(objects);
+    if game.preloaded.is_none() {
+        preload_next_level(game);
+    }
 
-        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
-            Some((_, Event::Mouse(m))) => tcod.mouse = m,
-            Some((_, Event::Key(k))) => tcod.key = k,
-            _ => tcod.key = Default::default(),
+    while !tcod.renderer.window_closed() {
+        match tcod.renderer.check_for_event() {
+            Some(InputEvent::Mouse(m)) => tcod.mouse = m,
+            Some(InputEvent::Key(k)) => tcod.key = k,
+            None => tcod.key = Default::default(),
         }
 
         // render the screen
-        let fov_recompute = previous_player_position != (objects[PLAYER].pos());
-        render_all(tcod, game, &objects, fov_recompute);
+        let frame_start = Instant::now();
+        render_all(tcod, game, objects);
+        tcod.render.frame_time = frame_start.elapsed();
 
-        tcod.root.flush();
+        tcod.renderer.flush();
 
         // level up if needed
         level_up(tcod, game, objects);
 
         // handle keys and exit if needed
-        previous_player_position = objects[PLAYER].pos();
-        let player_action = handle_keys(tcod, game, objects, &mut menu_open);
+        let player_was_alive = objects[PLAYER].alive;
+        let player_action = if let Some(rest) = resting.as_mut() {
+            // keep waiting until something interrupts, and any key always does
+            let reason = if tcod.key.code != KeyCode::None {
+                Some("You stop resting.")
+            } else {
+                rest.interruption(&tcod.fov, game, objects)
+            };
+            if let Some(reason) = reason {
+                game.messages.add(reason, Severity::Info);
+                resting = None;
+                PlayerAction::DidntTakeTurn
+            } else {
+                rest.turns += 1;
+                PlayerAction::TookTurn
+            }
+        } else {
+            tcod.record_key();
+            handle_keys(tcod, game, objects, &mut menu_open)
+        };
+        // resting starts for real on the next turn
+        let player_action = if player_action == PlayerAction::Rest {
+            resting = Some(Rest::start(&tcod.fov, game, objects));
+            PlayerAction::DidntTakeTurn
+        } else {
+            player_action
+        };
         if player_action == PlayerAction::Exit {
-            save_game(game, objects).unwrap();
+            save_game(game, objects, SAVE_FILE).unwrap();
             break;
         }
+        if player_action == PlayerAction::QuickLoad {
+            match quick_load(tcod, game, objects) {
+                Ok(()) => game.messages.add("Game quick-loaded.", Severity::Info),
+                Err(e) => game.messages.add(e, Severity::Warning),
+            }
+            continue;
+        }
+
+        if player_action == PlayerAction::TookTurn {
+            game.stats.turns += 1;
+            game.scent.mark(objects[PLAYER].pos(), game.stats.turns);
+            reveal_mimics(game, objects);
+            tick_hazards(game);
+            apply_statuses(objects, game);
+            if game.shield.tick() {
+                game.messages.add("Your magical shield fades away.", Severity::Warning);
+            }
+        }
 
         // let monsters take their turn
         if objects[PLAYER].alive && player_action != PlayerAction::DidntTakeTurn {
+            // monsters see the player where they are now, not where they were last frame
+            update_fov(tcod, objects);
             for id in 0..objects.len() {
                 if objects[id].ai.is_some() {
-                    ai_take_turn(id, tcod, game, objects);
+                    monster_turn(id, &tcod.fov, game, objects);
                 }
             }
         }
+
+        // slimes hurt this turn divide
+        split_slimes(&tcod.fov, game, objects);
+        tick_buffs(&tcod.fov, game, objects);
+
+        if player_action == PlayerAction::TookTurn {
+            spawn_wanderer(&tcod.fov, game, objects);
+            game.events.push(GameEvent::Turn);
+        }
+        run_plugins(game, objects);
+
+        debug_assert!(game.positions.is_in_sync(objects));
+
+        // warn once whenever the player's health drops into the danger zone
+        let low_hp = check_low_hp(game, objects);
+
+        // make sure a burst of messages doesn't scroll by unread
+        show_more_prompts(tcod, game, objects);
+
+        if low_hp && game.settings.low_hp_pause {
+            // stop and make sure the player noticed before carrying on
+            render_all(tcod, game, objects);
+            tcod.renderer.flush();
+            tcod.renderer.wait_for_keypress();
+        }
+
+        if player_was_alive && !objects[PLAYER].alive {
+            // the run is over, look back on how it went, then either go back
+            // to a quick-save or leave for the main menu. a dead game isn't
+            // saved on the way out
+            death_animation(tcod, game, objects);
+            show_run_summary(game, &mut *tcod.renderer);
+            if !game_over_menu(tcod, game, objects) {
+                break;
+            }
+        }
+    }
+}
+
+/// replace the running game with the quick-save. the error says why it couldn't be
+fn quick_load(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> Result<(), String> {
+    match load_game(QUICKSAVE_FILE) {
+        Ok((quicksaved, quicksaved_objects)) => {
+            *game = quicksaved;
+            *objects = quicksaved_objects;
+            game.positions.rebuild(objects);
+            initialize_fov(tcod, &game.map);
+            preload_next_level(game);
+            Ok(())
+        }
+        Err(e) if e.downcast_ref::<std::io::Error>().is_some() => {
+            Err("There is no quick-save to load.".into())
+        }
+        Err(e) => Err(format!("The quick-save can't be loaded: {}.", e)),
+    }
+}
+
+/// fade the map to red over the player's body. a key press skips the rest
+/// of it; nothing else the player does counts until it's over
+fn death_animation(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    render_all(tcod, game, objects);
+    tcod.renderer.flush();
+    let duration = game.settings.animation_millis(DEATH_FADE_MILLIS).max(1);
+    let start = Instant::now();
+    while !tcod.renderer.window_closed() {
+        if let Some(InputEvent::Key(_)) = tcod.renderer.check_for_event() {
+            break;
+        }
+        let elapsed = start.elapsed().as_millis();
+        if elapsed >= duration {
+            break;
+        }
+        let redness = elapsed as f32 / duration as f32;
+        for y in 0..MAP_HEIGHT {
+            for x in 0..MAP_WIDTH {
+                if game.map.get(x, y).explored {
+                    let color = tile_color(&tcod.fov, &game.map, x, y);
+                    let color = lerp(color, DARKEST_RED, redness);
+                    tcod.renderer.set_background(Layer::Map, x, y, color);
+                }
+            }
+        }
+        tcod.renderer.blit(Layer::Map, 0, 0);
+        tcod.renderer.flush();
+    }
+    // whatever comes next draws the map the way it really looks
+    tcod.render.map_changed();
+}
+
+/// what to do now that the player is dead. returns whether the game goes on
+/// from a quick-save, rather than back to the main menu
+fn game_over_menu(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> bool {
+    let choices = &["Load the quick-save", "Return to the main menu"];
+    loop {
+        match menu("\nYou have died.\n", choices, 30, &mut *tcod.renderer) {
+            Some(0) => match quick_load(tcod, game, objects) {
+                Ok(()) => {
+                    game.messages.add("Game quick-loaded.", Severity::Info);
+                    return true;
+                }
+                Err(e) => msgbox(&format!("\n{}\n", e), 40, &mut *tcod.renderer),
+            },
+            _ => return false,
+        }
     }
 }
 
-fn save_game(game: &Game, objects: &[Object]) -> Result<(), Box<dyn Error>> {
+fn show_run_summary(game: &Game, renderer: &mut dyn Renderer) {
+    let stats = &game.stats;
+    let msg = format!(
+        "Run summary\n\
+        \n\
+        Died on level: {}\n\
+        Deepest level: {}\n\
+        Turns taken: {}\n\
+        \n\
+        Damage dealt: {}\n\
+        Damage taken: {}\n\
+        Items used: {}\n\
+        \n\
+        Monsters killed: {}\n\
+        {}{}",
+        game.dungeon_level,
+        stats.deepest_level,
+        stats.turns,
+        stats.damage_dealt,
+        stats.damage_taken,
+        stats.items_used,
+        stats.total_kills(),
+        stats.kill_lines().join("\n"),
+        debug_run_note(game),
+    );
+    msgbox(&msg, SUMMARY_SCREEN_WIDTH, renderer);
+}
+
+pub fn save_game(game: &Game, objects: &[Object], path: &str) -> Result<(), Box<dyn Error>> {
     let save_data = serde_json::to_string(&(game, objects))?;
-    let mut file = File::create("savegame")?;
+    let mut file = File::create(path)?;
     file.write_all(save_data.as_bytes())?;
     Ok(())
 }
 
-fn load_game() -> Result<(Game, Vec<Object>), Box<dyn Error>> {
+/// whether there's a save at the path to be overwritten
+pub fn save_exists(path: &str) -> bool {
+    Path::new(path).exists()
+}
+
+/// ask before starting a game that would replace the saved one
+fn confirm_overwrite(renderer: &mut dyn Renderer) -> bool {
+    let choice = menu(
+        "There is a game in progress. Starting a new one will overwrite it.\n",
+        &["Start a new game anyway", "Go back"],
+        OVERWRITE_SCREEN_WIDTH,
+        renderer,
+    );
+    choice == Some(0)
+}
+
+/// write the whole game state as readable JSON, for debugging and tools.
+/// unlike the save file, this is never read back in
+pub fn export_game(game: &Game, objects: &[Object]) -> Result<(), Box<dyn Error>> {
+    let export_data = serde_json::to_string_pretty(&(game, objects))?;
+    let mut file = File::create("game_export.json")?;
+    file.write_all(export_data.as_bytes())?;
+    Ok(())
+}
+
+/// write everything needed to look into a bug to a fresh, timestamped file:
+/// the seed, the latest key presses and messages, and the whole game state.
+/// returns the name of the file
+pub fn write_bug_report(
+    tcod: &Tcod,
+    game: &Game,
+    objects: &[Object],
+) -> Result<String, Box<dyn Error>> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let path = format!("bug_report_{}.json", timestamp);
+    let inputs: Vec<String> = tcod
+        .recent_keys
+        .iter()
+        .map(|key| {
+            if key.alt {
+                format!("Alt+{:?}", key.code)
+            } else {
+                format!("{:?}", key.code)
+            }
+        })
+        .collect();
+    let skip = game.messages.messages.len().saturating_sub(BUG_REPORT_MESSAGES);
+    let messages: Vec<&str> =
+        game.messages.messages[skip..].iter().map(|message| message.text.as_str()).collect();
+    let report = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "seed": game.seed,
+        "dungeon_level": game.dungeon_level,
+        "turns": game.stats.turns,
+        "recent_inputs": inputs,
+        "recent_messages": messages,
+        "state": (game, objects),
+    });
+    let mut file = File::create(&path)?;
+    file.write_all(serde_json::to_string_pretty(&report)?.as_bytes())?;
+    Ok(path)
+}
+
+fn load_game(path: &str) -> Result<(Game, Vec<Object>), Box<dyn Error>> {
     let mut json_save_state = String::new();
-    let mut file = File::open("savegame")?;
+    let mut file = File::open(path)?;
     file.read_to_string(&mut json_save_state)?;
-    let result = serde_json::from_str::<(Game, Vec<Object>)>(&json_save_state)?;
-    Ok(result)
-}
\ No newline at end of file
+    let (mut game, mut objects) =
+        serde_json::from_str::<(Game, Vec<Object>)>(&json_save_state)?;
+    game.lore = Lore::load();
+    // a save that reads fine can still be broken; fix what can be fixed
+    let repairs = validate_game_state(&mut game, &mut objects)?;
+    for repair in repairs {
+        game.messages.add(repair, Severity::Warning);
+    }
+    Ok((game, objects))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explored_tiles_survive_a_save_and_show_straight_away() {
+        // the player shut in a small room, having seen some walls far off
+        let mut map = Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+        for x in 4..=6 {
+            for y in 4..=6 {
+                map.set(x, y, Tile::empty());
+            }
+        }
+        let seen = [(40, 30), (41, 30), (42, 31)];
+        for &(x, y) in &seen {
+            map.get_mut(x, y).explored = true;
+        }
+        let player = Object::new(5, 5, '@', "player", WHITE, true);
+        let stairs = Object::new(6, 6, '<', "stairs", WHITE, false);
+        let (game, objects) = test_game(map, vec![player, stairs]);
+
+        let path = std::env::temp_dir().join("rustlike_dungeons_explored_test.sav");
+        let path = path.to_str().unwrap();
+        save_game(&game, &objects, path).unwrap();
+        let loaded = load_game(path);
+        std::fs::remove_file(path).unwrap();
+        let (mut game, objects) = loaded.unwrap();
+        for &(x, y) in &seen {
+            assert!(game.map.get(x, y).explored, "({}, {})", x, y);
+        }
+        assert!(!game.map.get(50, 30).explored);
+
+        // the first frame draws them, though they're nowhere near in view
+        let renderer = NullRenderer::default();
+        let painted = renderer.painted.clone();
+        let mut tcod = Tcod::new(Box::new(renderer));
+        initialize_fov(&mut tcod, &game.map);
+        render_all(&mut tcod, &mut game, &objects);
+        let painted = painted.borrow();
+        for &(x, y) in &seen {
+            assert!(!tcod.fov.is_in_fov(x, y));
+            assert!(painted.contains(&(x, y)), "({}, {}) wasn't drawn", x, y);
+        }
+        assert!(!painted.contains(&(50, 30)));
+    }
+
+    #[test]
+    fn turn_order_follows_the_order_monsters_act_in() {
+        let map = Map::new(MAP_WIDTH, MAP_HEIGHT, Tile::empty());
+        let player = Object::new(5, 5, '@', "player", WHITE, true);
+        let (game, mut objects) = test_game(map, vec![player]);
+        for &name in &["orc", "troll"] {
+            let mut monster = Object::new(6, 5, 'm', name, WHITE, true);
+            monster.fighter = Some(PlayerClass::Warrior.fighter());
+            monster.ai = Some(Ai::Basic);
+            objects.push(monster);
+        }
+        // drawn troll first, as if it were on top
+        let order = turn_order(&game, &objects, &[2, 1]);
+        assert_eq!(order, "Next: you, orc, troll");
+    }
+}
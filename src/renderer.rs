@@ -0,0 +1,357 @@
+#[cfg(feature = "tcod")]
+use tcod::console::{blit, BackgroundFlag, Console, Offscreen, Root, TextAlignment};
+#[cfg(feature = "tcod")]
+use tcod::input::{self, Event, Key, Mouse};
+
+use crate::*;
+
+/// The surfaces the game draws on. Map and panel make up the game screen,
+/// the window holds whatever menu is being shown, and the screen layer is
+/// for anything drawn across the whole window, like the title screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    Map,
+    Panel,
+    Window,
+    Screen,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// The keys the game cares about, independent of the backend
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum KeyCode {
+    #[default]
+    None,
+    Char(char),
+    Enter,
+    Escape,
+    Tab,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    NumPad5,
+    Shift,
+    F5,
+    F9,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KeyPress {
+    pub code: KeyCode,
+    pub alt: bool,
+}
+
+/// Where the mouse is, in console cells, and which buttons were just clicked
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MouseState {
+    pub x: i32,
+    pub y: i32,
+    pub lbutton_pressed: bool,
+    pub rbutton_pressed: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    Key(KeyPress),
+    Mouse(MouseState),
+}
+
+/// Everything the game needs from a display: drawing on the layers, showing
+/// them, and reading the player's input. `TcodRenderer`, behind the `tcod`
+/// feature, is the default one.
+pub trait Renderer {
+    /// wipe a layer back to black
+    fn clear(&mut self, layer: Layer);
+    /// draw a glyph in the given color, leaving the cell's background alone
+    fn put_char(&mut self, layer: Layer, x: i32, y: i32, glyph: char, color: Color);
+    fn set_background(&mut self, layer: Layer, x: i32, y: i32, color: Color);
+    /// blank out a rectangle, giving its cells the given background
+    fn fill(&mut self, layer: Layer, x: i32, y: i32, width: i32, height: i32, color: Color);
+    fn print(&mut self, layer: Layer, x: i32, y: i32, align: Align, color: Color, text: &str);
+    /// print left-aligned text, wrapped to the given width
+    fn print_wrapped(&mut self, layer: Layer, x: i32, y: i32, width: i32, color: Color, text: &str);
+    /// how many lines `text` takes up when wrapped to the given width
+    fn wrapped_height(&mut self, width: i32, text: &str) -> i32;
+    /// draw an image over a whole layer, at twice the console resolution
+    fn draw_image(&mut self, layer: Layer, path: &str);
+    /// start a fresh, blank window layer of the given size
+    fn open_window(&mut self, width: i32, height: i32);
+    /// copy a layer onto the screen; the window layer's background is see-through
+    fn blit(&mut self, layer: Layer, x: i32, y: i32);
+    /// present the screen to the player
+    fn flush(&mut self);
+
+    fn window_closed(&self) -> bool;
+    fn is_fullscreen(&self) -> bool;
+    fn set_fullscreen(&mut self, fullscreen: bool);
+    /// the next key or mouse event, if there is one waiting
+    fn check_for_event(&mut self) -> Option<InputEvent>;
+    /// block until a key is pressed
+    fn wait_for_keypress(&mut self) -> KeyPress;
+}
+
+/// A `Renderer` that draws nothing, for tests. It presses the keys it's
+/// been given, in order, and then nothing at all
+#[cfg(test)]
+#[derive(Default)]
+pub struct NullRenderer {
+    pub keys: Vec<KeyPress>,
+    /// the map tiles given a background since the map was last cleared,
+    /// shared so a test can still look once the renderer is handed over
+    pub painted: std::rc::Rc<std::cell::RefCell<Vec<(i32, i32)>>>,
+}
+
+#[cfg(test)]
+impl NullRenderer {
+    /// one that types out `text`, a key for each character
+    pub fn typing(text: &str) -> Self {
+        let keys = text.chars().map(|c| KeyPress { code: KeyCode::Char(c), alt: false }).collect();
+        NullRenderer { keys, ..NullRenderer::default() }
+    }
+}
+
+#[cfg(test)]
+impl Renderer for NullRenderer {
+    fn clear(&mut self, layer: Layer) {
+        if layer == Layer::Map {
+            self.painted.borrow_mut().clear();
+        }
+    }
+    fn put_char(&mut self, _layer: Layer, _x: i32, _y: i32, _glyph: char, _color: Color) {}
+    fn set_background(&mut self, layer: Layer, x: i32, y: i32, _color: Color) {
+        if layer == Layer::Map {
+            self.painted.borrow_mut().push((x, y));
+        }
+    }
+    fn fill(&mut self, _layer: Layer, _x: i32, _y: i32, _w: i32, _h: i32, _color: Color) {}
+    fn print(&mut self, _: Layer, _: i32, _: i32, _: Align, _: Color, _text: &str) {}
+    fn print_wrapped(&mut self, _: Layer, _: i32, _: i32, _: i32, _: Color, _text: &str) {}
+    fn wrapped_height(&mut self, _width: i32, _text: &str) -> i32 {
+        1
+    }
+    fn draw_image(&mut self, _layer: Layer, _path: &str) {}
+    fn open_window(&mut self, _width: i32, _height: i32) {}
+    fn blit(&mut self, _layer: Layer, _x: i32, _y: i32) {}
+    fn flush(&mut self) {}
+    fn window_closed(&self) -> bool {
+        false
+    }
+    fn is_fullscreen(&self) -> bool {
+        false
+    }
+    fn set_fullscreen(&mut self, _fullscreen: bool) {}
+    fn check_for_event(&mut self) -> Option<InputEvent> {
+        None
+    }
+    fn wait_for_keypress(&mut self) -> KeyPress {
+        if self.keys.is_empty() {
+            KeyPress::default()
+        } else {
+            self.keys.remove(0)
+        }
+    }
+}
+
+/// A `Renderer` on top of a libtcod root console
+#[cfg(feature = "tcod")]
+pub struct TcodRenderer {
+    root: Root,
+    con: Offscreen,
+    panel: Offscreen,
+    window: Offscreen,
+    window_size: (i32, i32),
+    screen: Offscreen,
+}
+
+#[cfg(feature = "tcod")]
+impl TcodRenderer {
+    /// Set up the offscreen consoles to go with the root console
+    pub fn new(root: Root) -> Self {
+        TcodRenderer {
+            root,
+            con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
+            panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
+            window: Offscreen::new(1, 1),
+            window_size: (1, 1),
+            screen: Offscreen::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+        }
+    }
+
+    fn console(&mut self, layer: Layer) -> &mut Offscreen {
+        match layer {
+            Layer::Map => &mut self.con,
+            Layer::Panel => &mut self.panel,
+            Layer::Window => &mut self.window,
+            Layer::Screen => &mut self.screen,
+        }
+    }
+}
+
+#[cfg(feature = "tcod")]
+impl Renderer for TcodRenderer {
+    fn clear(&mut self, layer: Layer) {
+        let con = self.console(layer);
+        con.set_default_background(BLACK);
+        con.clear();
+    }
+
+    fn put_char(&mut self, layer: Layer, x: i32, y: i32, glyph: char, color: Color) {
+        let con = self.console(layer);
+        con.set_default_foreground(color);
+        con.put_char(x, y, glyph, BackgroundFlag::None);
+    }
+
+    fn set_background(&mut self, layer: Layer, x: i32, y: i32, color: Color) {
+        self.console(layer).set_char_background(x, y, color, BackgroundFlag::Set);
+    }
+
+    fn fill(&mut self, layer: Layer, x: i32, y: i32, width: i32, height: i32, color: Color) {
+        let con = self.console(layer);
+        con.set_default_background(color);
+        con.rect(x, y, width, height, true, BackgroundFlag::Set);
+        con.set_default_background(BLACK);
+    }
+
+    fn print(&mut self, layer: Layer, x: i32, y: i32, align: Align, color: Color, text: &str) {
+        let alignment = match align {
+            Align::Left => TextAlignment::Left,
+            Align::Center => TextAlignment::Center,
+            Align::Right => TextAlignment::Right,
+        };
+        let con = self.console(layer);
+        con.set_default_foreground(color);
+        con.print_ex(x, y, BackgroundFlag::None, alignment, text);
+    }
+
+    fn print_wrapped(
+        &mut self,
+        layer: Layer,
+        x: i32,
+        y: i32,
+        width: i32,
+        color: Color,
+        text: &str,
+    ) {
+        let con = self.console(layer);
+        con.set_default_foreground(color);
+        con.print_rect_ex(x, y, width, 0, BackgroundFlag::None, TextAlignment::Left, text);
+    }
+
+    fn wrapped_height(&mut self, width: i32, text: &str) -> i32 {
+        self.root.get_height_rect(0, 0, width, 0, text)
+    }
+
+    fn draw_image(&mut self, layer: Layer, path: &str) {
+        let img = tcod::image::Image::from_file(path)
+            .ok()
+            .expect("Background image not found");
+        tcod::image::blit_2x(&img, (0, 0), (-1, -1), self.console(layer), (0, 0));
+    }
+
+    fn open_window(&mut self, width: i32, height: i32) {
+        self.window = Offscreen::new(width, height);
+        self.window_size = (width, height);
+    }
+
+    fn blit(&mut self, layer: Layer, x: i32, y: i32) {
+        let (size, bg_alpha) = match layer {
+            Layer::Map => ((MAP_WIDTH, MAP_HEIGHT), 1.0),
+            Layer::Panel => ((SCREEN_WIDTH, PANEL_HEIGHT), 1.0),
+            Layer::Window => (self.window_size, 0.7),
+            Layer::Screen => ((SCREEN_WIDTH, SCREEN_HEIGHT), 1.0),
+        };
+        let source = match layer {
+            Layer::Map => &self.con,
+            Layer::Panel => &self.panel,
+            Layer::Window => &self.window,
+            Layer::Screen => &self.screen,
+        };
+        // only copy the part that fits in the root console, so a window that
+        // doesn't match the layout gets cropped rather than breaking the blit
+        let width = size.0.min(self.root.width() - x);
+        let height = size.1.min(self.root.height() - y);
+        if width <= 0 || height <= 0 {
+            return;
+        }
+        blit(source, (0, 0), (width, height), &mut self.root, (x, y), 1.0, bg_alpha);
+    }
+
+    fn flush(&mut self) {
+        self.root.flush();
+    }
+
+    fn window_closed(&self) -> bool {
+        self.root.window_closed()
+    }
+
+    fn is_fullscreen(&self) -> bool {
+        self.root.is_fullscreen()
+    }
+
+    fn set_fullscreen(&mut self, fullscreen: bool) {
+        self.root.set_fullscreen(fullscreen);
+    }
+
+    fn check_for_event(&mut self) -> Option<InputEvent> {
+        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
+            Some((_, Event::Mouse(mouse))) => Some(InputEvent::Mouse(convert_mouse(mouse))),
+            Some((_, Event::Key(key))) => Some(InputEvent::Key(convert_key(key))),
+            None => None,
+        }
+    }
+
+    fn wait_for_keypress(&mut self) -> KeyPress {
+        convert_key(self.root.wait_for_keypress(true))
+    }
+}
+
+#[cfg(feature = "tcod")]
+fn convert_key(key: Key) -> KeyPress {
+    use tcod::input::KeyCode as Tcod;
+    let code = match key.code {
+        // typed characters come in as text events, or as printable keys
+        // when waiting for a keypress
+        Tcod::Text => key.text().chars().next().map_or(KeyCode::None, KeyCode::Char),
+        Tcod::Char => KeyCode::Char(key.printable),
+        Tcod::NoKey => KeyCode::None,
+        Tcod::Enter => KeyCode::Enter,
+        Tcod::Escape => KeyCode::Escape,
+        Tcod::Tab => KeyCode::Tab,
+        Tcod::Up => KeyCode::Up,
+        Tcod::Down => KeyCode::Down,
+        Tcod::Left => KeyCode::Left,
+        Tcod::Right => KeyCode::Right,
+        Tcod::Home => KeyCode::Home,
+        Tcod::End => KeyCode::End,
+        Tcod::PageUp => KeyCode::PageUp,
+        Tcod::PageDown => KeyCode::PageDown,
+        Tcod::NumPad5 => KeyCode::NumPad5,
+        Tcod::Shift => KeyCode::Shift,
+        Tcod::F5 => KeyCode::F5,
+        Tcod::F9 => KeyCode::F9,
+        _ => KeyCode::Other,
+    };
+    KeyPress { code, alt: key.alt }
+}
+
+#[cfg(feature = "tcod")]
+fn convert_mouse(mouse: Mouse) -> MouseState {
+    MouseState {
+        x: mouse.cx as i32,
+        y: mouse.cy as i32,
+        lbutton_pressed: mouse.lbutton_pressed,
+        rbutton_pressed: mouse.rbutton_pressed,
+    }
+}